@@ -0,0 +1,127 @@
+use super::simple_allocator_trait::Allocator;
+use std::marker::PhantomData;
+
+/// An allocator that containers allocate their free heap storage with through a
+/// handle they carry, so they can be tied to a particular arena or memory pool.
+///
+/// Every `Allocator` from `simple_allocator_trait`, like `DefaultHeap`, is also an
+/// `InstanceAllocator`, with a zero-sized `StaticHandle`.
+///
+/// Handles are stored in the static part of containers, including when they are
+/// compacted. Containers created with `new` or `Default` use `Handle::default()`,
+/// for other handles use the `_in` constructors, like `CompactVec::new_in`.
+///
+/// Handles that aren't zero-sized are only valid in the process that created them,
+/// so images of containers using them can't be persisted or sent to other processes.
+pub trait InstanceAllocator {
+    /// What each container stores to allocate
+    type Handle: Clone;
+
+    /// Allocate enough memory to store `capacity` of `T`
+    fn allocate_in<T>(handle: &Self::Handle, capacity: usize) -> *mut T;
+
+    /// Free memory allocated with `allocate_in`
+    ///
+    /// # Safety
+    /// `ptr` has to be allocated by `allocate_in` for the same `T` and `capacity`, through
+    /// `handle` or a clone of it, and must not be used anymore afterwards.
+    unsafe fn deallocate_in<T>(handle: &Self::Handle, ptr: *mut T, capacity: usize);
+}
+
+/// The zero-sized handle of a static `Allocator`
+pub struct StaticHandle<A>(PhantomData<fn() -> A>);
+
+impl<A> Clone for StaticHandle<A> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<A> Copy for StaticHandle<A> {}
+
+impl<A> Default for StaticHandle<A> {
+    fn default() -> Self {
+        StaticHandle(PhantomData)
+    }
+}
+
+impl<A> ::std::fmt::Debug for StaticHandle<A> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "StaticHandle")
+    }
+}
+
+impl<A: Allocator> InstanceAllocator for A {
+    type Handle = StaticHandle<A>;
+
+    fn allocate_in<T>(_handle: &StaticHandle<A>, capacity: usize) -> *mut T {
+        A::allocate(capacity)
+    }
+
+    unsafe fn deallocate_in<T>(_handle: &StaticHandle<A>, ptr: *mut T, capacity: usize) {
+        A::deallocate(ptr, capacity)
+    }
+}
+
+#[cfg(test)]
+struct CountingHeap;
+
+#[cfg(test)]
+impl InstanceAllocator for CountingHeap {
+    type Handle = ::std::rc::Rc<::std::cell::Cell<usize>>;
+
+    fn allocate_in<T>(handle: &Self::Handle, capacity: usize) -> *mut T {
+        handle.set(handle.get() + 1);
+        ::simple_allocator_trait::DefaultHeap::allocate(capacity)
+    }
+
+    unsafe fn deallocate_in<T>(handle: &Self::Handle, ptr: *mut T, capacity: usize) {
+        handle.set(handle.get() - 1);
+        ::simple_allocator_trait::DefaultHeap::deallocate(ptr, capacity)
+    }
+}
+
+#[test]
+fn containers_allocate_through_their_handle() {
+    use super::compact::Compact;
+    use super::compact_ptr::CompactPtr;
+    use super::compact_vec::CompactVec;
+    let live = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+
+    let mut list: CompactVec<u32, CountingHeap> = CompactVec::new_in(live.clone());
+    list.extend(0..100);
+    let mut boxed: CompactPtr<u32, CountingHeap> = CompactPtr::new_in(7, live.clone());
+    *boxed += 1;
+    assert_eq!(2, live.get());
+
+    let cloned = list.clone();
+    assert_eq!(3, live.get());
+    assert!(::std::rc::Rc::ptr_eq(&live, cloned.allocator()));
+
+    unsafe {
+        let bytes = list.total_size_bytes();
+        let storage = ::simple_allocator_trait::DefaultHeap::allocate::<u8>(bytes)
+            as *mut CompactVec<u32, CountingHeap>;
+        Compact::compact_behind(&mut list, storage);
+        ::std::mem::forget(list);
+        assert_eq!(2, live.get());
+        // moves the handle out of `storage`, which then only needs to be freed
+        let decompacted = Compact::decompact(storage);
+        assert_eq!(3, live.get());
+        assert_eq!(&cloned[..], &decompacted[..]);
+        ::simple_allocator_trait::DefaultHeap::deallocate(storage as *mut u8, bytes);
+        drop(decompacted);
+    }
+    assert_eq!(2, live.get());
+    // `live`, `boxed` and `cloned`: no handle was leaked
+    assert_eq!(3, ::std::rc::Rc::strong_count(&live));
+
+    let mut bytes = Vec::new();
+    assert!(::snapshot::write_snapshot(&cloned, &mut bytes).is_err());
+    assert!(::stream::write_compact(&cloned, &mut bytes).is_err());
+    assert!(bytes.is_empty());
+
+    drop(cloned);
+    assert_eq!(8, boxed.into_inner());
+    assert_eq!(0, live.get());
+}
@@ -0,0 +1,173 @@
+use simple_allocator_trait::Allocator;
+use std::alloc::{self, Layout};
+use std::cell::RefCell;
+
+/// Size of the chunks that an arena requests from the global heap
+const CHUNK_SIZE: usize = 64 * 1024;
+const CHUNK_ALIGN: usize = 64;
+
+/// A bump region made of chunks from the global heap, which are kept for reuse
+/// when the region is reset.
+///
+/// Chunks are never returned to the global heap, not even when the thread exits,
+/// since containers allocated from them might have been sent to other threads.
+struct Arena {
+    chunks: Vec<(*mut u8, Layout)>,
+    /// Index of the chunk that is bumped into
+    current: usize,
+    /// Bytes used in the current chunk
+    used: usize,
+    /// Bytes handed out since the last reset, including alignment padding
+    allocated: usize,
+}
+
+impl Arena {
+    fn new() -> Arena {
+        Arena {
+            chunks: Vec::new(),
+            current: 0,
+            used: 0,
+            allocated: 0,
+        }
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        if layout.size() == 0 {
+            return layout.align() as *mut u8;
+        }
+
+        while self.current < self.chunks.len() {
+            let (chunk, chunk_layout) = self.chunks[self.current];
+            let start = unsafe { chunk.add(self.used) };
+            let padding = start.align_offset(layout.align());
+            if self.used + padding + layout.size() <= chunk_layout.size() {
+                self.used += padding + layout.size();
+                self.allocated += padding + layout.size();
+                return unsafe { start.add(padding) };
+            }
+            self.current += 1;
+            self.used = 0;
+        }
+
+        let chunk_layout = Layout::from_size_align(
+            CHUNK_SIZE.max(layout.size()),
+            CHUNK_ALIGN.max(layout.align()),
+        ).expect("arena allocation too large");
+        let chunk = unsafe { alloc::alloc(chunk_layout) };
+        if chunk.is_null() {
+            alloc::handle_alloc_error(chunk_layout);
+        }
+        self.chunks.push((chunk, chunk_layout));
+        self.current = self.chunks.len() - 1;
+        self.used = layout.size();
+        self.allocated += layout.size();
+        chunk
+    }
+
+    fn reset(&mut self) {
+        self.current = 0;
+        self.used = 0;
+        self.allocated = 0;
+    }
+
+    fn capacity(&self) -> usize {
+        self.chunks.iter().map(|&(_, layout)| layout.size()).sum()
+    }
+}
+
+thread_local! {
+    static ARENA: RefCell<Arena> = RefCell::new(Arena::new());
+}
+
+/// An implementation of `Allocator` that bump-allocates from an arena of the current thread.
+///
+/// Deallocation is a no-op, instead all memory of the arena is reclaimed at once
+/// with `ArenaHeap::reset`, for example after each simulation tick.
+/// This makes it a good fit for the spilled dynamic parts of short-lived messages.
+///
+/// The memory of an arena is kept for the lifetime of the process, so it stays valid
+/// for containers sent to other threads, even after the allocating thread exited.
+pub struct ArenaHeap {}
+
+impl ArenaHeap {
+    /// Reclaim all memory allocated from the arena of the current thread,
+    /// keeping it around for reuse.
+    ///
+    /// # Safety
+    ///
+    /// Containers which still use free heap storage allocated from it
+    /// (on any thread) will point to reused memory afterwards.
+    pub unsafe fn reset() {
+        ARENA.with(|arena| arena.borrow_mut().reset());
+    }
+
+    /// Bytes allocated from the arena of the current thread since the last reset
+    pub fn allocated_bytes() -> usize {
+        ARENA.with(|arena| arena.borrow().allocated)
+    }
+
+    /// Bytes that the arena of the current thread holds from the global heap
+    pub fn capacity_bytes() -> usize {
+        ARENA.with(|arena| arena.borrow().capacity())
+    }
+}
+
+impl Allocator for ArenaHeap {
+    fn allocate<T>(capacity: usize) -> *mut T {
+        let layout = Layout::array::<T>(capacity).expect("capacity overflow");
+        ARENA.with(|arena| arena.borrow_mut().allocate(layout)) as *mut T
+    }
+
+    unsafe fn deallocate<T>(_ptr: *mut T, _capacity: usize) {
+        // reclaimed wholesale by `reset`
+    }
+}
+
+#[test]
+fn arena_is_reused_after_reset() {
+    use super::compact::Compact;
+    use super::compact_vec::CompactVec;
+
+    let mut list: CompactVec<CompactVec<u64, ArenaHeap>, ArenaHeap> = CompactVec::new();
+    for i in 0..100 {
+        list.push((0..i).collect());
+    }
+    let allocated = ArenaHeap::allocated_bytes();
+    assert!(allocated >= 100 * 99 / 2 * ::std::mem::size_of::<u64>());
+    assert!(!list.is_still_compact());
+    assert_eq!(&[0, 1, 2], &list[3][..]);
+
+    let capacity = ArenaHeap::capacity_bytes();
+    let first = list[1].as_ptr();
+    drop(list);
+    unsafe { ArenaHeap::reset() };
+    assert_eq!(0, ArenaHeap::allocated_bytes());
+
+    let mut list: CompactVec<CompactVec<u64, ArenaHeap>, ArenaHeap> = CompactVec::new();
+    for i in 0..100 {
+        list.push((0..i).collect());
+    }
+    assert_eq!(allocated, ArenaHeap::allocated_bytes());
+    assert_eq!(capacity, ArenaHeap::capacity_bytes());
+    assert_eq!(first, list[1].as_ptr());
+}
+
+#[test]
+fn allocations_outlive_their_thread() {
+    use super::compact_vec::CompactVec;
+
+    let lists = (0..4)
+        .map(|i| {
+            ::std::thread::spawn(move || {
+                let list: CompactVec<u64, ArenaHeap> = (i..i + 1000).collect();
+                list
+            })
+            .join()
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+    // all threads exited, with their arenas
+    for (i, list) in lists.iter().enumerate() {
+        assert_eq!((i as u64..i as u64 + 1000).collect::<Vec<_>>(), &list[..]);
+    }
+}
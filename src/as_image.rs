@@ -0,0 +1,160 @@
+use super::compact::Compact;
+use super::snapshot::{read_snapshot, write_snapshot, SNAPSHOT_HEADER_SIZE};
+use super::stream::TrustedImage;
+use serde::de::{self, Deserializer, Visitor};
+use serde::ser::{self, Serializer};
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Serialize `value` as the bytes of a snapshot of it (see `write_snapshot`),
+/// which is its compact image behind a header describing it
+pub fn serialize<T: Compact, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut bytes = Vec::with_capacity(SNAPSHOT_HEADER_SIZE + value.total_size_bytes());
+    write_snapshot(value, &mut bytes).map_err(<S::Error as ser::Error>::custom)?;
+    serializer.serialize_bytes(&bytes)
+}
+
+/// Deserialize a `T` from the bytes written by `serialize`, refusing images of a different type
+/// or platform and corrupted images like `read_snapshot`, and copying the image out into a `T`.
+///
+/// Beyond that, the image is used as-is, so only types trusted with their images can be read,
+/// see `TrustedImage`.
+pub fn deserialize<'de, T: TrustedImage, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+    deserializer.deserialize_bytes(ImageVisitor(PhantomData))
+}
+
+struct ImageVisitor<T>(PhantomData<fn() -> T>);
+
+impl<'de, T: TrustedImage> Visitor<'de> for ImageVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("the bytes of a compact snapshot")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<T, E> {
+        let mut reader = bytes;
+        // the header is checked by `read_snapshot`, the image is trusted through `T: TrustedImage`
+        let value = unsafe { read_snapshot(&mut reader) }.map_err(E::custom)?;
+        if !reader.is_empty() {
+            return Err(E::invalid_length(bytes.len(), &self));
+        }
+        Ok(value)
+    }
+
+    // for formats that encode bytes as sequences of numbers
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut access: A) -> Result<T, A::Error> {
+        let mut bytes = Vec::with_capacity(access.size_hint().unwrap_or(0));
+        while let Some(byte) = access.next_element::<u8>()? {
+            bytes.push(byte);
+        }
+        self.visit_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+unsafe impl TrustedImage for super::compact_vec::CompactVec<super::compact_str::CompactString> {}
+#[cfg(test)]
+unsafe impl TrustedImage for super::compact_vec::CompactVec<u64> {}
+
+/// A format that only knows bytes and captures them
+#[cfg(test)]
+struct CaptureBytes;
+
+#[cfg(test)]
+macro_rules! refuse {
+    ($($method:ident($($arg:ty),*) -> $ok:ty;)*) => {$(
+        fn $method(self, $(_: $arg),*) -> Result<$ok, Self::Error> {
+            Err(ser::Error::custom("only bytes are captured"))
+        }
+    )*};
+}
+
+#[cfg(test)]
+impl Serializer for CaptureBytes {
+    type Ok = Vec<u8>;
+    type Error = serde::de::value::Error;
+    type SerializeSeq = ser::Impossible<Vec<u8>, Self::Error>;
+    type SerializeTuple = ser::Impossible<Vec<u8>, Self::Error>;
+    type SerializeTupleStruct = ser::Impossible<Vec<u8>, Self::Error>;
+    type SerializeTupleVariant = ser::Impossible<Vec<u8>, Self::Error>;
+    type SerializeMap = ser::Impossible<Vec<u8>, Self::Error>;
+    type SerializeStruct = ser::Impossible<Vec<u8>, Self::Error>;
+    type SerializeStructVariant = ser::Impossible<Vec<u8>, Self::Error>;
+
+    fn serialize_bytes(self, bytes: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        Ok(bytes.to_vec())
+    }
+
+    fn serialize_some<V: ?Sized + ser::Serialize>(self, _: &V) -> Result<Vec<u8>, Self::Error> {
+        Err(ser::Error::custom("only bytes are captured"))
+    }
+
+    fn serialize_newtype_struct<V: ?Sized + ser::Serialize>(self, _: &'static str, _: &V) -> Result<Vec<u8>, Self::Error> {
+        Err(ser::Error::custom("only bytes are captured"))
+    }
+
+    fn serialize_newtype_variant<V: ?Sized + ser::Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &V,
+    ) -> Result<Vec<u8>, Self::Error> {
+        Err(ser::Error::custom("only bytes are captured"))
+    }
+
+    refuse! {
+        serialize_bool(bool) -> Vec<u8>;
+        serialize_i8(i8) -> Vec<u8>;
+        serialize_i16(i16) -> Vec<u8>;
+        serialize_i32(i32) -> Vec<u8>;
+        serialize_i64(i64) -> Vec<u8>;
+        serialize_u8(u8) -> Vec<u8>;
+        serialize_u16(u16) -> Vec<u8>;
+        serialize_u32(u32) -> Vec<u8>;
+        serialize_u64(u64) -> Vec<u8>;
+        serialize_f32(f32) -> Vec<u8>;
+        serialize_f64(f64) -> Vec<u8>;
+        serialize_char(char) -> Vec<u8>;
+        serialize_str(&str) -> Vec<u8>;
+        serialize_none() -> Vec<u8>;
+        serialize_unit() -> Vec<u8>;
+        serialize_unit_struct(&'static str) -> Vec<u8>;
+        serialize_unit_variant(&'static str, u32, &'static str) -> Vec<u8>;
+        serialize_seq(Option<usize>) -> Self::SerializeSeq;
+        serialize_tuple(usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Self::SerializeTupleVariant;
+        serialize_map(Option<usize>) -> Self::SerializeMap;
+        serialize_struct(&'static str, usize) -> Self::SerializeStruct;
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Self::SerializeStructVariant;
+    }
+}
+
+#[test]
+fn images_roundtrip_as_bytes() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    use serde::de::value::{BytesDeserializer, Error as ValueError};
+    use serde::de::IntoDeserializer;
+
+    let value: CompactVec<CompactString> = vec!["in".to_owned().into(), "envelope".to_owned().into()].into();
+    let mut bytes = serialize(&value, CaptureBytes).unwrap();
+    let mut snapshot = Vec::new();
+    write_snapshot(&value, &mut snapshot).unwrap();
+    assert_eq!(snapshot, bytes);
+
+    let loaded: CompactVec<CompactString> = deserialize(BytesDeserializer::<ValueError>::new(&bytes)).unwrap();
+    assert_eq!(vec!["in", "envelope"], loaded.iter().map(|s| &**s).collect::<Vec<_>>());
+
+    let numbers = IntoDeserializer::<ValueError>::into_deserializer(bytes.clone());
+    let loaded: CompactVec<CompactString> = deserialize(numbers).unwrap();
+    assert_eq!(2, loaded.len());
+
+    let result: Result<CompactVec<u64>, _> = deserialize(BytesDeserializer::<ValueError>::new(&bytes));
+    assert!(result.unwrap_err().to_string().contains("type"));
+    bytes.push(0);
+    let result: Result<CompactVec<CompactString>, _> = deserialize(BytesDeserializer::<ValueError>::new(&bytes));
+    assert!(result.is_err());
+}
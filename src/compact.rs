@@ -1,4 +1,9 @@
+use super::compact_box::Image;
+use super::inspect::{LayoutNode, Storage};
+use std::hash::Hasher;
+use std::io::{self, Write};
 use std::mem;
+use std::ptr;
 
 /// A trait for objects with a statically-sized part and a potential dynamically-sized part
 /// that can be stored both compactly in consecutive memory or freely on the heap
@@ -33,6 +38,38 @@ pub trait Compact: Sized + Clone {
         Self::compact(source, dest, behind_dest)
     }
 
+    /// Size of the dynamic part in bytes when compacted using `compact_tight`
+    fn dynamic_size_bytes_tight(&self) -> usize {
+        self.dynamic_size_bytes()
+    }
+
+    /// Total size of the object when compacted using `compact_tight`
+    fn total_size_bytes_tight(&self) -> usize {
+        self.dynamic_size_bytes_tight() + mem::size_of::<Self>()
+    }
+
+    /// Like `compact`, but trims the capacity of all containers to their length,
+    /// so that the new dynamic part only takes `dynamic_size_bytes_tight()` bytes.
+    ///
+    /// Containers override this together with `dynamic_size_bytes_tight`,
+    /// by default it is the same as `compact`.
+    ///
+    /// # Safety
+    /// Like `compact`: `source` and `dest` have to be valid, `new_dynamic_part` has to have room
+    /// for `dynamic_size_bytes_tight()` bytes, and `source` must not be used afterwards.
+    unsafe fn compact_tight(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        Self::compact(source, dest, new_dynamic_part)
+    }
+
+    /// Like `compact_tight` with `new_dynamic_part` set to `dest.behind()`
+    ///
+    /// # Safety
+    /// Like `compact_tight`, with room for `total_size_bytes_tight()` bytes at `dest`.
+    unsafe fn compact_behind_tight(source: *mut Self, dest: *mut Self) {
+        let behind_dest = Self::behind(dest);
+        Self::compact_tight(source, dest, behind_dest)
+    }
+
     /// Creates a clone of self with the dynamic part guaranteed to be stored freely.
     ///
     /// *Note:* if the dynamic part was already stored freely, the calling environment
@@ -41,6 +78,201 @@ pub trait Compact: Sized + Clone {
     /// This is mostly used internally to correctly implement
     /// `Compact` datastructures that contain `Compact` elements.
     unsafe fn decompact(source: *const Self) -> Self;
+
+    /// A fingerprint of the type's structure, used to refuse loading persisted
+    /// images that were produced by a different layout.
+    ///
+    /// Implementations combine an explicit name and their layout with the fingerprints
+    /// of their fields or elements, using `structural_fingerprint`. The name should not
+    /// come from `std::any::type_name`, which can change between compiler versions and
+    /// when moving the type to another module.
+    fn type_fingerprint() -> u64;
+
+    /// Do `compact_static_to` and `write_dynamic_compact` stream a compact image of this type
+    /// directly from `self`? If not, their defaults compact a clone of `self` into a buffer
+    /// for each call, so callers streaming a whole image should rather buffer it once.
+    fn streams_compact() -> bool {
+        false
+    }
+
+    /// Write the static part of a compact image of `self` to `dest`, with pointers set up
+    /// as if `dest` was stored at byte `at` of the image, and its dynamic part at byte `dynamic_at`.
+    ///
+    /// See `write_static_compact`. The default compacts a clone of `self` into a buffer.
+    ///
+    /// # Safety
+    /// `dest` has to be valid for writing a `Self`, which is only meaningful as part of
+    /// an image and must never be used or dropped as a value.
+    unsafe fn compact_static_to(&self, dest: *mut Self, at: usize, dynamic_at: usize) {
+        let (image, lead) = compact_clone(self, at, dynamic_at);
+        ptr::copy_nonoverlapping((image.ptr() as *const u8).add(lead) as *const Self, dest, 1);
+    }
+
+    /// Stream the dynamic part of a compact image of `self`, stored at byte `dynamic_at`
+    /// of the image. Has to write exactly `dynamic_size_bytes()` bytes.
+    ///
+    /// The default compacts a clone of `self` into a buffer, like `compact_static_to`.
+    fn write_dynamic_compact(&self, dynamic_at: usize, out: &mut dyn Write) -> io::Result<()> {
+        let static_size = mem::size_of::<Self>();
+        let (image, lead) = compact_clone(self, dynamic_at - static_size, dynamic_at);
+        let dynamic_part = &image.bytes()[lead + static_size..];
+        out.write_all(dynamic_part)?;
+        // clones can have less spare capacity than `self`
+        write_zeros(self.dynamic_size_bytes() - dynamic_part.len(), out)
+    }
+
+    /// Does this type or any of its parts store allocator handles that aren't zero-sized?
+    /// Such handles are only valid in the process that created them, so images of this type
+    /// are refused when persisting them or sending them to other processes.
+    ///
+    /// Containers forward this to their elements, types containing containers should too.
+    fn uses_instance_handles() -> bool {
+        false
+    }
+
+    /// The largest alignment needed by this type or anything in its dynamic part.
+    /// Compact images are allocated with this alignment, so that values aligned
+    /// by their position in an image are aligned in memory as well.
+    ///
+    /// Containers forward this to their elements, types containing containers should too.
+    fn max_align() -> usize {
+        mem::align_of::<Self>()
+    }
+
+    /// Reserve spare capacity in all containers of `self` according to `headroom`,
+    /// so that they can grow a bit without spilling once compacted again.
+    ///
+    /// Types containing containers should forward this to them, the default does nothing.
+    fn reserve_headroom(&mut self, _headroom: Headroom) {}
+
+    /// Describe where the static and dynamic parts of `self` and its parts are stored,
+    /// for debugging. Containers override this, the default only reports the sizes.
+    fn inspect_layout(&self) -> LayoutNode {
+        LayoutNode::new(self, Storage::None)
+    }
+}
+
+/// How much spare capacity each container gets when recompacting,
+/// see `Compact::reserve_headroom` and `CompactBox::recompact_with_headroom`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Headroom {
+    /// Spare capacity relative to the current length, like `0.25` for 25%
+    pub ratio: f32,
+    /// Minimum spare capacity, in elements
+    pub min: usize,
+}
+
+impl Headroom {
+    /// Spare capacity for a container of length `len`
+    pub fn spare_for(&self, len: usize) -> usize {
+        ::std::cmp::max(self.min, (len as f32 * self.ratio).ceil() as usize)
+    }
+}
+
+/// Fail if images of `T` can't leave this process, see `Compact::uses_instance_handles`
+pub(crate) fn check_handles_can_leave_process<T: Compact>() -> io::Result<()> {
+    if T::uses_instance_handles() {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "images of types storing allocator handles can't leave the process",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Compact a clone of `value` into a new image, placed like `value` stored at byte `at`
+/// of an image with its dynamic part at byte `dynamic_at`, so that it is padded the same.
+/// Returns the image and the position of the clone in it.
+fn compact_clone<T: Compact>(value: &T, at: usize, dynamic_at: usize) -> (Image<T>, usize) {
+    let lead = at % T::max_align();
+    let mut source = value.clone();
+    let image = Image::new(lead + dynamic_at - at + source.dynamic_size_bytes());
+    unsafe {
+        let static_part = (image.ptr() as *mut u8).add(lead);
+        Compact::compact(&mut source, static_part as *mut T, static_part.add(dynamic_at - at));
+        mem::forget(source);
+    }
+    (image, lead)
+}
+
+/// Stream the static part of a compact image of `value`, stored at byte `at` of the image,
+/// with its dynamic part at byte `dynamic_at`
+pub fn write_static_compact<T: Compact>(
+    value: &T,
+    at: usize,
+    dynamic_at: usize,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let mut image = mem::MaybeUninit::<T>::zeroed();
+    let bytes = unsafe {
+        value.compact_static_to(image.as_mut_ptr(), at, dynamic_at);
+        ::std::slice::from_raw_parts(image.as_ptr() as *const u8, mem::size_of::<T>())
+    };
+    // the image is never dropped, since it only borrows the dynamic part of `value`
+    out.write_all(bytes)
+}
+
+/// Position in the image of `field`, if its containing struct `dest` is stored at byte `at`
+pub fn field_position<S, F>(dest: *const S, field: *const F, at: usize) -> usize {
+    at + (field as usize - dest as usize)
+}
+
+/// Write `n` zero bytes, used for unused capacity and padding in streamed images
+pub fn write_zeros(n: usize, out: &mut dyn Write) -> io::Result<()> {
+    const ZEROS: [u8; 64] = [0; 64];
+    let mut left = n;
+    while left > 0 {
+        let chunk = ::std::cmp::min(left, ZEROS.len());
+        out.write_all(&ZEROS[..chunk])?;
+        left -= chunk;
+    }
+    Ok(())
+}
+
+/// Combine `name`, size and alignment of `T` and the fingerprints of its `components`
+/// into a fingerprint that is stable across runs, builds and platforms with the same layout,
+/// as long as `name` and the `components` are (64-bit FNV-1a)
+pub fn structural_fingerprint<T>(name: &str, components: &[u64]) -> u64 {
+    let mut hasher = StableHasher::default();
+    hasher.write(name.as_bytes());
+    hasher.write(&(mem::size_of::<T>() as u64).to_le_bytes());
+    hasher.write(&(mem::align_of::<T>() as u64).to_le_bytes());
+    for component in components {
+        hasher.write(&component.to_le_bytes());
+    }
+    hasher.finish()
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is guaranteed
+/// to hash the same bytes the same way in every build
+pub(crate) struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> StableHasher {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Fingerprint of the allocator `A` of a container, to include in its `Compact::type_fingerprint`.
+///
+/// Allocators have no explicit names, so this uses their type name, which can change
+/// between compiler versions, like the fallback for `Copy` types.
+pub(crate) fn allocator_fingerprint<A: ::allocator::InstanceAllocator>() -> u64 {
+    structural_fingerprint::<A::Handle>(::std::any::type_name::<A>(), &[])
 }
 
 /// Trivial implementation for fixed-sized, `Copy` types (no dynamic part)
@@ -58,4 +290,45 @@ impl<T: Copy> Compact for T {
     default unsafe fn decompact(source: *const Self) -> Self {
         *source
     }
+
+    default fn streams_compact() -> bool {
+        true
+    }
+
+    default unsafe fn compact_static_to(&self, dest: *mut Self, _at: usize, _dynamic_at: usize) {
+        ptr::write(dest, *self)
+    }
+
+    default fn write_dynamic_compact(&self, _dynamic_at: usize, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Falls back to the type name, which isn't guaranteed to be unique or to stay the same
+    /// between compiler versions, and doesn't change when fields of the same size do.
+    /// Primitives have explicit names instead.
+    default fn type_fingerprint() -> u64 {
+        structural_fingerprint::<Self>(::std::any::type_name::<Self>(), &[])
+    }
+}
+
+macro_rules! impl_primitive_fingerprint {
+    ($($primitive:ident),*) => {
+        $(
+            impl Compact for $primitive {
+                fn type_fingerprint() -> u64 {
+                    structural_fingerprint::<$primitive>(stringify!($primitive), &[])
+                }
+            }
+        )*
+    };
+}
+
+impl_primitive_fingerprint!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+impl_primitive_fingerprint!(f32, f64, bool, char);
+
+#[test]
+fn primitive_fingerprints_are_stable() {
+    // FNV-1a of the name, followed by size and alignment as little-endian `u64`s
+    assert_eq!(0x9764_b957_2e2e_ab91, <u32 as Compact>::type_fingerprint());
+    assert!(<u32 as Compact>::type_fingerprint() != <f32 as Compact>::type_fingerprint());
 }
@@ -0,0 +1,256 @@
+use super::compact::{Compact, Headroom};
+use std::alloc::{self, Layout};
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+
+/// Zeroed heap memory aligned for a `T` and everything in its dynamic part
+/// (see `Compact::max_align`), holding a compact image of it.
+/// Never drops its contents.
+pub struct Image<T> {
+    ptr: *mut u8,
+    size: usize,
+    align: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+// an image owns its bytes like a `Box<T>` owns its value
+unsafe impl<T: Send> Send for Image<T> {}
+unsafe impl<T: Sync> Sync for Image<T> {}
+
+impl<T: Compact> Image<T> {
+    /// Allocate an image of `size` bytes, which has to be at least the static size of `T`
+    pub fn new(size: usize) -> Image<T> {
+        assert!(size >= mem::size_of::<T>(), "image is smaller than its static part");
+        let align = T::max_align();
+        let ptr = unsafe { alloc::alloc_zeroed(Self::layout(size, align)) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(Self::layout(size, align));
+        }
+        Image {
+            ptr,
+            size,
+            align,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Image<T> {
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size.max(1), align).expect("image too large")
+    }
+
+    /// Pointer to the static part of the image
+    pub fn ptr(&self) -> *mut T {
+        self.ptr as *mut T
+    }
+
+    /// The raw bytes of the image
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { ::std::slice::from_raw_parts(self.ptr, self.size) }
+    }
+
+    /// The raw bytes of the image, mutably
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { ::std::slice::from_raw_parts_mut(self.ptr, self.size) }
+    }
+}
+
+impl<T> Drop for Image<T> {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, Self::layout(self.size, self.align)) }
+    }
+}
+
+/// An owned `T` whose static and dynamic parts are stored compactly in one
+/// heap allocation, like the result of `Compact::compact_behind`.
+///
+/// Mutating the value can make its dynamic part spill onto the heap, just like
+/// for any other compact value.
+pub struct CompactBox<T: Compact> {
+    image: Image<T>,
+}
+
+impl<T: Compact> CompactBox<T> {
+    /// Compact `value` into a new allocation of exactly its total size
+    pub fn new(mut value: T) -> CompactBox<T> {
+        let image = Image::new(value.total_size_bytes());
+        unsafe {
+            Compact::compact_behind(&mut value, image.ptr());
+            mem::forget(value);
+            Self::from_image(image)
+        }
+    }
+
+    /// Compact `value` into a new allocation of exactly its tight total size,
+    /// trimming the capacity of all its containers to their length, see `Compact::compact_tight`
+    pub fn new_tight(mut value: T) -> CompactBox<T> {
+        let image = Image::new(value.total_size_bytes_tight());
+        unsafe {
+            Compact::compact_behind_tight(&mut value, image.ptr());
+            mem::forget(value);
+            Self::from_image(image)
+        }
+    }
+
+    /// Take ownership of a compact image of a `T`
+    ///
+    /// # Safety
+    /// `image` has to contain a valid compact `T`
+    pub unsafe fn from_image(image: Image<T>) -> CompactBox<T> {
+        CompactBox { image }
+    }
+
+    /// Copy a compact image of a `T` out of `bytes`
+    ///
+    /// # Safety
+    /// `bytes` has to be a valid compact image of a `T`, as returned by `as_bytes`
+    pub unsafe fn from_bytes(bytes: &[u8]) -> CompactBox<T> {
+        let mut image = Image::new(bytes.len());
+        image.bytes_mut().copy_from_slice(bytes);
+        Self::from_image(image)
+    }
+
+    /// Read a compact image of a `T` of `size` bytes from `reader`
+    ///
+    /// # Safety
+    /// The bytes read have to be a valid compact image of a `T`
+    pub unsafe fn read_from<R: Read>(reader: &mut R, size: usize) -> io::Result<CompactBox<T>> {
+        if size < mem::size_of::<T>() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "compact image is smaller than its static part",
+            ));
+        }
+        let mut image = Image::new(size);
+        reader.read_exact(image.bytes_mut())?;
+        Ok(Self::from_image(image))
+    }
+
+    /// The compact image, which can be sent or stored and turned back
+    /// into a `CompactBox` using `from_bytes`.
+    ///
+    /// Panics if the value spilled out of its compact image after being mutated.
+    pub fn as_bytes(&self) -> &[u8] {
+        assert!(
+            self.is_still_compact(),
+            "value isn't stored compactly anymore"
+        );
+        self.image.bytes()
+    }
+
+    /// If the value spilled out of its compact image after being mutated,
+    /// move it into a new image of exactly its total size. Returns whether it was moved.
+    pub fn recompact(&mut self) -> bool {
+        self.recompact_with_headroom(Headroom::default())
+    }
+
+    /// Like `recompact`, but first reserves spare capacity in all of the value's
+    /// containers according to `headroom`, so that they can grow a bit
+    /// without spilling again
+    pub fn recompact_with_headroom(&mut self, headroom: Headroom) -> bool {
+        if self.is_still_compact() {
+            return false;
+        }
+        (**self).reserve_headroom(headroom);
+        let image = Image::new(self.total_size_bytes());
+        unsafe {
+            // moves parts still stored in the old image as well as spilled ones
+            Compact::compact_behind(self.image.ptr(), image.ptr());
+        }
+        // the value was moved, only free the old image
+        self.image = image;
+        true
+    }
+
+    /// Move the value out, decompacting it
+    pub fn into_inner(self) -> T {
+        unsafe {
+            let value = Compact::decompact(self.image.ptr());
+            // the value was moved out, only free the image
+            let image = ptr::read(&self.image);
+            mem::forget(self);
+            drop(image);
+            value
+        }
+    }
+}
+
+impl<T: Compact> Deref for CompactBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.image.ptr() }
+    }
+}
+
+impl<T: Compact> DerefMut for CompactBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.image.ptr() }
+    }
+}
+
+impl<T: Compact> Drop for CompactBox<T> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.image.ptr()) }
+    }
+}
+
+impl<T: Compact> Clone for CompactBox<T> {
+    fn clone(&self) -> CompactBox<T> {
+        CompactBox::new((**self).clone())
+    }
+}
+
+impl<T: Compact + ::std::fmt::Debug> ::std::fmt::Debug for CompactBox<T> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[test]
+fn boxed_compact_value() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    let value: CompactVec<CompactString> = vec!["a".to_owned().into(), "b".to_owned().into()].into();
+    let total_size = value.total_size_bytes();
+    let mut boxed = CompactBox::new(value);
+    assert!(boxed.is_still_compact());
+    assert_eq!(total_size, boxed.as_bytes().len());
+
+    let copied = unsafe { CompactBox::<CompactVec<CompactString>>::from_bytes(boxed.as_bytes()) };
+    assert_eq!("b", &*copied[1]);
+
+    // spills to the heap
+    boxed.push("c".to_owned().into());
+    assert!(!boxed.is_still_compact());
+    let unboxed = boxed.into_inner();
+    assert_eq!("c", &*unboxed[2]);
+}
+
+#[test]
+fn recompact_with_headroom() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    let value: CompactVec<CompactString> = vec!["a".to_owned().into(), "b".to_owned().into()].into();
+    let mut boxed = CompactBox::new(value);
+    assert!(!boxed.recompact());
+
+    boxed.push("c".to_owned().into());
+    boxed[0].push_str("aa");
+    assert!(!boxed.is_still_compact());
+    let headroom = Headroom { ratio: 0.5, min: 1 };
+    assert!(boxed.recompact_with_headroom(headroom));
+    assert!(boxed.is_still_compact());
+    assert_eq!(boxed.total_size_bytes(), boxed.as_bytes().len());
+    assert_eq!(&["aaa", "b", "c"], &boxed.iter().map(|s| &**s).collect::<Vec<_>>()[..]);
+    assert!(boxed.capacity() >= 5);
+
+    // the next pushes stay compact
+    boxed.push(CompactString::new());
+    boxed[1].push_str("b");
+    assert!(boxed.is_still_compact());
+}
@@ -1,34 +1,59 @@
-use super::simple_allocator_trait::{Allocator, DefaultHeap};
-use super::compact::Compact;
+use super::allocator::InstanceAllocator;
+use super::simple_allocator_trait::DefaultHeap;
+use super::compact::{Compact, Headroom, structural_fingerprint, allocator_fingerprint, field_position};
+use super::inspect::{LayoutNode, Storage};
 use super::compact_vec::CompactVec;
+use super::compact_hash_map::{hash_unordered, sorted_pairs};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
 
 /// A simple linear-search key-value dictionary,
 /// implemented using two `CompactVec`'s, one for keys, one for values.
 ///
 /// The API loosely follows that of `std::collections::HashMap`.
-/// Spilling behaviour using `Allocator` is equivalent to `CompactVec`.
-pub struct CompactDict<K: Copy, V: Compact + Clone, A: Allocator = DefaultHeap> {
+/// Spilling behaviour using `InstanceAllocator` is equivalent to `CompactVec`.
+pub struct CompactDict<K: Copy, V: Compact + Clone, A: InstanceAllocator = DefaultHeap> {
     keys: CompactVec<K, A>,
     values: CompactVec<V, A>,
 }
 
-impl<K: Eq + Copy, V: Compact + Clone, A: Allocator> CompactDict<K, V, A> {
+impl<K: Eq + Copy, V: Compact + Clone, A: InstanceAllocator> CompactDict<K, V, A>
+where
+    A::Handle: Default,
+{
     /// Create new, empty dictionary
     pub fn new() -> Self {
-        CompactDict {
-            keys: CompactVec::new(),
-            values: CompactVec::new(),
-        }
+        Self::new_in(A::Handle::default())
     }
 
     /// Create new, empty dictionary with a given capactity
     pub fn with_capacity(cap: usize) -> Self {
+        Self::with_capacity_in(cap, A::Handle::default())
+    }
+}
+
+impl<K: Eq + Copy, V: Compact + Clone, A: InstanceAllocator> CompactDict<K, V, A> {
+    /// Create new, empty dictionary that allocates using `alloc`
+    pub fn new_in(alloc: A::Handle) -> Self {
         CompactDict {
-            keys: CompactVec::with_capacity(cap),
-            values: CompactVec::with_capacity(cap),
+            keys: CompactVec::new_in(alloc.clone()),
+            values: CompactVec::new_in(alloc),
         }
     }
 
+    /// Create new, empty dictionary with a given capactity, allocated using `alloc`
+    pub fn with_capacity_in(cap: usize, alloc: A::Handle) -> Self {
+        CompactDict {
+            keys: CompactVec::with_capacity_in(cap, alloc.clone()),
+            values: CompactVec::with_capacity_in(cap, alloc),
+        }
+    }
+
+    /// The handle of the allocator used for free heap storage
+    pub fn allocator(&self) -> &A::Handle {
+        self.keys.allocator()
+    }
+
     /// Amount of entries in the dictionary
     pub fn len(&self) -> usize {
         self.keys.len()
@@ -143,7 +168,10 @@ impl<K: Eq + Copy, V: Compact + Clone, A: Allocator> CompactDict<K, V, A> {
     }
 }
 
-impl<K: Eq + Copy, I: Compact, A1: Allocator, A2: Allocator> CompactDict<K, CompactVec<I, A1>, A2> {
+impl<K: Eq + Copy, I: Compact, A1: InstanceAllocator, A2: InstanceAllocator> CompactDict<K, CompactVec<I, A1>, A2>
+where
+    A1::Handle: Default,
+{
     /// Push a value onto the `CompactVec` at the key `query`
     pub fn push_at(&mut self, query: K, item: I) {
         for i in 0..self.keys.len() {
@@ -173,7 +201,7 @@ impl<K: Eq + Copy, I: Compact, A1: Allocator, A2: Allocator> CompactDict<K, Comp
     }
 }
 
-impl<K: Copy, V: Compact + Clone, A: Allocator> Compact for CompactDict<K, V, A> {
+impl<K: Copy, V: Compact + Clone, A: InstanceAllocator> Compact for CompactDict<K, V, A> {
     fn is_still_compact(&self) -> bool {
         self.keys.is_still_compact() && self.values.is_still_compact()
     }
@@ -192,15 +220,76 @@ impl<K: Copy, V: Compact + Clone, A: Allocator> Compact for CompactDict<K, V, A>
         );
     }
 
+    fn dynamic_size_bytes_tight(&self) -> usize {
+        self.keys.dynamic_size_bytes_tight() + self.values.dynamic_size_bytes_tight()
+    }
+
+    unsafe fn compact_tight(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let values_offset = (*source).keys.dynamic_size_bytes_tight() as isize;
+        Compact::compact_tight(&mut (*source).keys, &mut (*dest).keys, new_dynamic_part);
+        Compact::compact_tight(
+            &mut (*source).values,
+            &mut (*dest).values,
+            new_dynamic_part.offset(values_offset),
+        );
+    }
+
     unsafe fn decompact(source: *const Self) -> CompactDict<K, V, A> {
         CompactDict {
             keys: Compact::decompact(&(*source).keys),
             values: Compact::decompact(&(*source).values),
         }
     }
+
+    fn reserve_headroom(&mut self, headroom: Headroom) {
+        self.keys.reserve_headroom(headroom);
+        self.values.reserve_headroom(headroom);
+    }
+
+    fn inspect_layout(&self) -> LayoutNode {
+        LayoutNode::new(self, Storage::None)
+            .child("keys", self.keys.inspect_layout())
+            .child("values", self.values.inspect_layout())
+    }
+
+    fn type_fingerprint() -> u64 {
+        structural_fingerprint::<Self>(
+            "CompactDict",
+            &[K::type_fingerprint(), V::type_fingerprint(), allocator_fingerprint::<A>()],
+        )
+    }
+
+    fn streams_compact() -> bool {
+        V::streams_compact()
+    }
+
+    fn uses_instance_handles() -> bool {
+        CompactVec::<V, A>::uses_instance_handles()
+    }
+
+    fn max_align() -> usize {
+        ::std::cmp::max(CompactVec::<K, A>::max_align(), CompactVec::<V, A>::max_align())
+    }
+
+    unsafe fn compact_static_to(&self, dest: *mut Self, at: usize, dynamic_at: usize) {
+        let keys_at = field_position(dest, &(*dest).keys, at);
+        let values_at = field_position(dest, &(*dest).values, at);
+        self.keys.compact_static_to(&mut (*dest).keys, keys_at, dynamic_at);
+        self.values.compact_static_to(
+            &mut (*dest).values,
+            values_at,
+            dynamic_at + self.keys.dynamic_size_bytes(),
+        );
+    }
+
+    fn write_dynamic_compact(&self, dynamic_at: usize, out: &mut dyn Write) -> io::Result<()> {
+        self.keys.write_dynamic_compact(dynamic_at, out)?;
+        self.values
+            .write_dynamic_compact(dynamic_at + self.keys.dynamic_size_bytes(), out)
+    }
 }
 
-impl<K: Copy, V: Compact + Clone, A: Allocator> Clone for CompactDict<K, V, A> {
+impl<K: Copy, V: Compact + Clone, A: InstanceAllocator> Clone for CompactDict<K, V, A> {
     fn clone(&self) -> Self {
         CompactDict {
             keys: self.keys.clone(),
@@ -209,14 +298,19 @@ impl<K: Copy, V: Compact + Clone, A: Allocator> Clone for CompactDict<K, V, A> {
     }
 }
 
-impl<K: Copy + Eq, V: Compact + Clone, A: Allocator> Default for CompactDict<K, V, A> {
+impl<K: Copy + Eq, V: Compact + Clone, A: InstanceAllocator> Default for CompactDict<K, V, A>
+where
+    A::Handle: Default,
+{
     fn default() -> Self {
         CompactDict::new()
     }
 }
 
-impl<K: Copy + Eq, V: Compact + Clone, A: Allocator> ::std::iter::FromIterator<(K, V)>
+impl<K: Copy + Eq, V: Compact + Clone, A: InstanceAllocator> ::std::iter::FromIterator<(K, V)>
     for CompactDict<K, V, A>
+where
+    A::Handle: Default,
 {
     /// Construct a compact dictionary from an interator over key-value pairs
     fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
@@ -228,7 +322,7 @@ impl<K: Copy + Eq, V: Compact + Clone, A: Allocator> ::std::iter::FromIterator<(
     }
 }
 
-impl<K: Copy + Eq, V: Compact + Clone, A: Allocator> ::std::iter::Extend<(K, V)>
+impl<K: Copy + Eq, V: Compact + Clone, A: InstanceAllocator> ::std::iter::Extend<(K, V)>
     for CompactDict<K, V, A>
 {
     /// Extend a compact dictionary from an iterator over key-value pairs
@@ -239,11 +333,51 @@ impl<K: Copy + Eq, V: Compact + Clone, A: Allocator> ::std::iter::Extend<(K, V)>
     }
 }
 
+impl<K, V, A, B> PartialEq<CompactDict<K, V, B>> for CompactDict<K, V, A>
+where
+    K: Copy + Eq,
+    V: Compact + Clone + PartialEq,
+    A: InstanceAllocator,
+    B: InstanceAllocator,
+{
+    /// Dictionaries are equal if they contain the same pairs, independent of insertion order
+    fn eq(&self, other: &CompactDict<K, V, B>) -> bool {
+        self.len() == other.len() && self.pairs().all(|(key, value)| other.get(*key) == Some(value))
+    }
+}
+
+impl<K: Copy + Eq, V: Compact + Clone + Eq, A: InstanceAllocator> Eq for CompactDict<K, V, A> {}
+
+impl<K, V, A, B> PartialOrd<CompactDict<K, V, B>> for CompactDict<K, V, A>
+where
+    K: Copy + Ord,
+    V: Compact + Clone + PartialOrd,
+    A: InstanceAllocator,
+    B: InstanceAllocator,
+{
+    /// Dictionaries are ordered like their pairs sorted by key, like a `BTreeMap`
+    fn partial_cmp(&self, other: &CompactDict<K, V, B>) -> Option<::std::cmp::Ordering> {
+        sorted_pairs(self.pairs()).partial_cmp(&sorted_pairs(other.pairs()))
+    }
+}
+
+impl<K: Copy + Ord, V: Compact + Clone + Ord, A: InstanceAllocator> Ord for CompactDict<K, V, A> {
+    fn cmp(&self, other: &Self) -> ::std::cmp::Ordering {
+        sorted_pairs(self.pairs()).cmp(&sorted_pairs(other.pairs()))
+    }
+}
+
+impl<K: Copy + Eq + Hash, V: Compact + Clone + Hash, A: InstanceAllocator> Hash for CompactDict<K, V, A> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_unordered(self.pairs(), state)
+    }
+}
+
 impl<K, V, A> ::std::fmt::Debug for CompactDict<K, V, A>
 where
     K: Copy + Eq + ::std::fmt::Debug,
     V: Compact + ::std::fmt::Debug,
-    A: Allocator,
+    A: InstanceAllocator,
 {
     fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         fmt.debug_map().entries(self.pairs()).finish()
@@ -253,14 +387,18 @@ where
 #[cfg(feature = "serde-serialization")]
 use serde::ser::SerializeMap;
 #[cfg(feature = "serde-serialization")]
+use super::deserialize::{DeserializeCompact, DeserializePlan, Measure, WriteInto};
+#[cfg(feature = "serde-serialization")]
 use std::marker::PhantomData;
+#[cfg(feature = "serde-serialization")]
+use std::{mem, ptr};
 
 #[cfg(feature = "serde-serialization")]
 impl<K, V, A> ::serde::Serialize for CompactDict<K, V, A>
 where
     K: Copy + Eq + ::serde::Serialize,
     V: Compact + ::serde::Serialize,
-    A: Allocator,
+    A: InstanceAllocator,
 {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -275,12 +413,12 @@ where
 }
 
 #[cfg(feature = "serde-serialization")]
-struct CompactDictVisitor<K: Copy, V: Compact, A: Allocator> {
+struct CompactDictVisitor<K: Copy, V: Compact, A: InstanceAllocator> {
     marker: PhantomData<fn() -> CompactDict<K, V, A>>,
 }
 
 #[cfg(feature = "serde-serialization")]
-impl<K: Copy, V: Compact, A: Allocator> CompactDictVisitor<K, V, A> {
+impl<K: Copy, V: Compact, A: InstanceAllocator> CompactDictVisitor<K, V, A> {
     fn new() -> Self {
         CompactDictVisitor {
             marker: PhantomData,
@@ -293,7 +431,8 @@ impl<'de, K, V, A> ::serde::de::Visitor<'de> for CompactDictVisitor<K, V, A>
 where
     K: Copy + Eq + ::serde::de::Deserialize<'de>,
     V: Compact + ::serde::de::Deserialize<'de>,
-    A: Allocator,
+    A: InstanceAllocator,
+    A::Handle: Default,
 {
     type Value = CompactDict<K, V, A>;
 
@@ -320,7 +459,8 @@ impl<'de, K, V, A> ::serde::de::Deserialize<'de> for CompactDict<K, V, A>
 where
     K: Copy + Eq + ::serde::de::Deserialize<'de>,
     V: Compact + ::serde::de::Deserialize<'de>,
-    A: Allocator,
+    A: InstanceAllocator,
+    A::Handle: Default,
 {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -330,6 +470,123 @@ where
     }
 }
 
+#[cfg(feature = "serde-serialization")]
+struct MeasureEntries<'p, K, V> {
+    plan: &'p mut DeserializePlan,
+    marker: PhantomData<fn() -> (K, V)>,
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'de, 'p, K, V> ::serde::de::Visitor<'de> for MeasureEntries<'p, K, V>
+where
+    K: Copy + ::serde::de::Deserialize<'de>,
+    V: DeserializeCompact<'de>,
+{
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        formatter.write_str("A Compact Hash Map")
+    }
+
+    fn visit_map<M>(self, mut access: M) -> Result<usize, M::Error>
+    where
+        M: ::serde::de::MapAccess<'de>,
+    {
+        let reserved = self.plan.reserve_length();
+        let mut len = 0;
+        let mut values_size = 0;
+        while access.next_key::<K>()?.is_some() {
+            values_size += access.next_value_seed(Measure::<V>::new(self.plan))?;
+            len += 1;
+        }
+        self.plan.set_length(reserved, len);
+        Ok(len * (mem::size_of::<K>() + mem::size_of::<V>()) + values_size)
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+struct WriteEntries<'p, K: Copy, V: Compact, A: InstanceAllocator> {
+    plan: &'p mut DeserializePlan,
+    dest: *mut CompactDict<K, V, A>,
+    dynamic_part: *mut u8,
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'de, 'p, K, V, A> ::serde::de::Visitor<'de> for WriteEntries<'p, K, V, A>
+where
+    K: Copy + Eq + Hash + ::serde::de::Deserialize<'de>,
+    V: DeserializeCompact<'de>,
+    A: InstanceAllocator,
+    A::Handle: Default,
+{
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        formatter.write_str("A Compact Hash Map")
+    }
+
+    fn visit_map<M>(self, mut access: M) -> Result<usize, M::Error>
+    where
+        M: ::serde::de::MapAccess<'de>,
+    {
+        let len = self.plan.next_length()?;
+        unsafe {
+            let keys = self.dynamic_part as *mut K;
+            let values_part = self.dynamic_part.add(len * mem::size_of::<K>());
+            let values = values_part as *mut V;
+            CompactVec::write_compact_static(&mut (*self.dest).keys, len, A::Handle::default(), self.dynamic_part);
+            CompactVec::write_compact_static(&mut (*self.dest).values, len, A::Handle::default(), values_part);
+
+            let mut offset = len * (mem::size_of::<K>() + mem::size_of::<V>());
+            let mut seen = ::std::collections::HashSet::with_capacity(len);
+            for i in 0..len {
+                let key = match access.next_key::<K>()? {
+                    Some(key) => key,
+                    None => return Err(::serde::de::Error::invalid_length(i, &self)),
+                };
+                if !seen.insert(key) {
+                    return Err(::serde::de::Error::custom("duplicate key in compact dictionary"));
+                }
+                ptr::write(keys.add(i), key);
+                let seed = WriteInto::new(self.plan, values.add(i), self.dynamic_part.add(offset));
+                offset += access.next_value_seed(seed)?;
+            }
+            if access.next_key::<::serde::de::IgnoredAny>()?.is_some() {
+                return Err(::serde::de::Error::invalid_length(len + 1, &self));
+            }
+            Ok(offset)
+        }
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'de, K, V, A> DeserializeCompact<'de> for CompactDict<K, V, A>
+where
+    K: Copy + Eq + Hash + ::serde::de::Deserialize<'de>,
+    V: DeserializeCompact<'de>,
+    A: InstanceAllocator,
+    A::Handle: Default,
+{
+    fn measure<D>(deserializer: D, plan: &mut DeserializePlan) -> Result<usize, D::Error>
+    where
+        D: ::serde::de::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(MeasureEntries::<K, V> { plan, marker: PhantomData })
+    }
+
+    unsafe fn deserialize_compact_into<D>(
+        deserializer: D,
+        plan: &mut DeserializePlan,
+        dest: *mut Self,
+        dynamic_part: *mut u8,
+    ) -> Result<usize, D::Error>
+    where
+        D: ::serde::de::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(WriteEntries { plan, dest, dynamic_part })
+    }
+}
+
 #[cfg(test)]
 fn elem(n: usize) -> usize {
     (n * n) as usize
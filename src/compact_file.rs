@@ -0,0 +1,418 @@
+use super::compact::{check_handles_can_leave_process, Compact};
+use super::snapshot::{SnapshotError, SnapshotHeader};
+use memmap2::MmapMut;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::ptr;
+
+const MAGIC: &[u8; 8] = b"CMPCTFIL";
+/// Magic, format version, pointer width, endianness, flags and type fingerprint,
+/// encoded like in a `SnapshotHeader`
+const FILE_HEADER: usize = 24;
+/// Objects start at multiples of this, which is also the maximum supported alignment of `T`
+const ENTRY_ALIGN: usize = 8;
+/// Every object is prefixed with its total size as a `u64`, its CRC-32 as a `u32`
+/// and a nonzero marker, so that unused space at the end of the file is never read as objects
+const ENTRY_HEADER: usize = 16;
+const ENTRY_MARKER: u8 = 1;
+
+fn padded(size: usize) -> usize {
+    size.div_ceil(ENTRY_ALIGN) * ENTRY_ALIGN
+}
+
+fn encode_entry_header(size: usize, checksum: u32) -> [u8; ENTRY_HEADER] {
+    let mut bytes = [0u8; ENTRY_HEADER];
+    bytes[0..8].copy_from_slice(&(size as u64).to_le_bytes());
+    bytes[8..12].copy_from_slice(&checksum.to_le_bytes());
+    bytes[12] = ENTRY_MARKER;
+    bytes
+}
+
+/// A persistent, append-only store of compacted objects of type `T`, backed by a
+/// memory-mapped file.
+///
+/// Objects are placed into the file using `Compact::compact` and handed out as
+/// `&T` views directly from the mapping, without any deserialization.
+/// Mutating an object decompacts a copy of it (copy-on-write), which is only
+/// written back to the file by `flush` - unflushed changes are lost on drop.
+///
+/// The file grows geometrically while objects are pushed, and is trimmed to the objects
+/// on `flush` and on drop.
+pub struct CompactFile<T: Compact> {
+    file: File,
+    map: MmapMut,
+    /// Bytes used by the file header and the objects, the rest of the mapping is unused
+    used: usize,
+    /// Byte offset and total size of each object in the mapping
+    entries: Vec<(usize, usize)>,
+    /// Decompacted copies of objects that were mutated since the last `flush`
+    modified: HashMap<usize, T>,
+}
+
+impl<T: Compact> CompactFile<T> {
+    /// Create a new, empty store at `path`, replacing any existing file
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::assert_alignment();
+        check_handles_can_leave_process::<T>()?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(FILE_HEADER as u64)?;
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        map[..FILE_HEADER].copy_from_slice(&Self::file_header());
+
+        Ok(CompactFile {
+            file,
+            map,
+            used: FILE_HEADER,
+            entries: Vec::new(),
+            modified: HashMap::new(),
+        })
+    }
+
+    /// Open an existing store at `path`, refusing files written for a different type,
+    /// format version or platform, and files with corrupted objects.
+    ///
+    /// All object checksums are verified, so this reads the whole file once.
+    ///
+    /// # Safety
+    /// The objects in the file are used in-place, so the file must not be modified
+    /// externally while it is open. The checks protect against accidental mismatches,
+    /// not against maliciously crafted files.
+    pub unsafe fn open<P: AsRef<Path>>(path: P) -> Result<Self, SnapshotError> {
+        Self::assert_alignment();
+        check_handles_can_leave_process::<T>()?;
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let map = MmapMut::map_mut(&file)?;
+
+        if map.len() < MAGIC.len() || &map[..MAGIC.len()] != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        if map.len() < FILE_HEADER {
+            return Err(SnapshotError::Truncated);
+        }
+        Self::check_file_header(&map[..FILE_HEADER])?;
+
+        let mut entries = Vec::new();
+        let mut position = FILE_HEADER;
+        while position < map.len() {
+            // space grown into, but not used before the file was closed
+            if map[position..].iter().all(|&byte| byte == 0) {
+                break;
+            }
+            if position + ENTRY_HEADER > map.len() {
+                return Err(SnapshotError::Truncated);
+            }
+            let mut size_bytes = [0u8; 8];
+            size_bytes.copy_from_slice(&map[position..position + 8]);
+            let size = u64::from_le_bytes(size_bytes) as usize;
+            let mut checksum_bytes = [0u8; 4];
+            checksum_bytes.copy_from_slice(&map[position + 8..position + 12]);
+            let expected_checksum = u32::from_le_bytes(checksum_bytes);
+
+            let start = position + ENTRY_HEADER;
+            if size > map.len() - start {
+                return Err(SnapshotError::Truncated);
+            }
+            let checksum = ::crc32fast::hash(&map[start..start + size]);
+            if checksum != expected_checksum {
+                return Err(SnapshotError::ChecksumMismatch {
+                    found: checksum,
+                    expected: expected_checksum,
+                });
+            }
+            entries.push((start, size));
+            position = start + padded(size);
+        }
+
+        Ok(CompactFile {
+            file,
+            map,
+            used: position,
+            entries,
+            modified: HashMap::new(),
+        })
+    }
+
+    fn file_header() -> [u8; FILE_HEADER] {
+        let mut bytes = [0u8; FILE_HEADER];
+        bytes.copy_from_slice(&SnapshotHeader::new::<T>(0, 0).encode()[..FILE_HEADER]);
+        bytes[..MAGIC.len()].copy_from_slice(MAGIC);
+        bytes
+    }
+
+    fn check_file_header(bytes: &[u8]) -> Result<(), SnapshotError> {
+        // reuse snapshot header decoding and checks for the shared fields
+        let mut header_bytes = SnapshotHeader::new::<T>(0, 0).encode();
+        header_bytes[MAGIC.len()..FILE_HEADER].copy_from_slice(&bytes[MAGIC.len()..]);
+        SnapshotHeader::decode(&header_bytes)?.check_compatible::<T>()
+    }
+
+    fn assert_alignment() {
+        assert!(
+            T::max_align() <= ENTRY_ALIGN,
+            "CompactFile only supports types aligned to at most {} bytes",
+            ENTRY_ALIGN
+        );
+    }
+
+    /// Amount of objects in the store
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Is the store empty?
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Compact `value` into the end of the file and return its index
+    pub fn push(&mut self, mut value: T) -> io::Result<usize> {
+        let size = value.total_size_bytes();
+        let position = self.used;
+        let used = position + ENTRY_HEADER + padded(size);
+        if used > self.map.len() {
+            self.remap(::std::cmp::max(used, 2 * self.map.len()))?;
+        }
+        self.used = used;
+
+        let start = position + ENTRY_HEADER;
+        unsafe {
+            Compact::compact_behind(&mut value, self.map.as_mut_ptr().add(start) as *mut T);
+            mem::forget(value);
+        }
+        let checksum = ::crc32fast::hash(&self.map[start..start + size]);
+        self.map[position..start].copy_from_slice(&encode_entry_header(size, checksum));
+        self.entries.push((start, size));
+        Ok(self.entries.len() - 1)
+    }
+
+    /// Get the object at `index`, if it exists.
+    /// Unmodified objects are read directly from the mapping.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        match self.modified.get(&index) {
+            Some(value) => Some(value),
+            None => self
+                .entries
+                .get(index)
+                .map(|&(start, _)| unsafe { &*(self.map.as_ptr().add(start) as *const T) }),
+        }
+    }
+
+    /// Get the object at `index` mutably, if it exists.
+    /// The first mutable access decompacts a copy of the object, which is
+    /// only written back to the file by `flush`.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        let start = self.entries.get(index)?.0;
+        let mapped = unsafe { self.map.as_ptr().add(start) as *const T };
+        Some(
+            self.modified
+                .entry(index)
+                .or_insert_with(|| unsafe { Compact::decompact(mapped) }),
+        )
+    }
+
+    /// Is the object at `index` modified and not yet written back?
+    pub fn is_modified(&self, index: usize) -> bool {
+        self.modified.contains_key(&index)
+    }
+
+    /// Write all modified objects back to the file and sync it to disk.
+    ///
+    /// Since modified objects can change in size, this rewrites the whole file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if !self.modified.is_empty() {
+            let mut modified = mem::take(&mut self.modified);
+            let sizes = self
+                .entries
+                .iter()
+                .enumerate()
+                .map(|(index, &(_, size))| {
+                    modified
+                        .get(&index)
+                        .map_or(size, |value| value.total_size_bytes())
+                })
+                .collect::<Vec<_>>();
+            let total_size = FILE_HEADER
+                + sizes
+                    .iter()
+                    .map(|size| ENTRY_HEADER + padded(*size))
+                    .sum::<usize>();
+
+            // build the new image in a buffer with sufficient alignment
+            let mut image = vec![0u64; total_size / ENTRY_ALIGN];
+            let image_bytes = image.as_mut_ptr() as *mut u8;
+            let mut entries = Vec::with_capacity(sizes.len());
+
+            unsafe {
+                ptr::copy_nonoverlapping(self.map.as_ptr(), image_bytes, FILE_HEADER);
+                let mut position = FILE_HEADER;
+                for (index, size) in sizes.into_iter().enumerate() {
+                    let start = position + ENTRY_HEADER;
+                    if let Some(mut value) = modified.remove(&index) {
+                        Compact::compact_behind(&mut value, image_bytes.add(start) as *mut T);
+                        mem::forget(value);
+                        let checksum = ::crc32fast::hash(::std::slice::from_raw_parts(
+                            image_bytes.add(start),
+                            size,
+                        ));
+                        let entry_header = encode_entry_header(size, checksum);
+                        ptr::copy_nonoverlapping(
+                            entry_header.as_ptr(),
+                            image_bytes.add(position),
+                            ENTRY_HEADER,
+                        );
+                    } else {
+                        // compact objects only contain relative pointers,
+                        // so they can just be copied, together with their entry header
+                        ptr::copy_nonoverlapping(
+                            self.map.as_ptr().add(self.entries[index].0 - ENTRY_HEADER),
+                            image_bytes.add(position),
+                            ENTRY_HEADER + size,
+                        );
+                    }
+                    entries.push((start, size));
+                    position = start + padded(size);
+                }
+            }
+
+            self.remap(total_size)?;
+            unsafe {
+                ptr::copy_nonoverlapping(image_bytes, self.map.as_mut_ptr(), total_size);
+            }
+            self.used = total_size;
+            self.entries = entries;
+        } else if self.map.len() > self.used {
+            let used = self.used;
+            self.remap(used)?;
+        }
+
+        self.map.flush()
+    }
+
+    fn remap(&mut self, new_len: usize) -> io::Result<()> {
+        self.file.set_len(new_len as u64)?;
+        self.map = unsafe { MmapMut::map_mut(&self.file)? };
+        Ok(())
+    }
+}
+
+impl<T: Compact> Drop for CompactFile<T> {
+    /// Trim the unused space the file has grown into
+    fn drop(&mut self) {
+        if self.map.len() > self.used {
+            // best effort, the unused space is skipped when opening the file otherwise
+            let _ = self.file.set_len(self.used as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+fn temp_path(name: &str) -> ::std::path::PathBuf {
+    ::std::env::temp_dir().join(format!("compact_file_{}_{}", name, ::std::process::id()))
+}
+
+#[test]
+fn persist_and_reopen() {
+    use super::compact_vec::CompactVec;
+    let path = temp_path("persist_and_reopen");
+
+    {
+        let mut file: CompactFile<CompactVec<u32>> = CompactFile::create(&path).unwrap();
+        assert_eq!(0, file.push(vec![1, 2, 3].into()).unwrap());
+        assert_eq!(1, file.push(vec![4, 5].into()).unwrap());
+        file.flush().unwrap();
+    }
+
+    let file: CompactFile<CompactVec<u32>> = unsafe { CompactFile::open(&path).unwrap() };
+    assert_eq!(2, file.len());
+    assert!(file.get(0).unwrap().is_still_compact());
+    assert_eq!(&[1, 2, 3], &**file.get(0).unwrap());
+    assert_eq!(&[4, 5], &**file.get(1).unwrap());
+    assert!(file.get(2).is_none());
+
+    match unsafe { CompactFile::<CompactVec<u64>>::open(&path) } {
+        Err(SnapshotError::TypeMismatch { .. }) => {}
+        Err(other) => panic!("expected type mismatch, got {:?}", other),
+        Ok(_) => panic!("expected type mismatch"),
+    }
+
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn copy_on_write() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    type NestedType = CompactVec<CompactString>;
+    let path = temp_path("copy_on_write");
+
+    {
+        let mut file: CompactFile<NestedType> = CompactFile::create(&path).unwrap();
+        file.push(vec!["a".to_owned().into()].into()).unwrap();
+        file.push(vec!["b".to_owned().into()].into()).unwrap();
+        file.get_mut(0).unwrap().push("c".to_owned().into());
+        assert!(file.is_modified(0));
+        assert_eq!(2, file.get(0).unwrap().len());
+        // dropped without flush
+    }
+
+    {
+        let mut file: CompactFile<NestedType> = unsafe { CompactFile::open(&path).unwrap() };
+        assert_eq!(1, file.get(0).unwrap().len());
+        file.get_mut(0).unwrap().push("c".to_owned().into());
+        file.flush().unwrap();
+        assert!(!file.is_modified(0));
+        assert!(file.get(0).unwrap().is_still_compact());
+    }
+
+    let file: CompactFile<NestedType> = unsafe { CompactFile::open(&path).unwrap() };
+    let names = file
+        .get(0)
+        .unwrap()
+        .iter()
+        .chain(file.get(1).unwrap().iter())
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(vec!["a", "c", "b"], names);
+
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn grows_geometrically_and_trims_on_close() {
+    use super::compact_vec::CompactVec;
+    let path = temp_path("grows_geometrically");
+    let file_len = || ::std::fs::metadata(&path).unwrap().len() as usize;
+    let entry_size = ENTRY_HEADER + padded(CompactVec::<u32>::from(vec![0]).total_size_bytes());
+
+    {
+        let mut file: CompactFile<CompactVec<u32>> = CompactFile::create(&path).unwrap();
+        let mut lengths = Vec::new();
+        for i in 0..100 {
+            file.push(vec![i].into()).unwrap();
+            lengths.push(file_len());
+        }
+        lengths.dedup();
+        assert!(lengths.len() < 10);
+    }
+    assert_eq!(FILE_HEADER + 100 * entry_size, file_len());
+
+    // a file that wasn't closed keeps the space it has grown into
+    let mut file: CompactFile<CompactVec<u32>> = unsafe { CompactFile::open(&path).unwrap() };
+    file.push(vec![100].into()).unwrap();
+    mem::forget(file);
+    assert!(file_len() > FILE_HEADER + 101 * entry_size);
+    let file: CompactFile<CompactVec<u32>> = unsafe { CompactFile::open(&path).unwrap() };
+    assert_eq!(101, file.len());
+    assert_eq!(&[100], &**file.get(100).unwrap());
+    drop(file);
+
+    ::std::fs::remove_file(&path).unwrap();
+}
@@ -1,8 +1,15 @@
 extern crate primal;
 
-use super::compact::Compact;
+use super::compact::{Compact, Headroom, structural_fingerprint, allocator_fingerprint, field_position};
+use super::compact::StableHasher;
+use super::inspect::{LayoutNode, Storage};
+use super::compact_index::CompactIndex;
 use super::compact_vec::CompactVec;
-use super::simple_allocator_trait::{Allocator, DefaultHeap};
+use super::stats;
+use super::allocator::InstanceAllocator;
+use super::simple_allocator_trait::DefaultHeap;
+#[cfg(test)]
+use super::simple_allocator_trait::Allocator;
 use std::collections::hash_map::DefaultHasher;
 #[cfg(test)]
 use std::collections::HashMap;
@@ -12,22 +19,24 @@ use std::iter::Iterator;
 
 use std;
 use std::fmt::Write;
+use std::io;
 
 #[derive(Clone)]
 struct Entry<K, V> {
+    /// Stored like a `CompactIndex`, so it is little-endian in portable images
     hash: u32,
     tombstoned: bool,
     inner: Option<(K, V)>,
 }
 
-struct QuadraticProbingIterator<'a, K: 'a, V: 'a, A: 'a + Allocator = DefaultHeap> {
+struct QuadraticProbingIterator<'a, K: 'a, V: 'a, A: 'a + InstanceAllocator = DefaultHeap> {
     i: usize,
     number_used: usize,
     hash: u32,
     map: &'a OpenAddressingMap<K, V, A>,
 }
 
-struct QuadraticProbingMutIterator<'a, K: 'a, V: 'a, A: 'a + Allocator = DefaultHeap> {
+struct QuadraticProbingMutIterator<'a, K: 'a, V: 'a, A: 'a + InstanceAllocator = DefaultHeap> {
     i: usize,
     number_used: usize,
     hash: u32,
@@ -36,8 +45,9 @@ struct QuadraticProbingMutIterator<'a, K: 'a, V: 'a, A: 'a + Allocator = Default
 
 /// A dynamically-sized open adressing quadratic probing hashmap
 /// that can be stored in compact sequential storage and
-/// automatically spills over into free heap storage using `Allocator`.
-pub struct OpenAddressingMap<K, V, A: Allocator = DefaultHeap> {
+/// automatically spills over into free heap storage using `InstanceAllocator`.
+pub struct OpenAddressingMap<K, V, A: InstanceAllocator = DefaultHeap> {
+    /// Counters are stored like `CompactIndex`es, so they are little-endian in portable images
     number_alive: u32,
     number_used: u32,
     entries: CompactVec<Entry<K, V>, A>,
@@ -45,7 +55,7 @@ pub struct OpenAddressingMap<K, V, A: Allocator = DefaultHeap> {
 
 impl<K: Eq, V: Clone> Entry<K, V> {
     fn make_used(&mut self, hash: u32, key: K, value: V) {
-        self.hash = hash;
+        self.hash = CompactIndex::from_usize(hash as usize);
         self.inner = Some((key, value));
     }
 
@@ -113,7 +123,7 @@ impl<K: Eq, V: Clone> Entry<K, V> {
 
 impl<K, V> std::fmt::Debug for Entry<K, V> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Entry {:?}, {:?}", self.hash, self.inner.is_some())
+        write!(f, "Entry {:?}, {:?}", self.hash.to_usize(), self.inner.is_some())
     }
 }
 
@@ -161,6 +171,37 @@ impl<K: Copy, V: Compact> Compact for Entry<K, V> {
         }
     }
 
+    default fn inspect_layout(&self) -> LayoutNode {
+        let node = LayoutNode::new(self, Storage::None);
+        match self.inner {
+            Some(ref kv) if !self.tombstoned => node.child_unless_trivial("value", kv.1.inspect_layout()),
+            _ => node,
+        }
+    }
+
+    default fn dynamic_size_bytes_tight(&self) -> usize {
+        if self.tombstoned {
+            0
+        } else {
+            self.inner
+                .as_ref()
+                .map_or(0, |kv_tuple| kv_tuple.1.dynamic_size_bytes_tight())
+        }
+    }
+
+    default unsafe fn compact_tight(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        (*dest).hash = (*source).hash;
+        (*dest).tombstoned = (*source).tombstoned;
+        ::std::ptr::copy_nonoverlapping(&(*source).inner, &mut (*dest).inner, 1);
+        if (*dest).inner.is_some() {
+            Compact::compact_tight(
+                &mut (*source).inner.as_mut().unwrap().1,
+                &mut (*dest).inner.as_mut().unwrap().1,
+                new_dynamic_part,
+            )
+        }
+    }
+
     default unsafe fn decompact(source: *const Self) -> Entry<K, V> {
         if (*source).inner.is_none() {
             Entry {
@@ -177,6 +218,51 @@ impl<K: Copy, V: Compact> Compact for Entry<K, V> {
             }
         }
     }
+
+    default fn streams_compact() -> bool {
+        V::streams_compact()
+    }
+
+    default fn uses_instance_handles() -> bool {
+        V::uses_instance_handles()
+    }
+
+    default fn max_align() -> usize {
+        ::std::cmp::max(::std::mem::align_of::<Self>(), V::max_align())
+    }
+
+    default fn type_fingerprint() -> u64 {
+        structural_fingerprint::<Self>(
+            "OpenAddressingMapEntry",
+            &[K::type_fingerprint(), V::type_fingerprint()],
+        )
+    }
+
+    default unsafe fn compact_static_to(&self, dest: *mut Self, at: usize, dynamic_at: usize) {
+        ::std::ptr::write(
+            dest,
+            Entry {
+                hash: self.hash,
+                tombstoned: self.tombstoned,
+                inner: self
+                    .inner
+                    .as_ref()
+                    .map(|kv| (kv.0, ::std::ptr::read(&kv.1))),
+            },
+        );
+        if let Some(ref kv) = self.inner {
+            let value = &mut (*dest).inner.as_mut().unwrap().1;
+            let value_at = field_position(dest, value, at);
+            kv.1.compact_static_to(value, value_at, dynamic_at);
+        }
+    }
+
+    default fn write_dynamic_compact(&self, dynamic_at: usize, out: &mut dyn io::Write) -> io::Result<()> {
+        match self.inner {
+            Some(ref kv) if !self.tombstoned => kv.1.write_dynamic_compact(dynamic_at, out),
+            _ => Ok(()),
+        }
+    }
 }
 
 impl<K: Copy, V: Copy> Compact for Entry<K, V> {
@@ -203,11 +289,65 @@ impl<K: Copy, V: Copy> Compact for Entry<K, V> {
     }
 }
 
+/// Feeds integers to the wrapped hasher little-endian and with fixed widths,
+/// so that keys hash the same on all platforms and with all compiler versions
+struct PortableHasher<H: Hasher>(H);
+
+impl<H: Hasher> Hasher for PortableHasher<H> {
+    fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes)
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.0.write(&i.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.0.write(&i.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.0.write(&i.to_le_bytes())
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.0.write(&i.to_le_bytes())
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64)
+    }
+
+    fn write_i16(&mut self, i: i16) {
+        self.write_u16(i as u16)
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write_u32(i as u32)
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.write_u64(i as u64)
+    }
+
+    fn write_i128(&mut self, i: i128) {
+        self.write_u128(i as u128)
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_u64(i as u64)
+    }
+}
+
 lazy_static! {
     static ref PRIME_SIEVE: primal::Sieve = { primal::Sieve::new(1_000_000) };
 }
 
-impl<'a, K: Copy, V: Compact, A: Allocator> QuadraticProbingIterator<'a, K, V, A> {
+impl<'a, K: Copy, V: Compact, A: InstanceAllocator> QuadraticProbingIterator<'a, K, V, A> {
     fn for_map(
         map: &'a OpenAddressingMap<K, V, A>,
         hash: u32,
@@ -221,7 +361,7 @@ impl<'a, K: Copy, V: Compact, A: Allocator> QuadraticProbingIterator<'a, K, V, A
     }
 }
 
-impl<'a, K: Copy, V: Compact, A: Allocator> QuadraticProbingMutIterator<'a, K, V, A> {
+impl<'a, K: Copy, V: Compact, A: InstanceAllocator> QuadraticProbingMutIterator<'a, K, V, A> {
     fn for_map(
         map: &'a mut OpenAddressingMap<K, V, A>,
         hash: u32,
@@ -235,7 +375,7 @@ impl<'a, K: Copy, V: Compact, A: Allocator> QuadraticProbingMutIterator<'a, K, V
     }
 }
 
-impl<'a, K, V, A: Allocator> Iterator for QuadraticProbingIterator<'a, K, V, A> {
+impl<'a, K, V, A: InstanceAllocator> Iterator for QuadraticProbingIterator<'a, K, V, A> {
     type Item = &'a Entry<K, V>;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -248,7 +388,7 @@ impl<'a, K, V, A: Allocator> Iterator for QuadraticProbingIterator<'a, K, V, A>
     }
 }
 
-impl<'a, K, V, A: Allocator> Iterator for QuadraticProbingMutIterator<'a, K, V, A> {
+impl<'a, K, V, A: InstanceAllocator> Iterator for QuadraticProbingMutIterator<'a, K, V, A> {
     type Item = &'a mut Entry<K, V>;
     fn next(&mut self) -> Option<&'a mut Entry<K, V>> {
         if self.i >= self.number_used {
@@ -260,29 +400,51 @@ impl<'a, K, V, A: Allocator> Iterator for QuadraticProbingMutIterator<'a, K, V,
     }
 }
 
-impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> OpenAddressingMap<K, V, A> {
+impl<K: Copy + Eq + Hash, V: Compact, A: InstanceAllocator> OpenAddressingMap<K, V, A>
+where
+    A::Handle: Default,
+{
     /// constructor
     pub fn new() -> Self {
         Self::with_capacity(4)
     }
     /// constructor
     pub fn with_capacity(l: usize) -> Self {
+        Self::with_capacity_in(l, A::Handle::default())
+    }
+}
+
+impl<K: Copy + Eq + Hash, V: Compact, A: InstanceAllocator> OpenAddressingMap<K, V, A> {
+    /// constructor, allocating using `alloc`
+    pub fn new_in(alloc: A::Handle) -> Self {
+        Self::with_capacity_in(4, alloc)
+    }
+    /// constructor, allocating using `alloc`
+    pub fn with_capacity_in(l: usize, alloc: A::Handle) -> Self {
+        let capacity = Self::find_next_prime(l);
+        let mut entries = CompactVec::with_capacity_in(capacity, alloc);
+        entries.extend((0..capacity).map(|_| Entry::default()));
         OpenAddressingMap {
-            entries: vec![Entry::default(); Self::find_next_prime(l)].into(),
-            number_alive: 0,
-            number_used: 0,
+            entries,
+            number_alive: CompactIndex::from_usize(0),
+            number_used: CompactIndex::from_usize(0),
         }
     }
 
+    /// The handle of the allocator used for free heap storage
+    pub fn allocator(&self) -> &A::Handle {
+        self.entries.allocator()
+    }
+
     /// Amount of entries in the dictionary
     pub fn len(&self) -> usize {
-        self.number_alive as usize
+        self.number_alive.to_usize()
     }
 
     /// Amount of used entries in the dictionary
     #[cfg(test)]
     pub fn len_used(&self) -> usize {
-        self.number_used as usize
+        self.number_used.to_usize()
     }
 
     /// Capacity of the dictionary
@@ -293,7 +455,7 @@ impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> OpenAddressingMap<K, V, A> {
 
     /// Is the dictionary empty?
     pub fn is_empty(&self) -> bool {
-        self.number_alive == 0
+        self.len() == 0
     }
 
     /// Look up the value for key `query`, if it exists
@@ -312,6 +474,9 @@ impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> OpenAddressingMap<K, V, A> {
     }
 
     /// Insert new value at key `query` and return the previous value at that key, if any existed
+    ///
+    /// If hashing a key panics while the map grows, the existing entries
+    /// that weren't rehashed yet are leaked, but never dropped.
     pub fn insert(&mut self, query: K, value: V) -> Option<V> {
         self.insert_inner_growing(query, value)
     }
@@ -360,9 +525,22 @@ impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> OpenAddressingMap<K, V, A> {
     }
 
     fn hash(key: K) -> u32 {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        hasher.finish() as u32
+        if cfg!(feature = "portable") {
+            let mut hasher = PortableHasher(StableHasher::default());
+            key.hash(&mut hasher);
+            // the low bits of FNV-1a alone are badly distributed for integer keys
+            let hash = hasher.finish();
+            (hash ^ (hash >> 32)) as u32
+        } else {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish() as u32
+        }
+    }
+
+    fn count_new_entry(&mut self) {
+        self.number_alive = CompactIndex::from_usize(self.len() + 1);
+        self.number_used = CompactIndex::from_usize(self.number_used.to_usize() + 1);
     }
 
     fn insert_inner_growing(&mut self, query: K, value: V) -> Option<V> {
@@ -373,8 +551,7 @@ impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> OpenAddressingMap<K, V, A> {
     fn insert_inner(&mut self, query: K, value: V) -> Option<V> {
         let res = self.insert_inner_inner(query, value);
         if res.is_none() {
-            self.number_alive += 1;
-            self.number_used += 1;
+            self.count_new_entry();
         }
         res
     }
@@ -396,7 +573,7 @@ impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> OpenAddressingMap<K, V, A> {
         // remove inner does not alter the size because of tombstones
         let old = self.remove_inner_inner(query);
         if old.is_some() {
-            self.number_alive -= 1;
+            self.number_alive = CompactIndex::from_usize(self.len() - 1);
         }
         old
     }
@@ -412,27 +589,52 @@ impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> OpenAddressingMap<K, V, A> {
     }
 
     fn ensure_capacity(&mut self) {
-        if self.number_used as usize > self.entries.capacity() / 2 {
+        if self.number_used.to_usize() > self.entries.capacity() / 2 {
             let mut new_capacity = self.entries.capacity() * 2;
 
             // if there are lots of dead entries we do not need to double
             // we are going to just garbage collect them
-            let number_dead = self.entries.capacity() - self.number_alive as usize;
+            let number_dead = self.entries.capacity() - self.number_alive.to_usize();
             if number_dead > self.entries.capacity() / 2 {
                 new_capacity = self.entries.capacity();
             }
 
-            let mut new_hash_map = Self::with_capacity(new_capacity);
+            self.rehash(new_capacity);
+        }
+    }
 
-            for entry in self.entries.drain() {
-                if entry.alive() {
-                    let tuple = entry.into_tuple();
-                    new_hash_map.insert(tuple.0, tuple.1);
-                }
+    fn rehash(&mut self, new_capacity: usize) {
+        // rehash into fresh entries while `self` always stays a consistent map:
+        // if hashing a key panics, the entries that weren't moved over yet
+        // are leaked together with the draining iterator, but never dropped
+        if self.entries.in_compact_storage() {
+            stats::record::<Self>(|stats| stats.spills += 1);
+        }
+        let alloc = self.allocator().clone();
+        let mut old_entries = std::mem::ManuallyDrop::new(self.entries.drain());
+        *self = Self::with_capacity_in(new_capacity, alloc);
+
+        for entry in &mut *old_entries {
+            let entry = std::mem::ManuallyDrop::new(entry);
+            if entry.alive() {
+                let hash = Self::hash(*entry.key());
+                let (key, value) = std::mem::ManuallyDrop::into_inner(entry).into_tuple();
+                self.insert_rehashed(hash, key, value);
             }
+        }
+        std::mem::drop(std::mem::ManuallyDrop::into_inner(old_entries));
+    }
 
-            *self = new_hash_map;
+    /// Insert a key that is known not to be in the map yet, without comparing keys
+    fn insert_rehashed(&mut self, hash: u32, key: K, value: V) {
+        for entry in self.quadratic_iterator_mut(hash) {
+            if entry.free() {
+                entry.make_used(hash, key, value);
+                self.count_new_entry();
+                return;
+            }
         }
+        panic!("should have place")
     }
 
     fn find_used(&self, query: K) -> Option<&Entry<K, V>> {
@@ -468,20 +670,20 @@ impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> OpenAddressingMap<K, V, A> {
 
     fn display(&self) -> String {
         let mut res = String::new();
-        writeln!(&mut res, "size: {:?}", self.number_alive).unwrap();
-        let mut size_left: isize = self.number_alive as isize;
+        writeln!(&mut res, "size: {:?}", self.len()).unwrap();
+        let mut size_left: isize = self.len() as isize;
         for entry in self.entries.iter() {
             if entry.used() {
                 size_left -= 1;
             }
-            writeln!(&mut res, "  {:?} {:?}", entry.used(), entry.hash).unwrap();
+            writeln!(&mut res, "  {:?} {:?}", entry.used(), entry.hash.to_usize()).unwrap();
         }
         writeln!(&mut res, "size_left : {:?}", size_left).unwrap();
         res
     }
 }
 
-impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> Compact for OpenAddressingMap<K, V, A> {
+impl<K: Copy + Eq + Hash, V: Compact, A: InstanceAllocator> Compact for OpenAddressingMap<K, V, A> {
     default fn is_still_compact(&self) -> bool {
         self.entries.is_still_compact()
     }
@@ -500,6 +702,21 @@ impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> Compact for OpenAddressingMa
         );
     }
 
+    default fn dynamic_size_bytes_tight(&self) -> usize {
+        self.entries.dynamic_size_bytes_tight()
+    }
+
+    default unsafe fn compact_tight(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        // all entries are used for probing, so only the values get trimmed
+        (*dest).number_alive = (*source).number_alive;
+        (*dest).number_used = (*source).number_used;
+        Compact::compact_tight(
+            &mut (*source).entries,
+            &mut (*dest).entries,
+            new_dynamic_part,
+        );
+    }
+
     unsafe fn decompact(source: *const Self) -> OpenAddressingMap<K, V, A> {
         OpenAddressingMap {
             entries: Compact::decompact(&(*source).entries),
@@ -507,9 +724,59 @@ impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> Compact for OpenAddressingMa
             number_used: (*source).number_used,
         }
     }
+
+    default fn type_fingerprint() -> u64 {
+        structural_fingerprint::<Self>(
+            "OpenAddressingMap",
+            &[K::type_fingerprint(), V::type_fingerprint(), allocator_fingerprint::<A>()],
+        )
+    }
+
+    default fn streams_compact() -> bool {
+        V::streams_compact()
+    }
+
+    default fn uses_instance_handles() -> bool {
+        CompactVec::<Entry<K, V>, A>::uses_instance_handles()
+    }
+
+    default fn max_align() -> usize {
+        CompactVec::<Entry<K, V>, A>::max_align()
+    }
+
+    default unsafe fn compact_static_to(&self, dest: *mut Self, at: usize, dynamic_at: usize) {
+        ::std::ptr::write(&mut (*dest).number_alive, self.number_alive);
+        ::std::ptr::write(&mut (*dest).number_used, self.number_used);
+        let entries_at = field_position(dest, &(*dest).entries, at);
+        self.entries
+            .compact_static_to(&mut (*dest).entries, entries_at, dynamic_at);
+    }
+
+    default fn write_dynamic_compact(&self, dynamic_at: usize, out: &mut dyn io::Write) -> io::Result<()> {
+        self.entries.write_dynamic_compact(dynamic_at, out)
+    }
+
+    default fn inspect_layout(&self) -> LayoutNode {
+        // slots that are free or tombstoned
+        let unused = (self.entries.len() - self.len()) * ::std::mem::size_of::<Entry<K, V>>();
+        LayoutNode::new(self, Storage::None)
+            .wasting(unused)
+            .child("entries", self.entries.inspect_layout())
+    }
+
+    default fn reserve_headroom(&mut self, headroom: Headroom) {
+        for value in self.values_mut() {
+            value.reserve_headroom(headroom);
+        }
+        // stay below the load that `ensure_capacity` grows at
+        let spare = headroom.spare_for(self.len());
+        if self.number_used.to_usize() + spare > self.entries.capacity() / 2 {
+            self.rehash(2 * (self.len() + spare) + 1);
+        }
+    }
 }
 
-impl<K: Copy, V: Compact + Clone, A: Allocator> Clone for OpenAddressingMap<K, V, A> {
+impl<K: Copy, V: Compact + Clone, A: InstanceAllocator> Clone for OpenAddressingMap<K, V, A> {
     fn clone(&self) -> Self {
         OpenAddressingMap {
             entries: self.entries.clone(),
@@ -519,14 +786,19 @@ impl<K: Copy, V: Compact + Clone, A: Allocator> Clone for OpenAddressingMap<K, V
     }
 }
 
-impl<K: Copy + Eq + Hash, V: Compact, A: Allocator> Default for OpenAddressingMap<K, V, A> {
+impl<K: Copy + Eq + Hash, V: Compact, A: InstanceAllocator> Default for OpenAddressingMap<K, V, A>
+where
+    A::Handle: Default,
+{
     fn default() -> Self {
         OpenAddressingMap::with_capacity(5)
     }
 }
 
-impl<K: Copy + Eq + Hash, V: Compact + Clone, A: Allocator> ::std::iter::FromIterator<(K, V)>
+impl<K: Copy + Eq + Hash, V: Compact + Clone, A: InstanceAllocator> ::std::iter::FromIterator<(K, V)>
     for OpenAddressingMap<K, V, A>
+where
+    A::Handle: Default,
 {
     /// Construct a compact dictionary from an interator over key-value pairs
     fn from_iter<T: IntoIterator<Item = (K, V)>>(iter_to_be: T) -> Self {
@@ -542,7 +814,7 @@ impl<K: Copy + Eq + Hash, V: Compact + Clone, A: Allocator> ::std::iter::FromIte
 impl<
         K: Copy + Eq + Hash + ::std::fmt::Debug,
         V: Compact + Clone + ::std::fmt::Debug,
-        A: Allocator,
+        A: InstanceAllocator,
     > ::std::fmt::Debug for OpenAddressingMap<K, V, A>
 {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
@@ -550,14 +822,15 @@ impl<
     }
 }
 
-impl<K: Hash + Eq + Copy, I: Compact, A1: Allocator, A2: Allocator>
+impl<K: Hash + Eq + Copy, I: Compact, A1: InstanceAllocator, A2: InstanceAllocator>
     OpenAddressingMap<K, CompactVec<I, A1>, A2>
+where
+    A1::Handle: Default,
 {
     /// Push a value onto the `CompactVec` at the key `query`
     pub fn push_at(&mut self, query: K, item: I) {
         if self.push_at_inner(query, item) {
-            self.number_alive += 1;
-            self.number_used += 1;
+            self.count_new_entry();
         }
     }
 
@@ -595,12 +868,77 @@ impl<K: Hash + Eq + Copy, I: Compact, A1: Allocator, A2: Allocator>
     }
 }
 
-impl<T: Hash> Hash for CompactVec<T> {
+impl<K, V, A, B> PartialEq<OpenAddressingMap<K, V, B>> for OpenAddressingMap<K, V, A>
+where
+    K: Copy + Eq + Hash,
+    V: Compact + PartialEq,
+    A: InstanceAllocator,
+    B: InstanceAllocator,
+{
+    /// Maps are equal if they contain the same pairs, independent of insertion order
+    fn eq(&self, other: &OpenAddressingMap<K, V, B>) -> bool {
+        self.len() == other.len() && self.pairs().all(|(key, value)| other.get(*key) == Some(value))
+    }
+}
+
+impl<K: Copy + Eq + Hash, V: Compact + Eq, A: InstanceAllocator> Eq for OpenAddressingMap<K, V, A> {}
+
+impl<K, V, A, B> PartialOrd<OpenAddressingMap<K, V, B>> for OpenAddressingMap<K, V, A>
+where
+    K: Copy + Ord + Hash,
+    V: Compact + PartialOrd,
+    A: InstanceAllocator,
+    B: InstanceAllocator,
+{
+    /// Maps are ordered like their pairs sorted by key, like a `BTreeMap`
+    fn partial_cmp(&self, other: &OpenAddressingMap<K, V, B>) -> Option<::std::cmp::Ordering> {
+        sorted_pairs(self.pairs()).partial_cmp(&sorted_pairs(other.pairs()))
+    }
+}
+
+impl<K: Copy + Ord + Hash, V: Compact + Ord, A: InstanceAllocator> Ord for OpenAddressingMap<K, V, A> {
+    fn cmp(&self, other: &Self) -> ::std::cmp::Ordering {
+        sorted_pairs(self.pairs()).cmp(&sorted_pairs(other.pairs()))
+    }
+}
+
+impl<K: Copy + Eq + Hash, V: Compact + Hash, A: InstanceAllocator> Hash for OpenAddressingMap<K, V, A> {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        for elem in self {
-            elem.hash(state);
-        }
+        hash_unordered(self.pairs(), state)
+    }
+}
+
+/// The key-value pairs of a map sorted by key, to order maps independent of insertion order
+pub(crate) fn sorted_pairs<'a, K: Ord + 'a, V: 'a, P>(pairs: P) -> Vec<(&'a K, &'a V)>
+where
+    P: Iterator<Item = (&'a K, &'a V)>,
+{
+    let mut sorted: Vec<_> = pairs.collect();
+    sorted.sort_by(|a, b| a.0.cmp(b.0));
+    sorted
+}
+
+/// Hash the key-value pairs of a map independent of their order, by summing their hashes.
+///
+/// Each pair is hashed after a seed taken from `state`, so a keyed `state`
+/// (like the one of `RandomState`) keys the pair hashes as well.
+pub(crate) fn hash_unordered<'a, K: Hash + 'a, V: Hash + 'a, P, H>(pairs: P, state: &mut H)
+where
+    P: Iterator<Item = (&'a K, &'a V)>,
+    H: Hasher,
+{
+    let seed = state.finish();
+    let mut len = 0;
+    let mut sum = 0u64;
+    for pair in pairs {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u64(seed);
+        pair.hash(&mut hasher);
+        sum = sum.wrapping_add(hasher.finish());
+        len += 1;
     }
+    state.write_usize(len);
+    state.write_u64(sum);
 }
 
 #[cfg(feature = "serde-serialization")]
@@ -613,7 +951,7 @@ impl<K, V, A> ::serde::Serialize for OpenAddressingMap<K, V, A>
 where
     K: Copy + Eq + Hash + ::serde::Serialize,
     V: Compact + ::serde::Serialize,
-    A: Allocator,
+    A: InstanceAllocator,
 {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -628,12 +966,15 @@ where
 }
 
 #[cfg(feature = "serde-serialization")]
-struct OpenAddressingMapVisitor<K, V, A: Allocator> {
-    marker: PhantomData<fn() -> OpenAddressingMap<K, V, A>>,
+type MakeOpenAddressingMap<K, V, A> = fn() -> OpenAddressingMap<K, V, A>;
+
+#[cfg(feature = "serde-serialization")]
+struct OpenAddressingMapVisitor<K, V, A: InstanceAllocator> {
+    marker: PhantomData<MakeOpenAddressingMap<K, V, A>>,
 }
 
 #[cfg(feature = "serde-serialization")]
-impl<K, V, A: Allocator> OpenAddressingMapVisitor<K, V, A> {
+impl<K, V, A: InstanceAllocator> OpenAddressingMapVisitor<K, V, A> {
     fn new() -> Self {
         OpenAddressingMapVisitor {
             marker: PhantomData,
@@ -646,7 +987,8 @@ impl<'de, K, V, A> ::serde::de::Visitor<'de> for OpenAddressingMapVisitor<K, V,
 where
     K: Copy + Eq + Hash + ::serde::de::Deserialize<'de>,
     V: Compact + ::serde::de::Deserialize<'de>,
-    A: Allocator,
+    A: InstanceAllocator,
+    A::Handle: Default,
 {
     type Value = OpenAddressingMap<K, V, A>;
 
@@ -673,7 +1015,8 @@ impl<'de, K, V, A> ::serde::de::Deserialize<'de> for OpenAddressingMap<K, V, A>
 where
     K: Copy + Eq + Hash + ::serde::de::Deserialize<'de>,
     V: Compact + ::serde::de::Deserialize<'de>,
-    A: Allocator,
+    A: InstanceAllocator,
+    A::Handle: Default,
 {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -836,7 +1179,7 @@ fn insert_after_remove_works_same_hash() {
     // get 2 elems with the same hash
     let mut hash_to_usize: HashMap<u32, usize> = HashMap::new();
     let mut bad_pair_opt = None;
-    for i in 0..<usize>::max_value() {
+    for i in 0..usize::MAX {
         if i % 10000 == 0 {
             println!("i {}", i);
         }
@@ -872,6 +1215,60 @@ fn insert_after_remove_works_same_hash() {
     assert!(n1 == 1);
 }
 
+#[cfg(test)]
+thread_local! {
+    static HASH_PANICS: ::std::cell::Cell<bool> = const { ::std::cell::Cell::new(false) };
+}
+
+#[cfg(test)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct PanickyKey(u32);
+
+#[cfg(test)]
+impl Hash for PanickyKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if HASH_PANICS.with(|panics| panics.get()) {
+            panic!("hash panicked")
+        }
+        self.0.hash(state)
+    }
+}
+
+#[test]
+#[cfg(feature = "portable")]
+fn portable_hashes_are_stable() {
+    // these are stored in portable images, so they must never change
+    assert_eq!(0x94e7_de8d, OpenAddressingMap::<u64, u32>::hash(1));
+    assert_eq!(0x7366_93f8, OpenAddressingMap::<(u8, usize), u32>::hash((2, 3)));
+}
+
+#[test]
+fn ensure_capacity_with_panicking_hash_leaks_instead_of_dropping() {
+    use super::compact_vec::Tracked;
+    Tracked::reset();
+    let mut map: OpenAddressingMap<PanickyKey, Tracked> = OpenAddressingMap::new();
+    for n in 0..3 {
+        map.insert(PanickyKey(n), Tracked(n));
+    }
+
+    HASH_PANICS.with(|panics| panics.set(true));
+    let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+        map.insert(PanickyKey(3), Tracked(3));
+    }));
+    HASH_PANICS.with(|panics| panics.set(false));
+
+    assert!(result.is_err());
+    assert_eq!(0, map.len());
+    map.insert(PanickyKey(4), Tracked(4));
+    assert_eq!(4, map.get(PanickyKey(4)).unwrap().0);
+    ::std::mem::drop(map);
+
+    // the entries that weren't rehashed are leaked, only the new values are dropped
+    let mut dropped = Tracked::dropped();
+    dropped.sort();
+    assert_eq!(vec![3, 4], dropped);
+}
+
 #[test]
 fn compact_notcopy() {
     type NestedType = OpenAddressingMap<usize, CompactVec<usize>>;
@@ -981,3 +1378,52 @@ fn when_there_are_lots_of_few_tombstoned_entries_capacity_is_doubled() {
     assert_eq!(1940, map.len());
     assert_eq!(6421, map.capacity());
 }
+
+#[test]
+fn maps_compare_independent_of_insertion_order() {
+    use super::compact_dict::CompactDict;
+    use super::compact_option::CompactOption;
+    use std::collections::HashSet;
+    let forward: OpenAddressingMap<u32, CompactOption<CompactVec<u32>>> =
+        (0..20).map(|i| (i, CompactOption(Some(vec![i].into())))).collect();
+    let mut backward: OpenAddressingMap<u32, CompactOption<CompactVec<u32>>> =
+        (0..21).rev().map(|i| (i, CompactOption(Some(vec![i].into())))).collect();
+    assert!(forward != backward);
+    assert!(forward < backward);
+    backward.remove(20);
+    assert_eq!(forward, backward);
+    assert_eq!(Some(::std::cmp::Ordering::Equal), forward.partial_cmp(&backward));
+    let set: HashSet<_> = vec![forward.clone(), backward.clone()].into_iter().collect();
+    assert_eq!(1, set.len());
+    backward.insert(3, CompactOption(None));
+    assert!(forward > backward);
+
+    let forward: CompactDict<u32, u64> = (0..20).map(|i| (i, u64::from(i))).collect();
+    let backward: CompactDict<u32, u64> = (0..20).rev().map(|i| (i, u64::from(i))).collect();
+    assert_eq!(forward, backward);
+    let set: HashSet<_> = vec![forward, backward].into_iter().collect();
+    assert_eq!(1, set.len());
+}
+
+#[test]
+fn map_hashes_are_keyed_by_the_callers_hasher() {
+    // finishes with its key and records everything written to it
+    struct Keyed(u64, Vec<u8>);
+    impl Hasher for Keyed {
+        fn finish(&self) -> u64 {
+            self.0
+        }
+        fn write(&mut self, bytes: &[u8]) {
+            self.1.extend_from_slice(bytes)
+        }
+    }
+    let map: OpenAddressingMap<u32, u64> = (0..20).map(|i| (i, u64::from(i))).collect();
+    let mut first = Keyed(1, Vec::new());
+    let mut again = Keyed(1, Vec::new());
+    let mut second = Keyed(2, Vec::new());
+    map.hash(&mut first);
+    map.hash(&mut again);
+    map.hash(&mut second);
+    assert_eq!(first.1, again.1);
+    assert!(first.1 != second.1);
+}
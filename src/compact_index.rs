@@ -0,0 +1,70 @@
+use std::fmt::Debug;
+
+/// An unsigned integer type used to store lengths and capacities of compact containers.
+///
+/// Narrower types can make the static part of a container smaller, as far as
+/// the alignment of its other fields allows, wider types allow for bigger containers.
+///
+/// With the `portable` feature, values are stored little-endian, so only
+/// `from_usize` and `to_usize` give meaningful numbers. `usize` itself is not
+/// fixed-width, so it should be avoided in portable images.
+pub trait CompactIndex: Copy + Eq + Debug {
+    /// The biggest length or capacity that can be stored
+    const MAX: usize;
+
+    /// Convert from a `usize`, panicking if it doesn't fit
+    fn from_usize(n: usize) -> Self;
+
+    /// Convert to a `usize`
+    fn to_usize(self) -> usize;
+}
+
+macro_rules! impl_compact_index {
+    ($($index:ident),*) => {
+        $(
+            impl CompactIndex for $index {
+                const MAX: usize = if ::std::mem::size_of::<$index>() < ::std::mem::size_of::<usize>() {
+                    $index::MAX as usize
+                } else {
+                    usize::MAX
+                };
+
+                fn from_usize(n: usize) -> Self {
+                    assert!(
+                        n <= <Self as CompactIndex>::MAX,
+                        "{} doesn't fit into a {} length/capacity",
+                        n,
+                        stringify!($index)
+                    );
+                    if cfg!(feature = "portable") {
+                        (n as $index).to_le()
+                    } else {
+                        n as $index
+                    }
+                }
+
+                fn to_usize(self) -> usize {
+                    if cfg!(feature = "portable") {
+                        $index::from_le(self) as usize
+                    } else {
+                        self as usize
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_compact_index!(u16, u32, u64, usize);
+
+#[test]
+fn converts_in_range() {
+    assert_eq!(65_535, <u16 as CompactIndex>::from_usize(65_535).to_usize());
+    assert_eq!(70_000, <u32 as CompactIndex>::from_usize(70_000).to_usize());
+}
+
+#[test]
+#[should_panic]
+fn panics_on_overflow() {
+    <u16 as CompactIndex>::from_usize(65_536);
+}
@@ -1,9 +1,11 @@
-use super::compact::Compact;
+use super::compact::{Compact, Headroom, structural_fingerprint, field_position};
+use super::inspect::{LayoutNode, Storage};
+use std::io::{self, Write};
 
 /// A wrapper to make an `Option` of a nontrivial `Compact` possible.
 /// Unfortunately, we can't blanket-`impl` that, since that overlaps
 /// (for the compiler) with the `impl` for trivial `Copy` types...
-#[derive(Clone, Default, Debug)]
+#[derive(Clone, Default, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct CompactOption<T: Compact + Clone>(pub Option<T>);
 
 impl<T: Compact + Clone> ::std::ops::Deref for CompactOption<T> {
@@ -45,6 +47,23 @@ impl<T: Clone + Compact> Compact for CompactOption<T> {
         }
     }
 
+    fn dynamic_size_bytes_tight(&self) -> usize {
+        self.0.as_ref().map(|t| t.dynamic_size_bytes_tight()).unwrap_or(0)
+    }
+
+    unsafe fn compact_tight(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        if let CompactOption(Some(ref mut s)) = *source {
+            ::std::ptr::copy_nonoverlapping(source, dest, 1);
+            if let CompactOption(Some(ref mut d)) = *dest {
+                Compact::compact_tight(s, d, new_dynamic_part);
+            } else {
+                unreachable!()
+            }
+        } else {
+            ::std::ptr::write(dest, CompactOption(None));
+        }
+    }
+
     unsafe fn decompact(source: *const Self) -> Self {
         if let CompactOption(Some(ref s)) = *source {
             CompactOption(Some(Compact::decompact(s)))
@@ -52,8 +71,59 @@ impl<T: Clone + Compact> Compact for CompactOption<T> {
             CompactOption(None)
         }
     }
+
+    fn reserve_headroom(&mut self, headroom: Headroom) {
+        if let Some(ref mut value) = self.0 {
+            value.reserve_headroom(headroom)
+        }
+    }
+
+    fn inspect_layout(&self) -> LayoutNode {
+        let node = LayoutNode::new(self, Storage::None);
+        match self.0 {
+            Some(ref value) => node.child_unless_trivial("Some", value.inspect_layout()),
+            None => node,
+        }
+    }
+
+    fn type_fingerprint() -> u64 {
+        structural_fingerprint::<Self>("CompactOption", &[T::type_fingerprint()])
+    }
+
+    fn streams_compact() -> bool {
+        T::streams_compact()
+    }
+
+    fn uses_instance_handles() -> bool {
+        T::uses_instance_handles()
+    }
+
+    fn max_align() -> usize {
+        ::std::cmp::max(::std::mem::align_of::<Self>(), T::max_align())
+    }
+
+    unsafe fn compact_static_to(&self, dest: *mut Self, at: usize, dynamic_at: usize) {
+        if let CompactOption(Some(ref s)) = *self {
+            ::std::ptr::write(dest, CompactOption(Some(::std::ptr::read(s))));
+            if let CompactOption(Some(ref mut d)) = *dest {
+                let d_at = field_position(dest, d, at);
+                s.compact_static_to(d, d_at, dynamic_at);
+            }
+        } else {
+            ::std::ptr::write(dest, CompactOption(None));
+        }
+    }
+
+    fn write_dynamic_compact(&self, dynamic_at: usize, out: &mut dyn Write) -> io::Result<()> {
+        match self.0 {
+            Some(ref s) => s.write_dynamic_compact(dynamic_at, out),
+            None => Ok(()),
+        }
+    }
 }
 
+#[cfg(feature = "serde-serialization")]
+use super::deserialize::{DeserializeCompact, DeserializePlan};
 #[cfg(feature = "serde-serialization")]
 use std::marker::PhantomData;
 
@@ -121,6 +191,106 @@ impl<'de, T: Compact + ::serde::de::Deserialize<'de>> ::serde::de::Deserialize<'
     }
 }
 
+#[cfg(feature = "serde-serialization")]
+struct MeasureOption<'p, T> {
+    plan: &'p mut DeserializePlan,
+    marker: PhantomData<fn() -> T>,
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'de, 'p, T: DeserializeCompact<'de>> ::serde::de::Visitor<'de> for MeasureOption<'p, T> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        formatter.write_str("An option")
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<usize, D::Error>
+    where
+        D: ::serde::de::Deserializer<'de>,
+    {
+        let reserved = self.plan.reserve_length();
+        self.plan.set_length(reserved, 1);
+        T::measure(deserializer, self.plan)
+    }
+
+    fn visit_none<E>(self) -> Result<usize, E>
+    where
+        E: ::serde::de::Error,
+    {
+        let reserved = self.plan.reserve_length();
+        self.plan.set_length(reserved, 0);
+        Ok(0)
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+struct WriteOption<'p, T: Compact> {
+    plan: &'p mut DeserializePlan,
+    dest: *mut CompactOption<T>,
+    dynamic_part: *mut u8,
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'de, 'p, T: DeserializeCompact<'de>> ::serde::de::Visitor<'de> for WriteOption<'p, T> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        formatter.write_str("An option")
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<usize, D::Error>
+    where
+        D: ::serde::de::Deserializer<'de>,
+    {
+        if self.plan.next_length::<D::Error>()? != 1 {
+            return Err(::serde::de::Error::invalid_length(1, &self));
+        }
+        unsafe {
+            // the value is written straight into its slot inside the option, so its
+            // relative pointers are correct from the start, then the option is marked `Some`
+            let option = ::std::ptr::addr_of_mut!((*self.dest).0) as *mut u8;
+            let slot = option.add(::std::mem::offset_of!(Option<T>, Some.0)) as *mut T;
+            let size = T::deserialize_compact_into(deserializer, self.plan, slot, self.dynamic_part)?;
+            ::std::ptr::write(self.dest, CompactOption(Some(::std::ptr::read(slot))));
+            Ok(size)
+        }
+    }
+
+    fn visit_none<E>(self) -> Result<usize, E>
+    where
+        E: ::serde::de::Error,
+    {
+        if self.plan.next_length::<E>()? != 0 {
+            return Err(E::invalid_length(0, &self));
+        }
+        unsafe { ::std::ptr::write(self.dest, CompactOption(None)) };
+        Ok(0)
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'de, T: DeserializeCompact<'de>> DeserializeCompact<'de> for CompactOption<T> {
+    fn measure<D>(deserializer: D, plan: &mut DeserializePlan) -> Result<usize, D::Error>
+    where
+        D: ::serde::de::Deserializer<'de>,
+    {
+        deserializer.deserialize_option(MeasureOption::<T> { plan, marker: PhantomData })
+    }
+
+    unsafe fn deserialize_compact_into<D>(
+        deserializer: D,
+        plan: &mut DeserializePlan,
+        dest: *mut Self,
+        dynamic_part: *mut u8,
+    ) -> Result<usize, D::Error>
+    where
+        D: ::serde::de::Deserializer<'de>,
+    {
+        deserializer.deserialize_option(WriteOption { plan, dest, dynamic_part })
+    }
+}
+
 #[test]
 fn basic_option() {
     use super::compact_vec::CompactVec;
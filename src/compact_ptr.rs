@@ -0,0 +1,297 @@
+use super::allocator::InstanceAllocator;
+use super::simple_allocator_trait::DefaultHeap;
+#[cfg(test)]
+use super::simple_allocator_trait::Allocator;
+use super::pointer_to_maybe_compact::PointerToMaybeCompact;
+use super::compact::{Compact, Headroom, structural_fingerprint, allocator_fingerprint};
+use super::compact::{field_position, write_static_compact, write_zeros};
+use super::inspect::LayoutNode;
+use super::stats;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::mem;
+use std::io::{self, Write};
+
+/// An owning pointer to a single `T`, like `Box<T>`, which is either stored freely
+/// on the heap using `InstanceAllocator`, or compactly in the dynamic part of its parent.
+///
+/// This is the building block for compactable recursive structures
+/// (trees, linked lists) with the same semantics that `CompactVec` uses.
+pub struct CompactPtr<T, A: InstanceAllocator = DefaultHeap> {
+    /// Points to either compact or free storage, never null
+    ptr: PointerToMaybeCompact<T>,
+    /// Handle of the allocator for free heap storage, zero-sized for static allocators
+    alloc: A::Handle,
+}
+
+impl<T: Compact, A: InstanceAllocator> CompactPtr<T, A>
+where
+    A::Handle: Default,
+{
+    /// Move `value` into free heap storage
+    pub fn new(value: T) -> CompactPtr<T, A> {
+        Self::new_in(value, A::Handle::default())
+    }
+}
+
+impl<T: Compact, A: InstanceAllocator> CompactPtr<T, A> {
+    /// Move `value` into free heap storage allocated using `alloc`
+    pub fn new_in(value: T, alloc: A::Handle) -> CompactPtr<T, A> {
+        let mut ptr = PointerToMaybeCompact::default();
+        stats::record::<Self>(|stats| stats.heap_bytes += mem::size_of::<T>());
+        let storage = A::allocate_in::<T>(&alloc, 1);
+        unsafe { ptr::write(storage, value) };
+        ptr.set_to_free(storage);
+
+        CompactPtr { ptr, alloc }
+    }
+
+    /// The handle of the allocator used for free heap storage
+    pub fn allocator(&self) -> &A::Handle {
+        &self.alloc
+    }
+
+    /// Move the pointed-to value out, freeing heap storage, if any is used
+    pub fn into_inner(mut self) -> T {
+        unsafe {
+            // the value should be decompacted, else internal relative pointers get messed up!
+            let value = Compact::decompact(self.ptr.mut_ptr());
+            Self::release(&self.ptr, &self.alloc);
+            let alloc = ptr::read(&self.alloc);
+            ::std::mem::forget(self);
+            drop(alloc);
+            value
+        }
+    }
+
+    /// `Compact::compact` or, if `tight`, `Compact::compact_tight`
+    unsafe fn compact_value(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8, tight: bool) {
+        let padding = new_dynamic_part.align_offset(::std::mem::align_of::<T>());
+        let new_value = new_dynamic_part.add(padding) as *mut T;
+        if tight {
+            Compact::compact_behind_tight((*source).ptr.mut_ptr(), new_value);
+        } else {
+            Compact::compact_behind((*source).ptr.mut_ptr(), new_value);
+        }
+        (*dest).ptr.set_to_compact(new_value);
+        stats::record::<Self>(|stats| {
+            stats.recompactions += 1;
+            stats.compact_bytes += mem::size_of::<T>();
+        });
+        // the handle moves along with the value
+        ptr::write(&mut (*dest).alloc, ptr::read(&(*source).alloc));
+
+        // we want to free any allocated space,
+        // but not semantically drop our contents (they just moved)
+        Self::release(&(*source).ptr, &(*dest).alloc);
+    }
+
+    /// Padding needed in front of the value if the dynamic part starts at `position`
+    /// of an image, mirroring `align_offset` in `compact`, since images are aligned
+    /// to at least `Compact::max_align`
+    fn padding_at(position: usize) -> usize {
+        let align = mem::align_of::<T>();
+        (align - position % align) % align
+    }
+}
+
+impl<T, A: InstanceAllocator> CompactPtr<T, A> {
+    /// Stop using the storage of `ptr`, deallocating it if it is free
+    fn release(ptr: &PointerToMaybeCompact<T>, alloc: &A::Handle) {
+        let bytes = mem::size_of::<T>();
+        if ptr.is_compact() {
+            stats::record::<Self>(|stats| stats.compact_bytes = stats.compact_bytes.saturating_sub(bytes));
+        } else {
+            stats::record::<Self>(|stats| stats.heap_bytes = stats.heap_bytes.saturating_sub(bytes));
+        }
+        ptr.deallocate_if_free::<A>(alloc, 1);
+    }
+}
+
+impl<T, A: InstanceAllocator> Deref for CompactPtr<T, A> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr.ptr() }
+    }
+}
+
+impl<T, A: InstanceAllocator> DerefMut for CompactPtr<T, A> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.ptr.mut_ptr() }
+    }
+}
+
+impl<T, A: InstanceAllocator> Drop for CompactPtr<T, A> {
+    /// Drop the value and deallocate free heap storage, if any is used
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.ptr.mut_ptr()) };
+        Self::release(&self.ptr, &self.alloc);
+    }
+}
+
+impl<T: Compact, A: InstanceAllocator> Compact for CompactPtr<T, A> {
+    fn is_still_compact(&self) -> bool {
+        self.ptr.is_compact() && (**self).is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        // worst-case padding, since the value has to be aligned
+        // wherever in its parent the dynamic part ends up
+        ::std::mem::align_of::<T>() - 1 + ::std::mem::size_of::<T>()
+            + (**self).dynamic_size_bytes()
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        Self::compact_value(source, dest, new_dynamic_part, false)
+    }
+
+    fn dynamic_size_bytes_tight(&self) -> usize {
+        ::std::mem::align_of::<T>() - 1 + ::std::mem::size_of::<T>()
+            + (**self).dynamic_size_bytes_tight()
+    }
+
+    unsafe fn compact_tight(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        Self::compact_value(source, dest, new_dynamic_part, true)
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        if (*source).ptr.is_compact() {
+            stats::record::<Self>(|stats| {
+                stats.decompactions += 1;
+                stats.compact_bytes = stats.compact_bytes.saturating_sub(mem::size_of::<T>());
+            });
+            // like the value, the handle is moved out of the compact source
+            CompactPtr::new_in(Compact::decompact((*source).ptr.ptr()), ptr::read(&(*source).alloc))
+        } else {
+            CompactPtr {
+                ptr: ptr::read(&(*source).ptr as *const PointerToMaybeCompact<T>),
+                alloc: ptr::read(&(*source).alloc),
+            }
+            // caller has to make sure that self will not be dropped!
+        }
+    }
+
+    fn reserve_headroom(&mut self, headroom: Headroom) {
+        (**self).reserve_headroom(headroom)
+    }
+
+    fn inspect_layout(&self) -> LayoutNode {
+        LayoutNode::new(self, self.ptr.storage()).child("*", (**self).inspect_layout())
+    }
+
+    fn type_fingerprint() -> u64 {
+        structural_fingerprint::<Self>("CompactPtr", &[T::type_fingerprint(), allocator_fingerprint::<A>()])
+    }
+
+    fn streams_compact() -> bool {
+        T::streams_compact()
+    }
+
+    fn uses_instance_handles() -> bool {
+        mem::size_of::<A::Handle>() != 0 || T::uses_instance_handles()
+    }
+
+    fn max_align() -> usize {
+        ::std::cmp::max(mem::align_of::<Self>(), T::max_align())
+    }
+
+    unsafe fn compact_static_to(&self, dest: *mut Self, at: usize, dynamic_at: usize) {
+        let ptr_at = field_position(dest, &(*dest).ptr, at);
+        let value_at = dynamic_at + Self::padding_at(dynamic_at);
+        (*dest).ptr.set_to_compact_offset(value_at as isize - ptr_at as isize);
+        // only zero-sized handles leave the process, so the handle isn't cloned into the image
+        ptr::copy_nonoverlapping(&self.alloc, &mut (*dest).alloc, 1);
+    }
+
+    fn write_dynamic_compact(&self, dynamic_at: usize, out: &mut dyn Write) -> io::Result<()> {
+        let padding = Self::padding_at(dynamic_at);
+        let value_at = dynamic_at + padding;
+        write_zeros(padding, out)?;
+        write_static_compact(&**self, value_at, value_at + mem::size_of::<T>(), out)?;
+        (**self).write_dynamic_compact(value_at + mem::size_of::<T>(), out)?;
+        // rest of the worst-case padding
+        write_zeros(mem::align_of::<T>() - 1 - padding, out)
+    }
+}
+
+impl<T: Compact, A: InstanceAllocator> Clone for CompactPtr<T, A> {
+    fn clone(&self) -> CompactPtr<T, A> {
+        CompactPtr::new_in((**self).clone(), self.alloc.clone())
+    }
+}
+
+impl<T: Compact + Default, A: InstanceAllocator> Default for CompactPtr<T, A>
+where
+    A::Handle: Default,
+{
+    fn default() -> CompactPtr<T, A> {
+        CompactPtr::new(T::default())
+    }
+}
+
+impl<T: Compact + ::std::fmt::Debug, A: InstanceAllocator> ::std::fmt::Debug for CompactPtr<T, A> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: PartialEq<U>, U, A: InstanceAllocator, B: InstanceAllocator> PartialEq<CompactPtr<U, B>> for CompactPtr<T, A> {
+    fn eq(&self, other: &CompactPtr<U, B>) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Eq, A: InstanceAllocator> Eq for CompactPtr<T, A> {}
+
+impl<T: PartialOrd, A: InstanceAllocator, B: InstanceAllocator> PartialOrd<CompactPtr<T, B>> for CompactPtr<T, A> {
+    fn partial_cmp(&self, other: &CompactPtr<T, B>) -> Option<::std::cmp::Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: Ord, A: InstanceAllocator> Ord for CompactPtr<T, A> {
+    fn cmp(&self, other: &Self) -> ::std::cmp::Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: ::std::hash::Hash, A: InstanceAllocator> ::std::hash::Hash for CompactPtr<T, A> {
+    fn hash<H: ::std::hash::Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+#[test]
+fn basic_ptr() {
+    use super::compact_vec::CompactVec;
+    let mut boxed: CompactPtr<CompactVec<u32>> = CompactPtr::new(vec![1, 2, 3].into());
+    boxed.push(4);
+    let cloned = boxed.clone();
+    assert_eq!(&[1, 2, 3, 4], &**cloned);
+    assert_eq!(&[1, 2, 3, 4], &*boxed.into_inner());
+}
+
+#[test]
+fn nested_ptrs_in_compact_storage() {
+    use super::compact_vec::CompactVec;
+    type NestedType = CompactVec<CompactPtr<CompactVec<u32>>>;
+    let mut list: NestedType = CompactVec::new();
+    list.push(CompactPtr::new(vec![1, 2, 3].into()));
+    list.push(CompactPtr::new(vec![4, 5].into()));
+
+    let bytes = list.total_size_bytes();
+    let storage = DefaultHeap::allocate(bytes);
+
+    unsafe {
+        Compact::compact_behind(&mut list, storage as *mut NestedType);
+        ::std::mem::forget(list);
+        assert!((*(storage as *mut NestedType)).is_still_compact());
+        assert_eq!(&[1, 2, 3], &**(*(storage as *mut NestedType))[0]);
+        assert_eq!(&[4, 5], &**(*(storage as *mut NestedType))[1]);
+        let decompacted = Compact::decompact(storage as *mut NestedType);
+        assert!(!decompacted.is_still_compact());
+        assert_eq!(&[4, 5], &**decompacted[1]);
+        DefaultHeap::deallocate(storage, bytes);
+    }
+}
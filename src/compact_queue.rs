@@ -0,0 +1,249 @@
+use super::compact::Compact;
+use super::ring::{Ring, RingMessage, RECORD_ALIGN};
+use std::alloc::{self, Layout};
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+const MAGIC: [u8; 8] = *b"CMPCTQUE";
+
+/// The heap memory of a queue, shared by its senders and receiver
+struct Queue<T: Compact> {
+    memory: *mut u8,
+    layout: Layout,
+    ring: Ring,
+    senders: AtomicUsize,
+    _message: PhantomData<T>,
+}
+
+// messages are moved from the senders' threads to the receiver's thread,
+// everything else is synchronized by the ring
+unsafe impl<T: Compact + Send> Send for Queue<T> {}
+unsafe impl<T: Compact + Send> Sync for Queue<T> {}
+
+impl<T: Compact> Queue<T> {
+    fn new(capacity: usize, single_sender: bool) -> Queue<T> {
+        assert!(
+            mem::align_of::<T>() <= RECORD_ALIGN,
+            "compact queues only support types aligned to at most {} bytes",
+            RECORD_ALIGN
+        );
+        let capacity = Ring::round_capacity(capacity);
+        let layout = Layout::from_size_align(Ring::memory_size(capacity), 64).expect("queue too large");
+        unsafe {
+            let memory = alloc::alloc_zeroed(layout);
+            if memory.is_null() {
+                alloc::handle_alloc_error(layout);
+            }
+            let ring = Ring::init(memory, capacity, MAGIC, T::type_fingerprint());
+            Queue {
+                memory,
+                layout,
+                ring: if single_sender {
+                    ring.with_single_sender()
+                } else {
+                    ring
+                },
+                senders: AtomicUsize::new(1),
+                _message: PhantomData,
+            }
+        }
+    }
+}
+
+impl<T: Compact> Drop for Queue<T> {
+    fn drop(&mut self) {
+        unsafe {
+            // drop messages that were never received
+            while let Some(message) = self.ring.try_recv::<T>() {
+                drop(message);
+            }
+            alloc::dealloc(self.memory, self.layout);
+        }
+    }
+}
+
+/// Create a bounded queue of compact messages for one sending and one receiving thread,
+/// which can hold at least `capacity` bytes of messages.
+///
+/// Messages are compacted directly into the queue's ring buffer and received in-place,
+/// so sending and receiving doesn't allocate.
+pub fn spsc_queue<T: Compact>(capacity: usize) -> (QueueSender<T>, QueueReceiver<T>) {
+    let queue = Arc::new(Queue::new(capacity, true));
+    (
+        QueueSender {
+            queue: queue.clone(),
+            _not_sync: PhantomData,
+        },
+        QueueReceiver { queue },
+    )
+}
+
+/// Create a bounded queue of compact messages for any number of sending threads
+/// and one receiving thread, which can hold at least `capacity` bytes of messages.
+///
+/// Messages are compacted directly into the queue's ring buffer and received in-place,
+/// so sending and receiving doesn't allocate.
+pub fn mpsc_queue<T: Compact>(capacity: usize) -> (MultiQueueSender<T>, QueueReceiver<T>) {
+    let queue = Arc::new(Queue::new(capacity, false));
+    (
+        MultiQueueSender(QueueSender {
+            queue: queue.clone(),
+            _not_sync: PhantomData,
+        }),
+        QueueReceiver { queue },
+    )
+}
+
+/// Sends messages into a queue created by `spsc_queue`
+pub struct QueueSender<T: Compact> {
+    queue: Arc<Queue<T>>,
+    /// The single-sender queue reserves space without synchronizing senders,
+    /// so a sender must not be shared between threads
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl<T: Compact> QueueSender<T> {
+    /// Size of the largest message that can be sent
+    pub fn max_message_size(&self) -> usize {
+        self.queue.ring.max_message_size()
+    }
+
+    /// Compact `message` into the queue, or give it back if the queue is full.
+    ///
+    /// Panics if the message is larger than `max_message_size`.
+    pub fn try_send(&self, message: T) -> Result<(), T> {
+        self.queue.ring.try_send(message)
+    }
+
+    /// Compact `message` into the queue, waiting for space if it is full.
+    ///
+    /// Panics if the message is larger than `max_message_size`.
+    pub fn send(&self, message: T) {
+        self.queue.ring.send(message)
+    }
+}
+
+impl<T: Compact> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        self.queue.senders.fetch_sub(1, Ordering::SeqCst);
+        self.queue.ring.notify_receiver();
+    }
+}
+
+/// Sends messages into a queue created by `mpsc_queue`. Can be cloned to send from
+/// several threads.
+pub struct MultiQueueSender<T: Compact>(QueueSender<T>);
+
+impl<T: Compact> Clone for MultiQueueSender<T> {
+    fn clone(&self) -> MultiQueueSender<T> {
+        self.0.queue.senders.fetch_add(1, Ordering::SeqCst);
+        MultiQueueSender(QueueSender {
+            queue: self.0.queue.clone(),
+            _not_sync: PhantomData,
+        })
+    }
+}
+
+// a multi-sender queue synchronizes reserving space between senders
+unsafe impl<T: Compact + Send> Sync for MultiQueueSender<T> {}
+
+impl<T: Compact> Deref for MultiQueueSender<T> {
+    type Target = QueueSender<T>;
+
+    fn deref(&self) -> &QueueSender<T> {
+        &self.0
+    }
+}
+
+/// Receives messages from a queue, in-place
+pub struct QueueReceiver<T: Compact> {
+    queue: Arc<Queue<T>>,
+}
+
+impl<T: Compact> QueueReceiver<T> {
+    /// Receive the oldest message in-place, if there is one.
+    /// It is removed from the queue when the returned message is dropped.
+    pub fn try_recv(&mut self) -> Option<RingMessage<'_, T>> {
+        unsafe { self.queue.ring.try_recv() }
+    }
+
+    /// Receive the oldest message in-place, waiting for one if the queue is empty.
+    /// It is removed from the queue when the returned message is dropped.
+    ///
+    /// Returns `None` once the queue is empty and all senders were dropped.
+    pub fn recv(&mut self) -> Option<RingMessage<'_, T>> {
+        let queue = &self.queue;
+        unsafe {
+            queue
+                .ring
+                .recv_unless(|| queue.senders.load(Ordering::SeqCst) == 0)
+        }
+    }
+}
+
+#[test]
+fn messages_are_received_in_place() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    type Message = CompactVec<CompactString>;
+    let (sender, mut receiver) = spsc_queue::<Message>(256);
+
+    assert!(receiver.try_recv().is_none());
+    // wraps around the ring several times
+    for i in 0..50 {
+        let message: Message = vec![format!("message {}", i).into(), "end".to_owned().into()].into();
+        sender.send(message);
+        let received = receiver.recv().unwrap();
+        assert!(received.is_still_compact());
+        assert_eq!(format!("message {}", i), &*received[0]);
+    }
+
+    sender.send(vec!["owned".to_owned().into()].into());
+    sender.send(vec!["last".to_owned().into()].into());
+    let owned = receiver.recv().unwrap().into_inner();
+    assert_eq!("owned", &*owned[0]);
+
+    drop(sender);
+    assert!(receiver.recv().is_some());
+    assert!(receiver.recv().is_none());
+}
+
+#[test]
+fn many_senders() {
+    let (sender, mut receiver) = mpsc_queue::<(u32, u32)>(128);
+    let threads = (0..4)
+        .map(|thread| {
+            let sender = sender.clone();
+            ::std::thread::spawn(move || {
+                for i in 0..1000 {
+                    sender.send((thread, i));
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(sender);
+
+    let mut next = [0; 4];
+    while let Some(message) = receiver.recv() {
+        let (thread, i) = *message;
+        assert_eq!(next[thread as usize], i);
+        next[thread as usize] += 1;
+    }
+    assert_eq!([1000; 4], next);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn only_multi_senders_are_shareable() {
+    // checked at compile time
+    use super::compact_str::CompactString;
+    assert_impl_all!(QueueSender<CompactString>: Send);
+    assert_not_impl_any!(QueueSender<CompactString>: Sync);
+    assert_impl_all!(MultiQueueSender<CompactString>: Send, Sync);
+}
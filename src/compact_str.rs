@@ -1,5 +1,7 @@
-use super::compact::Compact;
+use super::compact::{Compact, Headroom, structural_fingerprint, field_position};
 use super::compact_vec::CompactVec;
+use super::inspect::LayoutNode;
+use std::io::{self, Write};
 
 /// A compact storage for a `String`. So far doesn't support direct mutable operations,
 /// Only conversion from and to `String`/`&str`
@@ -28,6 +30,45 @@ impl ::std::ops::Deref for CompactString {
     }
 }
 
+impl PartialEq for CompactString {
+    fn eq(&self, other: &CompactString) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for CompactString {}
+
+impl PartialEq<str> for CompactString {
+    fn eq(&self, other: &str) -> bool {
+        &**self == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for CompactString {
+    fn eq(&self, other: &&'a str) -> bool {
+        &**self == *other
+    }
+}
+
+impl PartialOrd for CompactString {
+    fn partial_cmp(&self, other: &CompactString) -> Option<::std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CompactString {
+    fn cmp(&self, other: &CompactString) -> ::std::cmp::Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl ::std::hash::Hash for CompactString {
+    /// Hashes like a `str`
+    fn hash<H: ::std::hash::Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
 impl ::std::convert::From<String> for CompactString {
     fn from(string: String) -> CompactString {
         CompactString {
@@ -49,13 +90,49 @@ impl Compact for CompactString {
         Compact::compact(&mut (*source).chars, &mut (*dest).chars, new_dynamic_part)
     }
 
+    fn dynamic_size_bytes_tight(&self) -> usize {
+        self.chars.dynamic_size_bytes_tight()
+    }
+
+    unsafe fn compact_tight(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        Compact::compact_tight(&mut (*source).chars, &mut (*dest).chars, new_dynamic_part)
+    }
+
     unsafe fn decompact(source: *const Self) -> Self {
         CompactString {
             chars: Compact::decompact(&(*source).chars),
         }
     }
+
+    fn reserve_headroom(&mut self, headroom: Headroom) {
+        self.chars.reserve_headroom(headroom)
+    }
+
+    fn inspect_layout(&self) -> LayoutNode {
+        let chars = self.chars.inspect_layout();
+        LayoutNode::new(self, chars.storage).wasting(chars.wasted_bytes)
+    }
+
+    fn type_fingerprint() -> u64 {
+        structural_fingerprint::<Self>("CompactString", &[])
+    }
+
+    fn streams_compact() -> bool {
+        true
+    }
+
+    unsafe fn compact_static_to(&self, dest: *mut Self, at: usize, dynamic_at: usize) {
+        let chars_at = field_position(dest, &(*dest).chars, at);
+        self.chars.compact_static_to(&mut (*dest).chars, chars_at, dynamic_at)
+    }
+
+    fn write_dynamic_compact(&self, dynamic_at: usize, out: &mut dyn Write) -> io::Result<()> {
+        self.chars.write_dynamic_compact(dynamic_at, out)
+    }
 }
 
+#[cfg(feature = "serde-serialization")]
+use super::deserialize::{DeserializeCompact, DeserializePlan};
 #[cfg(feature = "serde-serialization")]
 use std::marker::PhantomData;
 
@@ -117,4 +194,80 @@ impl<'de> ::serde::de::Deserialize<'de> for CompactString
     {
         deserializer.deserialize_string(CompactStringVisitor::new())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "serde-serialization")]
+struct MeasureStr<'p> {
+    plan: &'p mut DeserializePlan,
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'de, 'p> ::serde::de::Visitor<'de> for MeasureStr<'p> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        formatter.write_str("A string")
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<usize, E>
+    where
+        E: ::serde::de::Error,
+    {
+        let reserved = self.plan.reserve_length();
+        self.plan.set_length(reserved, s.len());
+        Ok(s.len())
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+struct WriteStr<'p> {
+    plan: &'p mut DeserializePlan,
+    dest: *mut CompactString,
+    dynamic_part: *mut u8,
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'de, 'p> ::serde::de::Visitor<'de> for WriteStr<'p> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        formatter.write_str("A string")
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<usize, E>
+    where
+        E: ::serde::de::Error,
+    {
+        let len = self.plan.next_length()?;
+        if s.len() != len {
+            return Err(E::invalid_length(s.len(), &self));
+        }
+        unsafe {
+            CompactVec::write_compact_static(&mut (*self.dest).chars, len, Default::default(), self.dynamic_part);
+            ::std::ptr::copy_nonoverlapping(s.as_ptr(), self.dynamic_part, len);
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'de> DeserializeCompact<'de> for CompactString {
+    fn measure<D>(deserializer: D, plan: &mut DeserializePlan) -> Result<usize, D::Error>
+    where
+        D: ::serde::de::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(MeasureStr { plan })
+    }
+
+    unsafe fn deserialize_compact_into<D>(
+        deserializer: D,
+        plan: &mut DeserializePlan,
+        dest: *mut Self,
+        dynamic_part: *mut u8,
+    ) -> Result<usize, D::Error>
+    where
+        D: ::serde::de::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(WriteStr { plan, dest, dynamic_part })
+    }
+}
@@ -0,0 +1,655 @@
+use super::allocator::InstanceAllocator;
+use super::simple_allocator_trait::DefaultHeap;
+#[cfg(test)]
+use super::simple_allocator_trait::Allocator;
+use super::compact::{Compact, Headroom, structural_fingerprint, allocator_fingerprint, field_position};
+use super::inspect::{LayoutNode, Storage};
+use super::compact_vec::CompactVec;
+use std::collections::VecDeque;
+use std::ptr;
+use std::io::{self, Write};
+
+const NONE: u32 = u32::MAX;
+
+/// Identifies a node in a `CompactTree`.
+///
+/// Ids of removed nodes are reused by nodes that are inserted later.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct NodeId(u32);
+
+#[derive(Clone)]
+struct Node<T> {
+    parent: u32,
+    first_child: u32,
+    last_child: u32,
+    prev_sibling: u32,
+    /// For free nodes, links to the next free node
+    next_sibling: u32,
+    value: Option<T>,
+}
+
+/// A forest of ordered trees, stored as one flat `CompactVec` of nodes
+/// that link to each other by index. This makes it possible to store
+/// recursive structures like scene graphs or syntax trees
+/// compactly in one consecutive region.
+///
+/// Spilling behaviour using `InstanceAllocator` is equivalent to `CompactVec`.
+pub struct CompactTree<T, A: InstanceAllocator = DefaultHeap> {
+    nodes: CompactVec<Node<T>, A>,
+    first_root: u32,
+    last_root: u32,
+    first_free: u32,
+    len: u32,
+}
+
+impl<T> Node<T> {
+    fn new(parent: u32, prev_sibling: u32, value: T) -> Self {
+        Node {
+            parent,
+            first_child: NONE,
+            last_child: NONE,
+            prev_sibling,
+            next_sibling: NONE,
+            value: Some(value),
+        }
+    }
+}
+
+impl<T: Compact> Compact for Node<T> {
+    fn is_still_compact(&self) -> bool {
+        self.value.as_ref().is_none_or(|value| value.is_still_compact())
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        self.value.as_ref().map_or(0, |value| value.dynamic_size_bytes())
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        ptr::copy_nonoverlapping(source, dest, 1);
+        if (*dest).value.is_some() {
+            Compact::compact(
+                (*source).value.as_mut().unwrap(),
+                (*dest).value.as_mut().unwrap(),
+                new_dynamic_part,
+            )
+        }
+    }
+
+    fn dynamic_size_bytes_tight(&self) -> usize {
+        self.value.as_ref().map_or(0, |value| value.dynamic_size_bytes_tight())
+    }
+
+    unsafe fn compact_tight(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        ptr::copy_nonoverlapping(source, dest, 1);
+        if (*dest).value.is_some() {
+            Compact::compact_tight(
+                (*source).value.as_mut().unwrap(),
+                (*dest).value.as_mut().unwrap(),
+                new_dynamic_part,
+            )
+        }
+    }
+
+    unsafe fn decompact(source: *const Self) -> Node<T> {
+        Node {
+            parent: (*source).parent,
+            first_child: (*source).first_child,
+            last_child: (*source).last_child,
+            prev_sibling: (*source).prev_sibling,
+            next_sibling: (*source).next_sibling,
+            value: (*source).value.as_ref().map(|value| Compact::decompact(value)),
+        }
+    }
+
+    fn reserve_headroom(&mut self, headroom: Headroom) {
+        if let Some(ref mut value) = self.value {
+            value.reserve_headroom(headroom)
+        }
+    }
+
+    fn inspect_layout(&self) -> LayoutNode {
+        let node = LayoutNode::new(self, Storage::None);
+        match self.value {
+            Some(ref value) => node.child_unless_trivial("value", value.inspect_layout()),
+            None => node,
+        }
+    }
+
+    fn streams_compact() -> bool {
+        T::streams_compact()
+    }
+
+    fn uses_instance_handles() -> bool {
+        T::uses_instance_handles()
+    }
+
+    fn max_align() -> usize {
+        ::std::cmp::max(::std::mem::align_of::<Self>(), T::max_align())
+    }
+
+    fn type_fingerprint() -> u64 {
+        structural_fingerprint::<Self>("CompactTreeNode", &[T::type_fingerprint()])
+    }
+
+    unsafe fn compact_static_to(&self, dest: *mut Self, at: usize, dynamic_at: usize) {
+        ptr::write(
+            dest,
+            Node {
+                parent: self.parent,
+                first_child: self.first_child,
+                last_child: self.last_child,
+                prev_sibling: self.prev_sibling,
+                next_sibling: self.next_sibling,
+                value: self.value.as_ref().map(|value| ptr::read(value)),
+            },
+        );
+        if let Some(ref value) = self.value {
+            let dest_value = (*dest).value.as_mut().unwrap();
+            let value_at = field_position(dest, dest_value, at);
+            value.compact_static_to(dest_value, value_at, dynamic_at);
+        }
+    }
+
+    fn write_dynamic_compact(&self, dynamic_at: usize, out: &mut dyn Write) -> io::Result<()> {
+        match self.value {
+            Some(ref value) => value.write_dynamic_compact(dynamic_at, out),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: Compact, A: InstanceAllocator> CompactTree<T, A>
+where
+    A::Handle: Default,
+{
+    /// Create a new, empty tree
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    /// Create a new, empty tree with space for `cap` nodes
+    pub fn with_capacity(cap: usize) -> Self {
+        Self::with_capacity_in(cap, A::Handle::default())
+    }
+}
+
+impl<T: Compact, A: InstanceAllocator> CompactTree<T, A> {
+    /// Create a new, empty tree that allocates using `alloc`
+    pub fn new_in(alloc: A::Handle) -> Self {
+        Self::with_capacity_in(0, alloc)
+    }
+
+    /// Create a new, empty tree with space for `cap` nodes, allocated using `alloc`
+    pub fn with_capacity_in(cap: usize, alloc: A::Handle) -> Self {
+        CompactTree {
+            nodes: CompactVec::with_capacity_in(cap, alloc),
+            first_root: NONE,
+            last_root: NONE,
+            first_free: NONE,
+            len: 0,
+        }
+    }
+
+    /// Amount of nodes in the tree
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Is the tree empty?
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterator over the ids of all root nodes
+    pub fn roots(&self) -> Siblings<'_, T, A> {
+        Siblings {
+            tree: self,
+            next: self.first_root,
+        }
+    }
+
+    /// The parent of `node`, if it isn't a root
+    pub fn parent(&self, node: NodeId) -> Option<NodeId> {
+        some_id(self.alive_node(node).parent)
+    }
+
+    /// Iterator over the ids of the children of `node`
+    pub fn children(&self, node: NodeId) -> Siblings<'_, T, A> {
+        Siblings {
+            tree: self,
+            next: self.alive_node(node).first_child,
+        }
+    }
+
+    /// Look up the value of `node`, if it exists
+    pub fn get(&self, node: NodeId) -> Option<&T> {
+        self.nodes
+            .get(node.0 as usize)
+            .and_then(|node| node.value.as_ref())
+    }
+
+    /// Look up the value of `node` mutably, if it exists
+    pub fn get_mut(&mut self, node: NodeId) -> Option<&mut T> {
+        self.nodes
+            .get_mut(node.0 as usize)
+            .and_then(|node| node.value.as_mut())
+    }
+
+    /// Add a new root node after all existing ones
+    pub fn push_root(&mut self, value: T) -> NodeId {
+        let last_root = self.last_root;
+        let id = self.allocate_node(Node::new(NONE, last_root, value));
+        if last_root == NONE {
+            self.first_root = id;
+        } else {
+            self.nodes[last_root as usize].next_sibling = id;
+        }
+        self.last_root = id;
+        NodeId(id)
+    }
+
+    /// Add a new node as the last child of `parent`
+    pub fn push_child(&mut self, parent: NodeId, value: T) -> NodeId {
+        let last_child = self.alive_node(parent).last_child;
+        let id = self.allocate_node(Node::new(parent.0, last_child, value));
+        if last_child == NONE {
+            self.nodes[parent.0 as usize].first_child = id;
+        } else {
+            self.nodes[last_child as usize].next_sibling = id;
+        }
+        self.nodes[parent.0 as usize].last_child = id;
+        NodeId(id)
+    }
+
+    /// Remove `node` together with all of its descendants and return its value
+    pub fn remove(&mut self, node: NodeId) -> T {
+        let (parent, prev_sibling, next_sibling) = {
+            let node = self.alive_node(node);
+            (node.parent, node.prev_sibling, node.next_sibling)
+        };
+
+        if prev_sibling == NONE {
+            if parent == NONE {
+                self.first_root = next_sibling;
+            } else {
+                self.nodes[parent as usize].first_child = next_sibling;
+            }
+        } else {
+            self.nodes[prev_sibling as usize].next_sibling = next_sibling;
+        }
+
+        if next_sibling == NONE {
+            if parent == NONE {
+                self.last_root = prev_sibling;
+            } else {
+                self.nodes[parent as usize].last_child = prev_sibling;
+            }
+        } else {
+            self.nodes[next_sibling as usize].prev_sibling = prev_sibling;
+        }
+
+        let descendants = self
+            .depth_first_from(node)
+            .skip(1)
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        for descendant in descendants {
+            self.nodes[descendant.0 as usize].value = None;
+            self.free_node(descendant.0);
+        }
+
+        let value = unsafe {
+            let value_slot = &mut self.nodes[node.0 as usize].value;
+            // the value should be decompacted, else internal relative pointers get messed up!
+            let value = Compact::decompact(value_slot.as_ref().unwrap());
+            // the old value was moved out, so it must not be dropped
+            ptr::write(value_slot, None);
+            value
+        };
+        self.free_node(node.0);
+        value
+    }
+
+    /// Iterator over all nodes in depth-first pre-order, together with their ids
+    pub fn depth_first(&self) -> DepthFirst<'_, T, A> {
+        DepthFirst {
+            tree: self,
+            next: self.first_root,
+            start: NONE,
+        }
+    }
+
+    /// Iterator over `node` and all of its descendants in depth-first pre-order
+    pub fn depth_first_from(&self, node: NodeId) -> DepthFirst<'_, T, A> {
+        self.alive_node(node);
+        DepthFirst {
+            tree: self,
+            next: node.0,
+            start: node.0,
+        }
+    }
+
+    /// Iterator over all nodes in breadth-first order, together with their ids
+    pub fn breadth_first(&self) -> BreadthFirst<'_, T, A> {
+        BreadthFirst {
+            tree: self,
+            queue: self.roots().map(|id| id.0).collect(),
+        }
+    }
+
+    /// Iterator over `node` and all of its descendants in breadth-first order
+    pub fn breadth_first_from(&self, node: NodeId) -> BreadthFirst<'_, T, A> {
+        self.alive_node(node);
+        BreadthFirst {
+            tree: self,
+            queue: Some(node.0).into_iter().collect(),
+        }
+    }
+
+    fn alive_node(&self, node: NodeId) -> &Node<T> {
+        let node = &self.nodes[node.0 as usize];
+        assert!(node.value.is_some(), "node was removed");
+        node
+    }
+
+    fn allocate_node(&mut self, node: Node<T>) -> u32 {
+        self.len += 1;
+        if self.first_free == NONE {
+            assert!(self.nodes.len() < NONE as usize, "too many nodes");
+            self.nodes.push(node);
+            (self.nodes.len() - 1) as u32
+        } else {
+            let id = self.first_free;
+            self.first_free = self.nodes[id as usize].next_sibling;
+            self.nodes[id as usize] = node;
+            id
+        }
+    }
+
+    fn free_node(&mut self, id: u32) {
+        self.len -= 1;
+        let first_free = self.first_free;
+        let node = &mut self.nodes[id as usize];
+        node.parent = NONE;
+        node.first_child = NONE;
+        node.last_child = NONE;
+        node.prev_sibling = NONE;
+        node.next_sibling = first_free;
+        self.first_free = id;
+    }
+}
+
+fn some_id(id: u32) -> Option<NodeId> {
+    if id == NONE {
+        None
+    } else {
+        Some(NodeId(id))
+    }
+}
+
+/// Iterator over a chain of sibling nodes
+pub struct Siblings<'a, T: 'a, A: 'a + InstanceAllocator> {
+    tree: &'a CompactTree<T, A>,
+    next: u32,
+}
+
+impl<'a, T, A: InstanceAllocator> Iterator for Siblings<'a, T, A> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let current = some_id(self.next)?;
+        self.next = self.tree.nodes[current.0 as usize].next_sibling;
+        Some(current)
+    }
+}
+
+/// Depth-first pre-order iterator over nodes, see `CompactTree::depth_first`
+pub struct DepthFirst<'a, T: 'a, A: 'a + InstanceAllocator> {
+    tree: &'a CompactTree<T, A>,
+    next: u32,
+    /// The node the traversal started at, `NONE` for the whole tree
+    start: u32,
+}
+
+impl<'a, T, A: InstanceAllocator> Iterator for DepthFirst<'a, T, A> {
+    type Item = (NodeId, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = some_id(self.next)?;
+        let nodes = &self.tree.nodes;
+        let node = &nodes[current.0 as usize];
+
+        self.next = if node.first_child != NONE {
+            node.first_child
+        } else {
+            // go up until there is a next sibling, but never above the start
+            let mut ancestor = current.0;
+            loop {
+                if ancestor == self.start {
+                    break NONE;
+                }
+                let next_sibling = nodes[ancestor as usize].next_sibling;
+                if next_sibling != NONE {
+                    break next_sibling;
+                }
+                ancestor = nodes[ancestor as usize].parent;
+                if ancestor == NONE {
+                    break NONE;
+                }
+            }
+        };
+
+        Some((current, node.value.as_ref().unwrap()))
+    }
+}
+
+/// Breadth-first iterator over nodes, see `CompactTree::breadth_first`
+pub struct BreadthFirst<'a, T: 'a, A: 'a + InstanceAllocator> {
+    tree: &'a CompactTree<T, A>,
+    queue: VecDeque<u32>,
+}
+
+impl<'a, T, A: InstanceAllocator> Iterator for BreadthFirst<'a, T, A> {
+    type Item = (NodeId, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = self.queue.pop_front()?;
+        let node = &self.tree.nodes[current as usize];
+        let mut child = node.first_child;
+        while child != NONE {
+            self.queue.push_back(child);
+            child = self.tree.nodes[child as usize].next_sibling;
+        }
+        Some((NodeId(current), node.value.as_ref().unwrap()))
+    }
+}
+
+impl<T: Compact, A: InstanceAllocator> Compact for CompactTree<T, A> {
+    fn is_still_compact(&self) -> bool {
+        self.nodes.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        self.nodes.dynamic_size_bytes()
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        (*dest).first_root = (*source).first_root;
+        (*dest).last_root = (*source).last_root;
+        (*dest).first_free = (*source).first_free;
+        (*dest).len = (*source).len;
+        Compact::compact(&mut (*source).nodes, &mut (*dest).nodes, new_dynamic_part);
+    }
+
+    fn dynamic_size_bytes_tight(&self) -> usize {
+        self.nodes.dynamic_size_bytes_tight()
+    }
+
+    unsafe fn compact_tight(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        (*dest).first_root = (*source).first_root;
+        (*dest).last_root = (*source).last_root;
+        (*dest).first_free = (*source).first_free;
+        (*dest).len = (*source).len;
+        Compact::compact_tight(&mut (*source).nodes, &mut (*dest).nodes, new_dynamic_part);
+    }
+
+    unsafe fn decompact(source: *const Self) -> CompactTree<T, A> {
+        CompactTree {
+            nodes: Compact::decompact(&(*source).nodes),
+            first_root: (*source).first_root,
+            last_root: (*source).last_root,
+            first_free: (*source).first_free,
+            len: (*source).len,
+        }
+    }
+
+    fn reserve_headroom(&mut self, headroom: Headroom) {
+        self.nodes.reserve_headroom(headroom)
+    }
+
+    fn inspect_layout(&self) -> LayoutNode {
+        LayoutNode::new(self, Storage::None).child("nodes", self.nodes.inspect_layout())
+    }
+
+    fn type_fingerprint() -> u64 {
+        structural_fingerprint::<Self>("CompactTree", &[T::type_fingerprint(), allocator_fingerprint::<A>()])
+    }
+
+    fn streams_compact() -> bool {
+        T::streams_compact()
+    }
+
+    fn uses_instance_handles() -> bool {
+        CompactVec::<Node<T>, A>::uses_instance_handles()
+    }
+
+    fn max_align() -> usize {
+        CompactVec::<Node<T>, A>::max_align()
+    }
+
+    unsafe fn compact_static_to(&self, dest: *mut Self, at: usize, dynamic_at: usize) {
+        ptr::write(&mut (*dest).first_root, self.first_root);
+        ptr::write(&mut (*dest).last_root, self.last_root);
+        ptr::write(&mut (*dest).first_free, self.first_free);
+        ptr::write(&mut (*dest).len, self.len);
+        let nodes_at = field_position(dest, &(*dest).nodes, at);
+        self.nodes
+            .compact_static_to(&mut (*dest).nodes, nodes_at, dynamic_at);
+    }
+
+    fn write_dynamic_compact(&self, dynamic_at: usize, out: &mut dyn Write) -> io::Result<()> {
+        self.nodes.write_dynamic_compact(dynamic_at, out)
+    }
+}
+
+impl<T: Compact, A: InstanceAllocator> Clone for CompactTree<T, A> {
+    fn clone(&self) -> Self {
+        CompactTree {
+            nodes: self.nodes.clone(),
+            first_root: self.first_root,
+            last_root: self.last_root,
+            first_free: self.first_free,
+            len: self.len,
+        }
+    }
+}
+
+impl<T: Compact, A: InstanceAllocator> Default for CompactTree<T, A>
+where
+    A::Handle: Default,
+{
+    fn default() -> Self {
+        CompactTree::new()
+    }
+}
+
+impl<T: Compact + ::std::fmt::Debug, A: InstanceAllocator> ::std::fmt::Debug for CompactTree<T, A> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_list()
+            .entries(self.depth_first().map(|(id, value)| (id, self.parent(id), value)))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+fn example_tree() -> (CompactTree<u32>, NodeId, NodeId) {
+    //      1        8
+    //    / | \
+    //   2  5  6
+    //  / \     \
+    // 3   4     7
+    let mut tree = CompactTree::new();
+    let one = tree.push_root(1);
+    let two = tree.push_child(one, 2);
+    tree.push_child(two, 3);
+    tree.push_child(two, 4);
+    tree.push_child(one, 5);
+    let six = tree.push_child(one, 6);
+    tree.push_child(six, 7);
+    tree.push_root(8);
+    (tree, two, six)
+}
+
+#[cfg(test)]
+fn values<'a, I: Iterator<Item = (NodeId, &'a u32)>>(iter: I) -> Vec<u32> {
+    iter.map(|(_, value)| *value).collect()
+}
+
+#[test]
+fn traversal() {
+    let (tree, two, six) = example_tree();
+    assert_eq!(8, tree.len());
+    assert_eq!(vec![1, 2, 3, 4, 5, 6, 7, 8], values(tree.depth_first()));
+    assert_eq!(vec![1, 8, 2, 5, 6, 3, 4, 7], values(tree.breadth_first()));
+    assert_eq!(vec![2, 3, 4], values(tree.depth_first_from(two)));
+    assert_eq!(vec![6, 7], values(tree.breadth_first_from(six)));
+    assert_eq!(Some(6), tree.parent(six).and_then(|one| tree.children(one).last()).map(|id| *tree.get(id).unwrap()));
+}
+
+#[test]
+fn remove_subtree() {
+    let (mut tree, two, six) = example_tree();
+    assert_eq!(2, tree.remove(two));
+    assert_eq!(5, tree.len());
+    assert!(tree.get(two).is_none());
+    assert_eq!(vec![1, 5, 6, 7, 8], values(tree.depth_first()));
+
+    let nine = tree.push_child(six, 9);
+    assert_eq!(5 + 1, tree.len());
+    assert!(nine.0 < 8, "should reuse a freed node");
+    assert_eq!(vec![1, 5, 6, 7, 9, 8], values(tree.depth_first()));
+}
+
+#[test]
+fn compact_tree() {
+    use super::compact_str::CompactString;
+    type NestedType = CompactTree<CompactString>;
+    let mut tree: NestedType = CompactTree::new();
+    let root = tree.push_root("scene".to_owned().into());
+    let camera = tree.push_child(root, "camera".to_owned().into());
+    tree.push_child(camera, "lens".to_owned().into());
+    tree.push_child(root, "light".to_owned().into());
+
+    let bytes = tree.total_size_bytes();
+    let storage = DefaultHeap::allocate(bytes);
+
+    unsafe {
+        Compact::compact_behind(&mut tree, storage as *mut NestedType);
+        ::std::mem::forget(tree);
+        assert!((*(storage as *mut NestedType)).is_still_compact());
+        let names = (*(storage as *mut NestedType))
+            .depth_first()
+            .map(|(_, name)| name.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["scene", "camera", "lens", "light"], names);
+
+        let mut decompacted = Compact::decompact(storage as *mut NestedType);
+        assert_eq!("camera", &*decompacted.remove(camera));
+        let names = decompacted
+            .breadth_first()
+            .map(|(_, name)| name.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["scene", "light"], names);
+        DefaultHeap::deallocate(storage, bytes);
+    }
+}
@@ -1,87 +1,156 @@
-use super::simple_allocator_trait::{Allocator, DefaultHeap};
+use super::allocator::InstanceAllocator;
+use super::simple_allocator_trait::DefaultHeap;
+#[cfg(test)]
+use super::simple_allocator_trait::Allocator;
 use super::pointer_to_maybe_compact::PointerToMaybeCompact;
-use super::compact::Compact;
+use super::compact::{Compact, Headroom, structural_fingerprint, allocator_fingerprint};
+use super::compact::{field_position, write_static_compact, write_zeros};
+use super::compact_index::CompactIndex;
+use super::inspect::LayoutNode;
+use super::stats;
+#[cfg(feature = "allocator-api")]
+use super::std_allocator::StdAlloc;
+#[cfg(feature = "serde-serialization")]
+use super::deserialize::{DeserializeCompact, DeserializePlan, Measure, WriteInto};
+#[cfg(feature = "serde-serialization")]
 use std::marker::PhantomData;
+use std::hash::{Hash, Hasher};
 use std::ptr;
 use std::ops::{Deref, DerefMut};
 use std::iter::FromIterator;
+use std::io::{self, Write};
 
 /// A dynamically-sized vector that can be stored in compact sequential storage and
-/// automatically spills over into free heap storage using `Allocator`.
+/// automatically spills over into free heap storage using `InstanceAllocator`.
 /// Tries to closely follow the API of `std::vec::Vec`, but is not complete.
-pub struct CompactVec<T, A: Allocator = DefaultHeap> {
+///
+/// The width of the stored length and capacity is chosen by `I`,
+/// see `CompactVec16` and `CompactVec64` for narrower and wider variants.
+pub struct CompactVec<T, A: InstanceAllocator = DefaultHeap, I: CompactIndex = u32> {
     /// Points to either compact or free storage
     ptr: PointerToMaybeCompact<T>,
-    len: u32,
+    len: I,
     /// Maximum capacity before needing to spill onto the heap
-    cap: u32,
-    _alloc: PhantomData<*const A>,
+    cap: I,
+    /// Handle of the allocator for free heap storage, zero-sized for static allocators
+    alloc: A::Handle,
 }
 
-impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
+/// A `CompactVec` with 16-bit length and capacity.
+///
+/// The static part is padded to the alignment of its pointer, so on 64-bit targets
+/// it is only smaller than that of a `CompactVec` if an allocator handle of at most
+/// 4 bytes fits into the padding. Otherwise, it just limits the length.
+pub type CompactVec16<T, A = DefaultHeap> = CompactVec<T, A, u16>;
+
+/// A `CompactVec` with 64-bit length and capacity, for huge vectors
+pub type CompactVec64<T, A = DefaultHeap> = CompactVec<T, A, u64>;
+
+impl<T: Compact + Clone, A: InstanceAllocator, I: CompactIndex> CompactVec<T, A, I> {
     /// Get the number of elements in the vector
     pub fn len(&self) -> usize {
-        self.len as usize
+        self.len.to_usize()
     }
 
     /// Is the vector empty?
     pub fn is_empty(&self) -> bool {
-        self.len == 0
+        self.len() == 0
     }
 
-    /// Create a new, empty vector
-    pub fn new() -> CompactVec<T, A> {
+    /// Create a new, empty vector that allocates using `alloc`
+    pub fn new_in(alloc: A::Handle) -> CompactVec<T, A, I> {
         CompactVec {
             ptr: PointerToMaybeCompact::default(),
-            len: 0,
-            cap: 0,
-            _alloc: PhantomData,
+            len: I::from_usize(0),
+            cap: I::from_usize(0),
+            alloc,
         }
     }
 
-    /// Create a new, empty vector with a given capacity
-    pub fn with_capacity(cap: usize) -> CompactVec<T, A> {
+    /// Create a new, empty vector with a given capacity, allocated using `alloc`
+    pub fn with_capacity_in(cap: usize, alloc: A::Handle) -> CompactVec<T, A, I> {
         let mut vec = CompactVec {
             ptr: PointerToMaybeCompact::default(),
-            len: 0,
-            cap: cap as u32,
-            _alloc: PhantomData,
+            len: I::from_usize(0),
+            cap: I::from_usize(cap),
+            alloc,
         };
 
-        vec.ptr.set_to_free(A::allocate::<T>(cap));
+        vec.ptr.set_to_free(Self::allocate_free(&vec.alloc, cap));
         vec
     }
 
     /// Create a new vector from raw parts
-    /// Assumes that `ptr` has been allocated by the same Allocator that is `A`
-    pub unsafe fn from_raw_parts(ptr: *mut T, len: usize, cap: usize) -> CompactVec<T, A> {
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by `alloc` with room for `cap` items,
+    /// the first `len` of which are initialized.
+    pub unsafe fn from_raw_parts_in(
+        ptr: *mut T,
+        len: usize,
+        cap: usize,
+        alloc: A::Handle,
+    ) -> CompactVec<T, A, I> {
+        stats::record::<Self>(|stats| stats.heap_bytes += cap * ::std::mem::size_of::<T>());
         CompactVec {
             ptr: PointerToMaybeCompact::new_free(ptr),
-            len: len as u32,
-            cap: cap as u32,
-            _alloc: PhantomData,
+            len: I::from_usize(len),
+            cap: I::from_usize(cap),
+            alloc,
         }
     }
 
+    /// The handle of the allocator used for free heap storage
+    pub fn allocator(&self) -> &A::Handle {
+        &self.alloc
+    }
+
     /// current capacity
     pub fn capacity(&self) -> usize {
-        self.cap as usize
+        self.cap.to_usize()
     }
 
-    /// Double the capacity of the vector by spilling onto the heap
+    /// Double the capacity of the vector by spilling onto the heap,
+    /// panics if the capacity is already the maximum representable by `I`
     fn double_buf(&mut self) {
-        let new_cap = if self.cap == 0 { 1 } else { self.cap * 2 };
-        let new_ptr = A::allocate::<T>(new_cap as usize);
+        let cap = self.capacity();
+        assert!(cap < I::MAX, "capacity overflow");
+        let new_cap = if cap == 0 {
+            1
+        } else {
+            ::std::cmp::min(cap.saturating_mul(2), I::MAX)
+        };
+        self.reallocate(new_cap);
+    }
+
+    /// Reserve capacity for at least `additional` more elements,
+    /// spilling onto the heap if the current storage is insufficient
+    pub fn reserve(&mut self, additional: usize) {
+        let needed = self.len().checked_add(additional).expect("capacity overflow");
+        if needed > self.capacity() {
+            assert!(needed <= I::MAX, "capacity overflow");
+            self.reallocate(needed);
+        }
+    }
+
+    /// Move the elements into new free heap storage of capacity `new_cap`
+    fn reallocate(&mut self, new_cap: usize) {
+        let cap = self.capacity();
+        if self.in_compact_storage() {
+            stats::record::<Self>(|stats| stats.spills += 1);
+        }
+        let new_ptr = Self::allocate_free(&self.alloc, new_cap);
 
         // items should be decompacted, else internal relative pointers get messed up!
         for (i, item) in self.iter().enumerate() {
-            unsafe { ptr::write(new_ptr.offset(i as isize), Compact::decompact(item)) };
+            unsafe { ptr::write(new_ptr.add(i), Compact::decompact(item)) };
         }
 
         // items shouldn't be dropped here, they live on in the new backing store!
-        self.ptr.deallocate_if_free::<A>(self.cap as usize);
+        Self::release(&self.ptr, &self.alloc, cap);
         self.ptr.set_to_free(new_ptr);
-        self.cap = new_cap;
+        self.cap = I::from_usize(new_cap);
     }
 
     /// Push an item onto the vector, spills onto the heap
@@ -92,23 +161,16 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
         }
 
         unsafe {
-            let end = self.as_mut_ptr().offset(self.len as isize);
+            let len = self.len();
+            let end = self.as_mut_ptr().add(len);
             ptr::write(end, value);
-            self.len += 1;
+            self.len = I::from_usize(len + 1);
         }
     }
 
     /// push at position
     pub fn push_at(&mut self, _: usize, value: T) {
-        if self.len == self.cap {
-            self.double_buf();
-        }
-
-        unsafe {
-            let end = self.as_mut_ptr().offset(self.len as isize);
-            ptr::write(end, value);
-            self.len += 1;
-        }
+        self.push(value)
     }
 
     /// Extend from a copyable slice
@@ -116,74 +178,86 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
     where
         T: Copy,
     {
-        while self.len + other.len() as u32 > self.cap {
+        let old_len = self.len();
+        while old_len + other.len() > self.capacity() {
             self.double_buf();
         }
 
-        let old_len = self.len as usize;
-        self.len += other.len() as u32;
+        self.len = I::from_usize(old_len + other.len());
         self[old_len..].copy_from_slice(other);
     }
 
     /// Pop and return the last element, if the vector wasn't empty
     pub fn pop(&mut self) -> Option<T> {
-        if self.len == 0 {
+        if self.is_empty() {
             None
         } else {
             unsafe {
-                self.len -= 1;
-                Some(Compact::decompact(self.get_unchecked(self.len as usize)))
+                let new_len = self.len() - 1;
+                self.len = I::from_usize(new_len);
+                Some(Compact::decompact(self.as_ptr().add(new_len)))
             }
         }
     }
 
     /// Insert a value at `index`, copying the elements after `index` upwards
+    ///
+    /// If decompacting one of the shifted elements panics,
+    /// the elements after `index` are leaked, but never dropped twice.
     pub fn insert(&mut self, index: usize, value: T) {
+        let len = self.len();
+        assert!(index <= len);
+
         if self.len == self.cap {
             self.double_buf();
         }
 
         unsafe {
-            // infallible
+            // while shifting, elements exist twice - hide them from `Drop`
+            self.len = I::from_usize(index);
             {
-                let ptr = self.as_mut_ptr().offset(index as isize);
+                let ptr = self.as_mut_ptr().add(index);
                 // elements should be decompacted, else internal relative pointers get messed up!
-                for i in (0..self.len as usize - index).rev() {
+                for i in (0..len - index).rev() {
                     ptr::write(
-                        ptr.offset((i + 1) as isize),
-                        Compact::decompact(&self[index + i]),
+                        ptr.add(i + 1),
+                        Compact::decompact(ptr.add(i)),
                     );
                 }
                 ptr::write(ptr, value);
             }
-            self.len += 1;
+            self.len = I::from_usize(len + 1);
         }
     }
 
     /// Remove the element at `index`, copying the elements after `index` downwards
+    ///
+    /// If decompacting one of the shifted elements panics,
+    /// the elements after `index` are leaked, but never dropped twice.
     pub fn remove(&mut self, index: usize) -> T {
-        let len = self.len;
-        assert!(index < len as usize);
+        let len = self.len();
+        assert!(index < len);
         unsafe {
-            // infallible
+            // while shifting, elements exist twice - hide them from `Drop`
+            self.len = I::from_usize(index);
             let ret;
             {
                 // the place we are taking from.
-                let ptr = self.as_mut_ptr().offset(index as isize);
+                let ptr = self.as_mut_ptr().add(index);
                 // copy it out, unsafely having a copy of the value on
                 // the stack and in the vector at the same time.
                 ret = Compact::decompact(ptr);
 
                 // Shift everything down to fill in that spot.
                 // elements should be decompacted, else internal relative pointers get messed up!
-                for i in 0..(len as usize) - index - 1 {
+                for i in 0..len - index - 1 {
                     ptr::write(
-                        ptr.offset(i as isize),
-                        Compact::decompact(&self[index + i + 1]),
+                        ptr.add(i),
+                        Compact::decompact(ptr.add(i + 1)),
                     )
                 }
             }
-            self.len -= 1;
+            self.len = I::from_usize(len - 1);
             ret
         }
     }
@@ -194,49 +268,68 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
     ///
     /// This does not preserve ordering, but is O(1).
     pub fn swap_remove(&mut self, index: usize) -> T {
+        let len = self.len();
+        assert!(index < len);
         unsafe {
-            let ret = Compact::decompact(&self[index]);
-            let len = self.len;
-
-            ptr::write(
-                self.as_mut_ptr().offset(index as isize),
-                Compact::decompact(&self[len as usize - 1]),
-            );
+            // while moving the last element, elements exist twice - hide them from `Drop`
+            self.len = I::from_usize(index);
+            let ptr = self.as_mut_ptr();
+            let ret = Compact::decompact(ptr.add(index));
+
+            if index != len - 1 {
+                ptr::write(
+                    ptr.add(index),
+                    Compact::decompact(ptr.add(len - 1)),
+                );
+            }
 
-            self.len -= 1;
+            self.len = I::from_usize(len - 1);
             ret
         }
     }
 
     /// Take a function which returns whether an element should be kept,
     /// and mutably removes all elements from the vector which are not kept
+    ///
+    /// If `keep` panics, the elements that weren't visited yet are leaked,
+    /// but never dropped twice.
     pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut keep: F) {
-        let mut del = 0;
-        let len = self.len as usize;
-        {
-            let v = &mut **self;
+        let len = self.len();
+        unsafe {
+            let ptr = self.as_mut_ptr();
+            // hide all elements from `Drop` while they are moved around,
+            // the guard makes the kept ones visible again, even when unwinding
+            self.len = I::from_usize(0);
+            let mut guard = SetLenOnDrop {
+                len: &mut self.len,
+                local_len: 0,
+            };
 
             for i in 0..len {
-                if !keep(&v[i]) {
-                    del += 1;
+                let item = ptr.add(i);
+                if keep(&*item) {
+                    if guard.local_len != i {
+                        // elements should be decompacted, else internal relative pointers get messed up!
+                        ptr::write(
+                            ptr.add(guard.local_len),
+                            Compact::decompact(item),
+                        );
+                    }
+                    guard.local_len += 1;
                 } else {
-                    v.swap(i - del, i);
+                    ptr::drop_in_place(item);
                 }
             }
         }
-
-        if del > 0 {
-            self.truncate(len - del);
-        }
     }
 
     /// Truncate the vector to the given length
     pub fn truncate(&mut self, desired_len: usize) {
         unsafe {
-            while desired_len < self.len as usize {
-                self.len -= 1;
-                let len = self.len;
-                ptr::drop_in_place(self.get_unchecked_mut(len as usize));
+            while desired_len < self.len() {
+                let len = self.len() - 1;
+                self.len = I::from_usize(len);
+                ptr::drop_in_place(self.as_mut_ptr().add(len));
             }
         }
     }
@@ -250,7 +343,7 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
     pub fn drain(&mut self) -> IntoIter<T, A> {
         unsafe {
             let decompacted = Compact::decompact(self);
-            ::std::ptr::write(self, CompactVec::new());
+            ::std::ptr::write(self, CompactVec::new_in(self.alloc.clone()));
             decompacted.into_iter()
         }
     }
@@ -261,7 +354,43 @@ impl<T: Compact + Clone, A: Allocator> CompactVec<T, A> {
     }
 }
 
-impl<T: Compact + Clone, A: Allocator> From<Vec<T>> for CompactVec<T, A> {
+impl<T: Compact + Clone, A: InstanceAllocator, I: CompactIndex> CompactVec<T, A, I>
+where
+    A::Handle: Default,
+{
+    /// Create a new, empty vector
+    pub fn new() -> CompactVec<T, A, I> {
+        Self::new_in(A::Handle::default())
+    }
+
+    /// Create a new, empty vector with a given capacity
+    pub fn with_capacity(cap: usize) -> CompactVec<T, A, I> {
+        Self::with_capacity_in(cap, A::Handle::default())
+    }
+
+    /// Create a new vector from raw parts
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by the same Allocator that is `A` with room for `cap` items,
+    /// the first `len` of which are initialized.
+    pub unsafe fn from_raw_parts(ptr: *mut T, len: usize, cap: usize) -> CompactVec<T, A, I> {
+        Self::from_raw_parts_in(ptr, len, cap, A::Handle::default())
+    }
+}
+
+impl<T: Compact + Clone, A: InstanceAllocator, I: CompactIndex> From<Vec<T>> for CompactVec<T, A, I>
+where
+    A::Handle: Default,
+{
+    /// Create a `CompactVec` from a normal `Vec`,
+    /// moving its items into free heap storage of `A`
+    default fn from(vec: Vec<T>) -> Self {
+        vec.into_iter().collect()
+    }
+}
+
+impl<T: Compact + Clone, I: CompactIndex> From<Vec<T>> for CompactVec<T, DefaultHeap, I> {
     /// Create a `CompactVec` from a normal `Vec`,
     /// directly using the backing storage as free heap storage
     fn from(mut vec: Vec<T>) -> Self {
@@ -271,50 +400,191 @@ impl<T: Compact + Clone, A: Allocator> From<Vec<T>> for CompactVec<T, A> {
     }
 }
 
-impl<T, A: Allocator> Drop for CompactVec<T, A> {
+#[cfg(feature = "allocator-api")]
+impl<T: Compact + Clone, A: ::std::alloc::Allocator + Clone, I: CompactIndex> CompactVec<T, StdAlloc<A>, I> {
+    /// Create a `CompactVec` from a `Vec` with a std allocator,
+    /// directly using its backing storage as free heap storage
+    pub fn from_std_vec(vec: Vec<T, A>) -> Self {
+        let mut vec = ::std::mem::ManuallyDrop::new(vec);
+        let alloc = unsafe { ptr::read(vec.allocator()) };
+        if vec.capacity() == 0 {
+            CompactVec::new_in(alloc)
+        } else {
+            // zero-sized elements have a capacity of `usize::MAX`, but need no storage
+            let cap = if ::std::mem::size_of::<T>() == 0 { vec.len() } else { vec.capacity() };
+            unsafe { Self::from_raw_parts_in(vec.as_mut_ptr(), vec.len(), cap, alloc) }
+        }
+    }
+
+    /// Convert into a `Vec` with the same std allocator,
+    /// without copying if the vector uses free heap storage
+    pub fn into_std_vec(mut self) -> Vec<T, A> {
+        if self.ptr.is_compact() {
+            let mut vec = Vec::with_capacity_in(self.len(), self.alloc.clone());
+            // items should be decompacted, else internal relative pointers get messed up!
+            vec.extend(self.iter().map(|item| unsafe { Compact::decompact(item) }));
+            // items shouldn't be dropped here, they live on in `vec`
+            self.len = I::from_usize(0);
+            vec
+        } else {
+            Self::release_stats(&self.ptr, self.capacity());
+            unsafe {
+                let vec = Vec::from_raw_parts_in(
+                    self.ptr.mut_ptr(),
+                    self.len(),
+                    self.capacity(),
+                    ptr::read(&self.alloc),
+                );
+                ::std::mem::forget(self);
+                vec
+            }
+        }
+    }
+}
+
+#[cfg(feature = "allocator-api")]
+impl<T: Compact + Clone, A: ::std::alloc::Allocator + Clone, I: CompactIndex> From<CompactVec<T, StdAlloc<A>, I>>
+    for Vec<T, A>
+{
+    fn from(vec: CompactVec<T, StdAlloc<A>, I>) -> Self {
+        vec.into_std_vec()
+    }
+}
+
+impl<T, A: InstanceAllocator, I: CompactIndex> CompactVec<T, A, I> {
+    /// Is the vector using actual compact storage, as opposed to no storage at all?
+    pub(crate) fn in_compact_storage(&self) -> bool {
+        self.ptr.is_compact() && !unsafe { self.ptr.ptr() }.is_null()
+    }
+
+    /// Allocate free heap storage for `cap` elements
+    fn allocate_free(alloc: &A::Handle, cap: usize) -> *mut T {
+        stats::record::<Self>(|stats| stats.heap_bytes += cap * ::std::mem::size_of::<T>());
+        A::allocate_in::<T>(alloc, cap)
+    }
+
+    /// Stop using the storage of `ptr` with capacity `cap`, deallocating it if it is free
+    fn release(ptr: &PointerToMaybeCompact<T>, alloc: &A::Handle, cap: usize) {
+        Self::release_stats(ptr, cap);
+        ptr.deallocate_if_free::<A>(alloc, cap);
+    }
+
+    fn release_stats(ptr: &PointerToMaybeCompact<T>, cap: usize) {
+        let bytes = cap * ::std::mem::size_of::<T>();
+        if ptr.is_compact() {
+            stats::record::<Self>(|stats| stats.compact_bytes = stats.compact_bytes.saturating_sub(bytes));
+        } else {
+            stats::record::<Self>(|stats| stats.heap_bytes = stats.heap_bytes.saturating_sub(bytes));
+        }
+    }
+
+    /// Decompacting copies the elements out of compact storage, which is abandoned afterwards
+    fn record_decompaction(cap: usize) {
+        stats::record::<Self>(|stats| {
+            stats.decompactions += 1;
+            stats.compact_bytes = stats.compact_bytes.saturating_sub(cap * ::std::mem::size_of::<T>());
+        });
+    }
+}
+
+/// Writes back the length of a vector when dropped, so that a panic
+/// while moving elements around leaks elements instead of dropping them twice
+struct SetLenOnDrop<'a, I: 'a + CompactIndex> {
+    len: &'a mut I,
+    local_len: usize,
+}
+
+impl<'a, I: CompactIndex> Drop for SetLenOnDrop<'a, I> {
+    fn drop(&mut self) {
+        *self.len = I::from_usize(self.local_len);
+    }
+}
+
+impl<T, A: InstanceAllocator, I: CompactIndex> Drop for CompactVec<T, A, I> {
     /// Drop elements and deallocate free heap storage, if any is allocated
     fn drop(&mut self) {
         unsafe { ptr::drop_in_place(&mut self[..]) };
-        self.ptr.deallocate_if_free::<A>(self.cap as usize);
+        Self::release(&self.ptr, &self.alloc, self.cap.to_usize());
     }
 }
 
-impl<T, A: Allocator> Deref for CompactVec<T, A> {
+impl<T, A: InstanceAllocator, I: CompactIndex> Deref for CompactVec<T, A, I> {
     type Target = [T];
 
     fn deref(&self) -> &[T] {
         if unsafe { self.ptr.ptr().is_null() } {
-            unsafe { ::std::slice::from_raw_parts(0x1 as *const T, 0) }
+            unsafe { ::std::slice::from_raw_parts(ptr::NonNull::dangling().as_ptr(), 0) }
         } else {
-            unsafe { ::std::slice::from_raw_parts(self.ptr.ptr(), self.len as usize) }
+            unsafe { ::std::slice::from_raw_parts(self.ptr.ptr(), self.len.to_usize()) }
         }
     }
 }
 
-impl<T, A: Allocator> DerefMut for CompactVec<T, A> {
+impl<T, A: InstanceAllocator, I: CompactIndex> DerefMut for CompactVec<T, A, I> {
     fn deref_mut(&mut self) -> &mut [T] {
         if unsafe { self.ptr.ptr().is_null() } {
-            unsafe { ::std::slice::from_raw_parts_mut(0x1 as *mut T, 0) }
+            unsafe { ::std::slice::from_raw_parts_mut(ptr::NonNull::dangling().as_ptr(), 0) }
         } else {
-            unsafe { ::std::slice::from_raw_parts_mut(self.ptr.mut_ptr(), self.len as usize) }
+            unsafe { ::std::slice::from_raw_parts_mut(self.ptr.mut_ptr(), self.len.to_usize()) }
         }
     }
 }
 
-pub struct IntoIter<T, A: Allocator> {
+impl<T, U, A, B, I, J> PartialEq<CompactVec<U, B, J>> for CompactVec<T, A, I>
+where
+    T: PartialEq<U>,
+    A: InstanceAllocator,
+    B: InstanceAllocator,
+    I: CompactIndex,
+    J: CompactIndex,
+{
+    fn eq(&self, other: &CompactVec<U, B, J>) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl<T: Eq, A: InstanceAllocator, I: CompactIndex> Eq for CompactVec<T, A, I> {}
+
+impl<T, A, B, I, J> PartialOrd<CompactVec<T, B, J>> for CompactVec<T, A, I>
+where
+    T: PartialOrd,
+    A: InstanceAllocator,
+    B: InstanceAllocator,
+    I: CompactIndex,
+    J: CompactIndex,
+{
+    fn partial_cmp(&self, other: &CompactVec<T, B, J>) -> Option<::std::cmp::Ordering> {
+        self[..].partial_cmp(&other[..])
+    }
+}
+
+impl<T: Ord, A: InstanceAllocator, I: CompactIndex> Ord for CompactVec<T, A, I> {
+    fn cmp(&self, other: &Self) -> ::std::cmp::Ordering {
+        self[..].cmp(&other[..])
+    }
+}
+
+impl<T: Hash, A: InstanceAllocator, I: CompactIndex> Hash for CompactVec<T, A, I> {
+    /// Hashes like a slice, independent of allocator and index type
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self[..].hash(state)
+    }
+}
+
+pub struct IntoIter<T, A: InstanceAllocator> {
     ptr: PointerToMaybeCompact<T>,
     len: usize,
     cap: usize,
     index: usize,
-    _alloc: PhantomData<*const A>,
+    alloc: A::Handle,
 }
 
-impl<T, A: Allocator> Iterator for IntoIter<T, A> {
+impl<T, A: InstanceAllocator> Iterator for IntoIter<T, A> {
     type Item = T;
 
     fn next(&mut self) -> Option<T> {
         if self.index < self.len {
-            let item = unsafe { ptr::read(self.ptr.ptr().offset(self.index as isize)) };
+            let item = unsafe { ptr::read(self.ptr.ptr().add(self.index)) };
             self.index += 1;
             Some(item)
         } else {
@@ -323,37 +593,41 @@ impl<T, A: Allocator> Iterator for IntoIter<T, A> {
     }
 }
 
-impl<T, A: Allocator> Drop for IntoIter<T, A> {
+impl<T, A: InstanceAllocator> Drop for IntoIter<T, A> {
     fn drop(&mut self) {
         // drop all remaining elements
-        unsafe {
-            ptr::drop_in_place(&mut ::std::slice::from_raw_parts(
-                self.ptr.ptr().offset(self.index as isize),
-                self.len,
-            ))
-        };
-        self.ptr.deallocate_if_free::<A>(self.cap as usize);
+        if self.index < self.len {
+            unsafe {
+                ptr::drop_in_place(::std::ptr::slice_from_raw_parts_mut(
+                    self.ptr.mut_ptr().add(self.index),
+                    self.len - self.index,
+                ))
+            };
+        }
+        self.ptr.deallocate_if_free::<A>(&self.alloc, self.cap as usize);
     }
 }
 
-impl<T, A: Allocator> IntoIterator for CompactVec<T, A> {
+impl<T, A: InstanceAllocator, I: CompactIndex> IntoIterator for CompactVec<T, A, I> {
     type Item = T;
     type IntoIter = IntoIter<T, A>;
 
     fn into_iter(self) -> Self::IntoIter {
+        // the iterator takes over the storage
+        Self::release_stats(&self.ptr, self.cap.to_usize());
         let iter = IntoIter {
             ptr: unsafe { ptr::read(&self.ptr) },
-            len: self.len as usize,
-            cap: self.cap as usize,
+            len: self.len.to_usize(),
+            cap: self.cap.to_usize(),
             index: 0,
-            _alloc: PhantomData,
+            alloc: unsafe { ptr::read(&self.alloc) },
         };
         ::std::mem::forget(self);
         iter
     }
 }
 
-impl<'a, T, A: Allocator> IntoIterator for &'a CompactVec<T, A> {
+impl<'a, T, A: InstanceAllocator, I: CompactIndex> IntoIterator for &'a CompactVec<T, A, I> {
     type Item = &'a T;
     type IntoIter = ::std::slice::Iter<'a, T>;
 
@@ -362,7 +636,7 @@ impl<'a, T, A: Allocator> IntoIterator for &'a CompactVec<T, A> {
     }
 }
 
-impl<'a, T, A: Allocator> IntoIterator for &'a mut CompactVec<T, A> {
+impl<'a, T, A: InstanceAllocator, I: CompactIndex> IntoIterator for &'a mut CompactVec<T, A, I> {
     type Item = &'a mut T;
     type IntoIter = ::std::slice::IterMut<'a, T>;
 
@@ -371,13 +645,46 @@ impl<'a, T, A: Allocator> IntoIterator for &'a mut CompactVec<T, A> {
     }
 }
 
-impl<T: Compact + Clone, A: Allocator> Compact for CompactVec<T, A> {
+impl<T: Compact + Clone, A: InstanceAllocator, I: CompactIndex> CompactVec<T, A, I> {
+    /// `Compact::compact` or, if `tight`, `Compact::compact_tight` for non-`Copy` items
+    unsafe fn compact_items(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8, tight: bool) {
+        let cap = if tight { (*source).len() } else { (*source).capacity() };
+        (*dest).len = (*source).len;
+        (*dest).cap = I::from_usize(cap);
+        // the handle moves along with the contents
+        ptr::write(&mut (*dest).alloc, ptr::read(&(*source).alloc));
+        (*dest).ptr.set_to_compact(new_dynamic_part as *mut T);
+        stats::record::<Self>(|stats| {
+            stats.recompactions += 1;
+            stats.compact_bytes += cap * ::std::mem::size_of::<T>();
+        });
+
+        let mut offset = cap * ::std::mem::size_of::<T>();
+
+        for (i, item) in (*source).iter_mut().enumerate() {
+            let item_dynamic_part = new_dynamic_part.add(offset);
+            if tight {
+                offset += item.dynamic_size_bytes_tight();
+                Compact::compact_tight(item, (*dest).ptr.mut_ptr().add(i), item_dynamic_part);
+            } else {
+                offset += item.dynamic_size_bytes();
+                Compact::compact(item, (*dest).ptr.mut_ptr().add(i), item_dynamic_part);
+            }
+        }
+
+        // we want to free any allocated space,
+        // but not semantically drop our contents (they just moved)
+        Self::release(&(*source).ptr, &(*dest).alloc, (*source).capacity());
+    }
+}
+
+impl<T: Compact + Clone, A: InstanceAllocator, I: CompactIndex> Compact for CompactVec<T, A, I> {
     default fn is_still_compact(&self) -> bool {
         self.ptr.is_compact() && self.iter().all(|elem| elem.is_still_compact())
     }
 
     default fn dynamic_size_bytes(&self) -> usize {
-        self.cap as usize * ::std::mem::size_of::<T>()
+        self.capacity() * ::std::mem::size_of::<T>()
             + self
                 .iter()
                 .map(|elem| elem.dynamic_size_bytes())
@@ -385,58 +692,119 @@ impl<T: Compact + Clone, A: Allocator> Compact for CompactVec<T, A> {
     }
 
     default unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
-        (*dest).len = (*source).len;
-        (*dest).cap = (*source).cap;
-        (*dest).ptr.set_to_compact(new_dynamic_part as *mut T);
-
-        let mut offset = (*source).cap as usize * ::std::mem::size_of::<T>();
+        Self::compact_items(source, dest, new_dynamic_part, false)
+    }
 
-        for (i, item) in (*source).iter_mut().enumerate() {
-            let size_of_this_item = item.dynamic_size_bytes();
-            Compact::compact(
-                item,
-                &mut (*dest)[i],
-                new_dynamic_part.offset(offset as isize),
-            );
-            offset += size_of_this_item;
-        }
+    default fn dynamic_size_bytes_tight(&self) -> usize {
+        self.len() * ::std::mem::size_of::<T>()
+            + self
+                .iter()
+                .map(|elem| elem.dynamic_size_bytes_tight())
+                .sum::<usize>()
+    }
 
-        // we want to free any allocated space,
-        // but not semantically drop our contents (they just moved)
-        (*source).ptr.deallocate_if_free::<A>((*source).cap as usize);
+    default unsafe fn compact_tight(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        Self::compact_items(source, dest, new_dynamic_part, true)
     }
 
     default unsafe fn decompact(source: *const Self) -> Self {
         if (*source).ptr.is_compact() {
-            (*source)
-                .iter()
-                .map(|item| Compact::decompact(item))
-                .collect()
+            if (*source).in_compact_storage() {
+                Self::record_decompaction((*source).capacity());
+            }
+            // like the items, the handle is moved out of the compact source
+            let alloc = ptr::read(&(*source).alloc);
+            let mut vec = CompactVec::with_capacity_in((*source).len(), alloc);
+            vec.extend((*source).iter().map(|item| Compact::decompact(item)));
+            vec
         } else {
             CompactVec {
                 ptr: ptr::read(&(*source).ptr as *const PointerToMaybeCompact<T>),
                 len: (*source).len,
                 cap: (*source).cap,
-                _alloc: (*source)._alloc,
+                alloc: ptr::read(&(*source).alloc),
             }
             // caller has to make sure that self will not be dropped!
         }
     }
-}
 
-impl<T: Copy, A: Allocator> Compact for CompactVec<T, A> {
-    fn is_still_compact(&self) -> bool {
-        self.ptr.is_compact()
+    default fn type_fingerprint() -> u64 {
+        structural_fingerprint::<Self>(
+            "CompactVec",
+            &[T::type_fingerprint(), allocator_fingerprint::<A>(), I::type_fingerprint()],
+        )
     }
 
-    fn dynamic_size_bytes(&self) -> usize {
-        self.cap as usize * ::std::mem::size_of::<T>()
+    default fn streams_compact() -> bool {
+        T::streams_compact()
     }
 
-    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+    default fn uses_instance_handles() -> bool {
+        ::std::mem::size_of::<A::Handle>() != 0 || T::uses_instance_handles()
+    }
+
+    default fn max_align() -> usize {
+        ::std::cmp::max(::std::mem::align_of::<Self>(), T::max_align())
+    }
+
+    default unsafe fn compact_static_to(&self, dest: *mut Self, at: usize, dynamic_at: usize) {
+        ptr::write(&mut (*dest).len, self.len);
+        ptr::write(&mut (*dest).cap, self.cap);
+        // only zero-sized handles leave the process, so the handle isn't cloned into the image
+        ptr::copy_nonoverlapping(&self.alloc, &mut (*dest).alloc, 1);
+        let ptr_at = field_position(dest, &(*dest).ptr, at);
+        (*dest).ptr.set_to_compact_offset(dynamic_at as isize - ptr_at as isize);
+    }
+
+    default fn inspect_layout(&self) -> LayoutNode {
+        let unused = (self.capacity() - self.len()) * ::std::mem::size_of::<T>();
+        let mut node = LayoutNode::new(self, self.ptr.storage()).wasting(unused);
+        for (i, item) in self.iter().enumerate() {
+            node = node.child_unless_trivial(format!("[{}]", i), item.inspect_layout());
+        }
+        node
+    }
+
+    default fn reserve_headroom(&mut self, headroom: Headroom) {
+        for item in self.iter_mut() {
+            item.reserve_headroom(headroom);
+        }
+        let spare = headroom.spare_for(self.len());
+        self.reserve(spare);
+    }
+
+    default fn write_dynamic_compact(&self, dynamic_at: usize, out: &mut dyn Write) -> io::Result<()> {
+        let size_of_item = ::std::mem::size_of::<T>();
+        let items_dynamic_at = dynamic_at + self.capacity() * size_of_item;
+
+        // same layout as in `compact`: all items, unused capacity, then the items' dynamic parts
+        let mut item_dynamic_at = items_dynamic_at;
+        for (i, item) in self.iter().enumerate() {
+            write_static_compact(item, dynamic_at + i * size_of_item, item_dynamic_at, out)?;
+            item_dynamic_at += item.dynamic_size_bytes();
+        }
+        write_zeros((self.capacity() - self.len()) * size_of_item, out)?;
+
+        let mut item_dynamic_at = items_dynamic_at;
+        for item in self.iter() {
+            item.write_dynamic_compact(item_dynamic_at, out)?;
+            item_dynamic_at += item.dynamic_size_bytes();
+        }
+        Ok(())
+    }
+}
+
+impl<T: Copy, A: InstanceAllocator, I: CompactIndex> CompactVec<T, A, I> {
+    /// `Compact::compact` for `Copy` items, with a compact capacity of `cap`
+    unsafe fn compact_copies(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8, cap: usize) {
         (*dest).len = (*source).len;
-        (*dest).cap = (*source).cap;
+        (*dest).cap = I::from_usize(cap);
+        ptr::write(&mut (*dest).alloc, ptr::read(&(*source).alloc));
         (*dest).ptr.set_to_compact(new_dynamic_part as *mut T);
+        stats::record::<Self>(|stats| {
+            stats.recompactions += 1;
+            stats.compact_bytes += cap * ::std::mem::size_of::<T>();
+        });
 
         ptr::copy_nonoverlapping(
             (*source).ptr.ptr(),
@@ -446,29 +814,70 @@ impl<T: Copy, A: Allocator> Compact for CompactVec<T, A> {
 
         // we want to free any allocated space,
         // but not semantically drop our contents (they just moved)
-        (*source).ptr.deallocate_if_free::<A>((*source).cap as usize);
+        Self::release(&(*source).ptr, &(*dest).alloc, (*source).capacity());
     }
 }
 
-impl<T: Compact + Clone, A: Allocator> Clone for CompactVec<T, A> {
-    default fn clone(&self) -> CompactVec<T, A> {
-        self.iter().cloned().collect::<Vec<_>>().into()
+impl<T: Copy, A: InstanceAllocator, I: CompactIndex> Compact for CompactVec<T, A, I> {
+    fn is_still_compact(&self) -> bool {
+        self.ptr.is_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        self.capacity() * ::std::mem::size_of::<T>()
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        Self::compact_copies(source, dest, new_dynamic_part, (*source).capacity())
+    }
+
+    fn dynamic_size_bytes_tight(&self) -> usize {
+        self.len() * ::std::mem::size_of::<T>()
+    }
+
+    fn inspect_layout(&self) -> LayoutNode {
+        let unused = (self.capacity() - self.len()) * ::std::mem::size_of::<T>();
+        LayoutNode::new(self, self.ptr.storage()).wasting(unused)
+    }
+
+    unsafe fn compact_tight(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        Self::compact_copies(source, dest, new_dynamic_part, (*source).len())
+    }
+
+    fn write_dynamic_compact(&self, _dynamic_at: usize, out: &mut dyn Write) -> io::Result<()> {
+        let size_of_item = ::std::mem::size_of::<T>();
+        let bytes = unsafe {
+            ::std::slice::from_raw_parts(self.as_ptr() as *const u8, self.len() * size_of_item)
+        };
+        out.write_all(bytes)?;
+        write_zeros((self.capacity() - self.len()) * size_of_item, out)
+    }
+}
+
+impl<T: Compact + Clone, A: InstanceAllocator, I: CompactIndex> Clone for CompactVec<T, A, I> {
+    default fn clone(&self) -> CompactVec<T, A, I> {
+        let mut new_vec = Self::with_capacity_in(self.len(), self.alloc.clone());
+        new_vec.extend(self.iter().cloned());
+        new_vec
     }
 }
 
-impl<T: Copy, A: Allocator> Clone for CompactVec<T, A> {
-    fn clone(&self) -> CompactVec<T, A> {
-        let mut new_vec = Self::with_capacity(self.cap as usize);
+impl<T: Copy, A: InstanceAllocator, I: CompactIndex> Clone for CompactVec<T, A, I> {
+    fn clone(&self) -> CompactVec<T, A, I> {
+        let mut new_vec = Self::with_capacity_in(self.capacity(), self.alloc.clone());
         unsafe {
-            ptr::copy_nonoverlapping(self.ptr.ptr(), new_vec.ptr.mut_ptr(), self.len as usize);
+            ptr::copy_nonoverlapping(self.ptr.ptr(), new_vec.ptr.mut_ptr(), self.len());
         }
         new_vec.len = self.len;
         new_vec
     }
 }
 
-impl<T: Compact + Clone, A: Allocator> FromIterator<T> for CompactVec<T, A> {
-    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+impl<T: Compact + Clone, A: InstanceAllocator, I: CompactIndex> FromIterator<T> for CompactVec<T, A, I>
+where
+    A::Handle: Default,
+{
+    fn from_iter<It: IntoIterator<Item = T>>(iter: It) -> Self {
         let into_iter = iter.into_iter();
         let mut vec = CompactVec::with_capacity(into_iter.size_hint().0);
         for item in into_iter {
@@ -478,21 +887,26 @@ impl<T: Compact + Clone, A: Allocator> FromIterator<T> for CompactVec<T, A> {
     }
 }
 
-impl<T: Compact + Clone, A: Allocator> Extend<T> for CompactVec<T, A> {
-    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+impl<T: Compact + Clone, A: InstanceAllocator, I: CompactIndex> Extend<T> for CompactVec<T, A, I> {
+    fn extend<It: IntoIterator<Item = T>>(&mut self, iter: It) {
         for item in iter {
             self.push(item);
         }
     }
 }
 
-impl<T: Compact, A: Allocator> Default for CompactVec<T, A> {
-    fn default() -> CompactVec<T, A> {
+impl<T: Compact, A: InstanceAllocator, I: CompactIndex> Default for CompactVec<T, A, I>
+where
+    A::Handle: Default,
+{
+    fn default() -> CompactVec<T, A, I> {
         CompactVec::new()
     }
 }
 
-impl<T: Compact + ::std::fmt::Debug, A: Allocator> ::std::fmt::Debug for CompactVec<T, A> {
+impl<T: Compact + ::std::fmt::Debug, A: InstanceAllocator, I: CompactIndex> ::std::fmt::Debug
+    for CompactVec<T, A, I>
+{
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         (self.deref()).fmt(f)
     }
@@ -502,10 +916,11 @@ impl<T: Compact + ::std::fmt::Debug, A: Allocator> ::std::fmt::Debug for Compact
 use ::serde::ser::SerializeSeq;
 
 #[cfg(feature = "serde-serialization")]
-impl<T, A> ::serde::ser::Serialize for CompactVec<T, A>
+impl<T, A, I> ::serde::ser::Serialize for CompactVec<T, A, I>
 where
     T: Compact + ::serde::ser::Serialize,
-    A: Allocator
+    A: InstanceAllocator,
+    I: CompactIndex
 {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -520,12 +935,15 @@ where
 }
 
 #[cfg(feature = "serde-serialization")]
-struct CompactVecVisitor<T, A: Allocator> {
-    marker: PhantomData<fn() -> CompactVec<T, A>>
+type MakeCompactVec<T, A, I> = fn() -> CompactVec<T, A, I>;
+
+#[cfg(feature = "serde-serialization")]
+struct CompactVecVisitor<T, A: InstanceAllocator, I: CompactIndex> {
+    marker: PhantomData<MakeCompactVec<T, A, I>>
 }
 
 #[cfg(feature = "serde-serialization")]
-impl<T, A: Allocator> CompactVecVisitor<T, A> {
+impl<T, A: InstanceAllocator, I: CompactIndex> CompactVecVisitor<T, A, I> {
     fn new() -> Self {
         CompactVecVisitor {
             marker: PhantomData
@@ -534,12 +952,14 @@ impl<T, A: Allocator> CompactVecVisitor<T, A> {
 }
 
 #[cfg(feature = "serde-serialization")]
-impl<'de, T, A> ::serde::de::Visitor<'de> for CompactVecVisitor<T, A>
+impl<'de, T, A, I> ::serde::de::Visitor<'de> for CompactVecVisitor<T, A, I>
 where
     T: Compact + ::serde::de::Deserialize<'de>,
-    A: Allocator
+    A: InstanceAllocator,
+    A::Handle: Default,
+    I: CompactIndex
 {
-    type Value = CompactVec<T, A>;
+    type Value = CompactVec<T, A, I>;
 
     fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         formatter.write_str("A Compact Vector")
@@ -560,16 +980,136 @@ where
 }
 
 #[cfg(feature = "serde-serialization")]
-impl<'de, T, A> ::serde::de::Deserialize<'de> for CompactVec<T, A>
+impl<'de, T, A, I> ::serde::de::Deserialize<'de> for CompactVec<T, A, I>
 where
     T: Compact + ::serde::de::Deserialize<'de>,
-    A: Allocator
+    A: InstanceAllocator,
+    A::Handle: Default,
+    I: CompactIndex
 {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: ::serde::de::Deserializer<'de>,
     {
-        deserializer.deserialize_map(CompactVecVisitor::new())
+        deserializer.deserialize_seq(CompactVecVisitor::new())
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<T, A: InstanceAllocator, I: CompactIndex> CompactVec<T, A, I> {
+    /// Write the static part of a vector with `len` items stored compactly at `dynamic_part` to `dest`
+    pub(crate) unsafe fn write_compact_static(dest: *mut Self, len: usize, alloc: A::Handle, dynamic_part: *mut u8) {
+        ptr::write(&mut (*dest).len, I::from_usize(len));
+        ptr::write(&mut (*dest).cap, I::from_usize(len));
+        ptr::write(&mut (*dest).alloc, alloc);
+        ptr::write(&mut (*dest).ptr, PointerToMaybeCompact::default());
+        (*dest).ptr.set_to_compact(dynamic_part as *mut T);
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+struct MeasureItems<'p, T, I> {
+    plan: &'p mut DeserializePlan,
+    marker: PhantomData<fn() -> (T, I)>,
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'de, 'p, T: DeserializeCompact<'de>, I: CompactIndex> ::serde::de::Visitor<'de> for MeasureItems<'p, T, I> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        formatter.write_str("A Compact Vector")
+    }
+
+    fn visit_seq<S>(self, mut access: S) -> Result<usize, S::Error>
+    where
+        S: ::serde::de::SeqAccess<'de>,
+    {
+        let reserved = self.plan.reserve_length();
+        let mut len = 0;
+        let mut items_size = 0;
+        while let Some(item_size) = access.next_element_seed(Measure::<T>::new(self.plan))? {
+            len += 1;
+            items_size += item_size;
+        }
+        if len > I::MAX {
+            return Err(::serde::de::Error::invalid_length(len, &self));
+        }
+        self.plan.set_length(reserved, len);
+        Ok(len * ::std::mem::size_of::<T>() + items_size)
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+struct WriteItems<'p, T, A: InstanceAllocator, I: CompactIndex> {
+    plan: &'p mut DeserializePlan,
+    dest: *mut CompactVec<T, A, I>,
+    dynamic_part: *mut u8,
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'de, 'p, T, A, I> ::serde::de::Visitor<'de> for WriteItems<'p, T, A, I>
+where
+    T: DeserializeCompact<'de>,
+    A: InstanceAllocator,
+    A::Handle: Default,
+    I: CompactIndex,
+{
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        formatter.write_str("A Compact Vector")
+    }
+
+    fn visit_seq<S>(self, mut access: S) -> Result<usize, S::Error>
+    where
+        S: ::serde::de::SeqAccess<'de>,
+    {
+        let len = self.plan.next_length()?;
+        unsafe {
+            CompactVec::write_compact_static(self.dest, len, A::Handle::default(), self.dynamic_part);
+            let items = self.dynamic_part as *mut T;
+            let mut offset = len * ::std::mem::size_of::<T>();
+            for i in 0..len {
+                let seed = WriteInto::new(self.plan, items.add(i), self.dynamic_part.add(offset));
+                match access.next_element_seed(seed)? {
+                    Some(item_size) => offset += item_size,
+                    None => return Err(::serde::de::Error::invalid_length(i, &self)),
+                }
+            }
+            if access.next_element::<::serde::de::IgnoredAny>()?.is_some() {
+                return Err(::serde::de::Error::invalid_length(len + 1, &self));
+            }
+            Ok(offset)
+        }
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'de, T, A, I> DeserializeCompact<'de> for CompactVec<T, A, I>
+where
+    T: DeserializeCompact<'de>,
+    A: InstanceAllocator,
+    A::Handle: Default,
+    I: CompactIndex,
+{
+    fn measure<D>(deserializer: D, plan: &mut DeserializePlan) -> Result<usize, D::Error>
+    where
+        D: ::serde::de::Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(MeasureItems::<T, I> { plan, marker: PhantomData })
+    }
+
+    unsafe fn deserialize_compact_into<D>(
+        deserializer: D,
+        plan: &mut DeserializePlan,
+        dest: *mut Self,
+        dynamic_part: *mut u8,
+    ) -> Result<usize, D::Error>
+    where
+        D: ::serde::de::Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(WriteItems { plan, dest, dynamic_part })
     }
 }
 
@@ -625,3 +1165,272 @@ fn nested_vector() {
         DefaultHeap::deallocate(storage, bytes);
     }
 }
+
+#[test]
+fn tight_compaction_trims_capacity() {
+    use super::compact_box::CompactBox;
+    use super::compact_hash_map::OpenAddressingMap;
+    type NestedType = CompactVec<CompactVec<u32>>;
+    let mut list_of_lists: NestedType = CompactVec::with_capacity(1024);
+    let mut inner = CompactVec::with_capacity(100);
+    inner.extend_from_copy_slice(&[1, 2, 3]);
+    list_of_lists.push(inner);
+    list_of_lists.push(CompactVec::new());
+
+    let element_size = ::std::mem::size_of::<CompactVec<u32>>();
+    assert_eq!(1024 * element_size + 100 * 4, list_of_lists.dynamic_size_bytes());
+    assert_eq!(2 * element_size + 3 * 4, list_of_lists.dynamic_size_bytes_tight());
+
+    let tight_size = list_of_lists.total_size_bytes_tight();
+    let mut boxed = CompactBox::new_tight(list_of_lists);
+    assert_eq!(tight_size, boxed.as_bytes().len());
+    assert_eq!(2, boxed.capacity());
+    assert_eq!(3, boxed[0].capacity());
+    assert_eq!(&[1, 2, 3], &*boxed[0]);
+
+    // spills, since there's no capacity left
+    boxed[1].push(4);
+    assert!(!boxed.is_still_compact());
+    assert_eq!(&[4], &*boxed[1]);
+
+    let mut map: OpenAddressingMap<u32, CompactVec<u32>> = OpenAddressingMap::new();
+    map.insert(1, CompactVec::with_capacity(50));
+    map.get_mut(1).unwrap().push(5);
+    assert_eq!(map.dynamic_size_bytes() - 49 * 4, map.dynamic_size_bytes_tight());
+    let boxed = CompactBox::new_tight(map);
+    assert_eq!(&[5], &**boxed.get(1).unwrap());
+    assert_eq!(1, boxed.get(1).unwrap().capacity());
+}
+
+#[test]
+fn narrow_and_wide_vectors() {
+    let mut narrow: CompactVec16<u32> = CompactVec::new();
+    let mut wide: CompactVec64<CompactVec16<u32>> = CompactVec::new();
+    narrow.extend_from_copy_slice(&[1, 2, 3]);
+    wide.push(narrow);
+
+    let bytes = wide.total_size_bytes();
+    let storage = DefaultHeap::allocate(bytes);
+
+    unsafe {
+        Compact::compact_behind(&mut wide, storage as *mut CompactVec64<CompactVec16<u32>>);
+        ::std::mem::forget(wide);
+        assert_eq!(&[1, 2, 3], &*(*(storage as *mut CompactVec64<CompactVec16<u32>>))[0]);
+        let decompacted = Compact::decompact(storage as *mut CompactVec64<CompactVec16<u32>>);
+        assert_eq!(&[1, 2, 3], &*decompacted[0]);
+        DefaultHeap::deallocate(storage, bytes);
+    }
+}
+
+#[test]
+fn static_part_is_pointer_and_two_indices() {
+    use std::mem::size_of;
+    let pointer = size_of::<PointerToMaybeCompact<u32>>();
+    // padded to the alignment of the pointer, so no smaller than with `u32`s on 64-bit targets
+    assert_eq!((pointer + 2 * 2).next_multiple_of(pointer), size_of::<CompactVec16<u32>>());
+    assert_eq!(pointer + 2 * 4, size_of::<CompactVec<u32>>());
+    assert_eq!(pointer + 2 * 8, size_of::<CompactVec64<u32>>());
+}
+
+#[test]
+fn fingerprint_covers_index_width_and_allocator() {
+    use super::arena::ArenaHeap;
+    let fingerprint = <CompactVec<u32> as Compact>::type_fingerprint();
+    assert_ne!(fingerprint, <CompactVec16<u32> as Compact>::type_fingerprint());
+    assert_ne!(fingerprint, <CompactVec64<u32> as Compact>::type_fingerprint());
+    assert_ne!(fingerprint, <CompactVec<u32, ArenaHeap> as Compact>::type_fingerprint());
+}
+
+#[test]
+#[should_panic(expected = "capacity overflow")]
+fn narrow_vector_overflow() {
+    let mut narrow: CompactVec16<u8> = CompactVec::with_capacity(u16::MAX as usize);
+    for _ in 0..u16::MAX {
+        narrow.push(0);
+    }
+    narrow.push(0);
+}
+
+#[cfg(test)]
+thread_local! {
+    static DROPPED: ::std::cell::RefCell<Vec<u32>> = const { ::std::cell::RefCell::new(Vec::new()) };
+    static DECOMPACTS_BEFORE_PANIC: ::std::cell::Cell<Option<usize>> = const { ::std::cell::Cell::new(None) };
+}
+
+/// Element that records its drops and can be told to panic when being decompacted
+#[cfg(test)]
+#[derive(Clone)]
+pub struct Tracked(pub u32);
+
+#[cfg(test)]
+impl Tracked {
+    pub fn reset() {
+        DROPPED.with(|dropped| dropped.borrow_mut().clear());
+        DECOMPACTS_BEFORE_PANIC.with(|n| n.set(None));
+    }
+
+    pub fn panic_after_decompacts(n: Option<usize>) {
+        DECOMPACTS_BEFORE_PANIC.with(|before_panic| before_panic.set(n));
+    }
+
+    pub fn dropped() -> Vec<u32> {
+        DROPPED.with(|dropped| dropped.borrow().clone())
+    }
+
+    pub fn assert_dropped_at_most_once() {
+        let mut dropped = Self::dropped();
+        let n_drops = dropped.len();
+        dropped.sort();
+        dropped.dedup();
+        assert_eq!(n_drops, dropped.len(), "element dropped twice");
+    }
+}
+
+#[cfg(test)]
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        DROPPED.with(|dropped| dropped.borrow_mut().push(self.0));
+    }
+}
+
+#[cfg(test)]
+impl Compact for Tracked {
+    fn is_still_compact(&self) -> bool {
+        true
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        0
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, _new_dynamic_part: *mut u8) {
+        ptr::copy_nonoverlapping(source, dest, 1)
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        DECOMPACTS_BEFORE_PANIC.with(|before_panic| match before_panic.get() {
+            Some(0) => panic!("decompact panicked"),
+            Some(n) => before_panic.set(Some(n - 1)),
+            None => {}
+        });
+        Tracked((*source).0)
+    }
+
+    fn type_fingerprint() -> u64 {
+        structural_fingerprint::<Self>("Tracked", &[])
+    }
+}
+
+#[cfg(test)]
+fn tracked_list(n: u32) -> CompactVec<Tracked> {
+    Tracked::reset();
+    let mut list = CompactVec::with_capacity(2 * n as usize);
+    for i in 0..n {
+        list.push(Tracked(i));
+    }
+    list
+}
+
+#[test]
+fn insert_with_panicking_element_never_drops_twice() {
+    let mut list = tracked_list(5);
+
+    Tracked::panic_after_decompacts(Some(2));
+    let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+        list.insert(1, Tracked(5));
+    }));
+    Tracked::panic_after_decompacts(None);
+
+    assert!(result.is_err());
+    assert_eq!(1, list.len());
+    ::std::mem::drop(list);
+    Tracked::assert_dropped_at_most_once();
+}
+
+#[test]
+fn remove_with_panicking_element_never_drops_twice() {
+    let mut list = tracked_list(5);
+
+    Tracked::panic_after_decompacts(Some(2));
+    let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+        list.remove(1);
+    }));
+    Tracked::panic_after_decompacts(None);
+
+    assert!(result.is_err());
+    assert_eq!(1, list.len());
+    ::std::mem::drop(list);
+    Tracked::assert_dropped_at_most_once();
+}
+
+#[test]
+fn retain_with_panicking_closure_never_drops_twice() {
+    let mut list = tracked_list(5);
+
+    let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+        list.retain(|item| if item.0 == 3 { panic!("keep panicked") } else { item.0 % 2 == 0 });
+    }));
+
+    assert!(result.is_err());
+    assert_eq!(vec![0, 2], list.iter().map(|item| item.0).collect::<Vec<_>>());
+    ::std::mem::drop(list);
+    assert_eq!(vec![1, 0, 2], Tracked::dropped());
+    Tracked::assert_dropped_at_most_once();
+}
+
+#[test]
+fn retain_nested_vector_in_compact_storage() {
+    type NestedType = CompactVec<CompactVec<u32>>;
+    let mut list_of_lists: NestedType = CompactVec::new();
+
+    list_of_lists.push(vec![1, 2, 3].into());
+    list_of_lists.push(vec![4, 5].into());
+    list_of_lists.push(vec![6, 7, 8, 9].into());
+
+    let bytes = list_of_lists.total_size_bytes();
+    let storage = DefaultHeap::allocate(bytes);
+
+    unsafe {
+        Compact::compact_behind(&mut list_of_lists, storage as *mut NestedType);
+        ::std::mem::forget(list_of_lists);
+        (*(storage as *mut NestedType)).retain(|list| list.len() != 2);
+        assert_eq!(2, (*(storage as *mut NestedType)).len());
+        assert_eq!(&[1, 2, 3], &*(*(storage as *mut NestedType))[0]);
+        assert_eq!(&[6, 7, 8, 9], &*(*(storage as *mut NestedType))[1]);
+        let decompacted = Compact::decompact(storage as *mut NestedType);
+        assert_eq!(&[6, 7, 8, 9], &*decompacted[1]);
+        DefaultHeap::deallocate(storage, bytes);
+    }
+}
+
+#[test]
+fn equality_ordering_and_hashing() {
+    use super::arena::ArenaHeap;
+    use super::compact_str::CompactString;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::HashSet;
+    let hash = |value: &dyn Fn(&mut DefaultHasher)| {
+        let mut hasher = DefaultHasher::new();
+        value(&mut hasher);
+        hasher.finish()
+    };
+
+    let list: CompactVec<u32> = vec![1, 2, 3].into();
+    let mut arena_list: CompactVec16<u32, ArenaHeap> = CompactVec::new();
+    arena_list.extend_from_copy_slice(&[1, 2, 3]);
+    assert!(list == arena_list);
+    assert_eq!(hash(&|state| list.hash(state)), hash(&|state| arena_list.hash(state)));
+    assert_eq!(hash(&|state| list.hash(state)), hash(&|state| [1u32, 2, 3][..].hash(state)));
+    arena_list.push(0);
+    assert!(list < arena_list);
+
+    let words: CompactVec<CompactString> =
+        vec!["b".to_owned().into(), "a".to_owned().into(), "b".to_owned().into()].into();
+    assert!(words[0] == "b");
+    assert!(words[1] < words[0]);
+    let unique: HashSet<&CompactString> = words.iter().collect();
+    assert_eq!(2, unique.len());
+
+    let boxed = super::compact_box::CompactBox::new(words.clone());
+    assert!(words == *boxed);
+}
@@ -0,0 +1,253 @@
+use super::compact::Compact;
+use super::compact_box::{CompactBox, Image};
+use serde::de::{Deserialize, DeserializeSeed, Deserializer, Error};
+use std::marker::PhantomData;
+use std::mem;
+
+/// The lengths of all containers in a serialized value, in the order in which they are
+/// deserialized, recorded by the measuring pass of `deserialize_compact` for its writing pass
+#[derive(Default, Debug)]
+pub struct DeserializePlan {
+    lengths: Vec<usize>,
+    next: usize,
+}
+
+impl DeserializePlan {
+    /// In the measuring pass: reserve the place of the length of a container
+    /// that is only known after deserializing its contents, see `set_length`
+    pub fn reserve_length(&mut self) -> usize {
+        self.lengths.push(0);
+        self.lengths.len() - 1
+    }
+
+    /// In the measuring pass: set the length reserved with `reserve_length`
+    pub fn set_length(&mut self, reserved: usize, length: usize) {
+        self.lengths[reserved] = length;
+    }
+
+    /// In the writing pass: the next recorded length
+    pub fn next_length<E: Error>(&mut self) -> Result<usize, E> {
+        let length = self.lengths.get(self.next).cloned();
+        self.next += 1;
+        length.ok_or_else(|| E::custom("input changed between measuring and writing"))
+    }
+}
+
+/// Types that can be deserialized directly into compact form, in two passes over the same input:
+/// first measuring the size of the dynamic part, then writing the static and dynamic parts
+/// into memory of exactly that size.
+///
+/// Implemented for `Copy` types and the containers of this crate. Both passes of an implementation
+/// have to use the same layout as `Compact::compact_tight`.
+pub trait DeserializeCompact<'de>: Compact {
+    /// Deserialize a `Self` only to return the size of its dynamic part,
+    /// recording the lengths of its containers in `plan`
+    fn measure<D: Deserializer<'de>>(deserializer: D, plan: &mut DeserializePlan) -> Result<usize, D::Error>;
+
+    /// Deserialize a `Self` with the lengths recorded in `plan`, writing its static part to `dest`
+    /// and its dynamic part to `dynamic_part`. Returns the size of the dynamic part written.
+    ///
+    /// # Safety
+    /// `dest` has to point to zeroed memory for a `Self` and `dynamic_part` to zeroed memory
+    /// of the size returned by `measure` for the same input. The input has to be checked against
+    /// `plan`, so that nothing is written beyond that size if the input changed between passes.
+    unsafe fn deserialize_compact_into<D: Deserializer<'de>>(
+        deserializer: D,
+        plan: &mut DeserializePlan,
+        dest: *mut Self,
+        dynamic_part: *mut u8,
+    ) -> Result<usize, D::Error>;
+}
+
+impl<'de, T: Copy + Deserialize<'de>> DeserializeCompact<'de> for T {
+    fn measure<D: Deserializer<'de>>(deserializer: D, _plan: &mut DeserializePlan) -> Result<usize, D::Error> {
+        T::deserialize(deserializer).map(|_| 0)
+    }
+
+    unsafe fn deserialize_compact_into<D: Deserializer<'de>>(
+        deserializer: D,
+        _plan: &mut DeserializePlan,
+        dest: *mut Self,
+        _dynamic_part: *mut u8,
+    ) -> Result<usize, D::Error> {
+        ::std::ptr::write(dest, T::deserialize(deserializer)?);
+        Ok(0)
+    }
+}
+
+/// A seed for `DeserializeCompact::measure` of nested values
+pub(crate) struct Measure<'p, T> {
+    plan: &'p mut DeserializePlan,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<'p, T> Measure<'p, T> {
+    pub(crate) fn new(plan: &'p mut DeserializePlan) -> Self {
+        Measure {
+            plan,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'de, 'p, T: DeserializeCompact<'de>> DeserializeSeed<'de> for Measure<'p, T> {
+    type Value = usize;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<usize, D::Error> {
+        T::measure(deserializer, self.plan)
+    }
+}
+
+/// A seed for `DeserializeCompact::deserialize_compact_into` of nested values
+pub(crate) struct WriteInto<'p, T> {
+    plan: &'p mut DeserializePlan,
+    dest: *mut T,
+    dynamic_part: *mut u8,
+}
+
+impl<'p, T> WriteInto<'p, T> {
+    /// Has the same safety requirements as `DeserializeCompact::deserialize_compact_into`
+    pub(crate) unsafe fn new(plan: &'p mut DeserializePlan, dest: *mut T, dynamic_part: *mut u8) -> Self {
+        WriteInto {
+            plan,
+            dest,
+            dynamic_part,
+        }
+    }
+}
+
+impl<'de, 'p, T: DeserializeCompact<'de>> DeserializeSeed<'de> for WriteInto<'p, T> {
+    type Value = usize;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<usize, D::Error> {
+        unsafe { T::deserialize_compact_into(deserializer, self.plan, self.dest, self.dynamic_part) }
+    }
+}
+
+/// Deserialize a `T` directly into a `CompactBox`, without building free heap storage first.
+///
+/// The input is deserialized twice, from the deserializers returned by `deserializer`, so it
+/// has to be the same both times, like when reading from the same slice. Changed input is
+/// detected and reported as an error.
+pub fn deserialize_compact<'de, T, D, F>(mut deserializer: F) -> Result<CompactBox<T>, D::Error>
+where
+    T: DeserializeCompact<'de>,
+    D: Deserializer<'de>,
+    F: FnMut() -> D,
+{
+    let mut plan = DeserializePlan::default();
+    let dynamic_size = T::measure(deserializer(), &mut plan)?;
+
+    let image = Image::new(mem::size_of::<T>() + dynamic_size);
+    // the image never drops its contents, so a partially written value is just discarded on errors
+    let written = unsafe {
+        T::deserialize_compact_into(deserializer(), &mut plan, image.ptr(), T::behind(image.ptr()))?
+    };
+    if written != dynamic_size || plan.next != plan.lengths.len() {
+        return Err(D::Error::custom("input changed between measuring and writing"));
+    }
+    Ok(unsafe { CompactBox::from_image(image) })
+}
+
+#[test]
+fn deserializes_directly_into_compact_form() {
+    use super::compact_dict::CompactDict;
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    use serde::de::value::Error as ValueError;
+    use serde::de::IntoDeserializer;
+    use std::collections::BTreeMap;
+
+    let words = vec!["compact", "", "values"];
+    let boxed: CompactBox<CompactVec<CompactString>> =
+        deserialize_compact(|| IntoDeserializer::<ValueError>::into_deserializer(words.clone())).unwrap();
+    assert!(boxed.is_still_compact());
+    assert_eq!(boxed.total_size_bytes(), boxed.as_bytes().len());
+    assert_eq!(words, boxed.iter().map(|word| &**word).collect::<Vec<_>>());
+
+    let mut nested = BTreeMap::new();
+    nested.insert(3u64, vec![vec![1u64, 2], vec![]]);
+    nested.insert(1, vec![vec![3]]);
+    let boxed: CompactBox<CompactDict<u64, CompactVec<CompactVec<u64>>>> =
+        deserialize_compact(|| IntoDeserializer::<ValueError>::into_deserializer(nested.clone())).unwrap();
+    assert!(boxed.is_still_compact());
+    assert_eq!(boxed.total_size_bytes(), boxed.as_bytes().len());
+    assert_eq!(&[1, 2], &boxed.get(3).unwrap()[0][..]);
+    assert_eq!(&[3], &boxed.get(1).unwrap()[0][..]);
+
+    let decompacted: CompactVec<u32> =
+        Deserialize::deserialize(IntoDeserializer::<ValueError>::into_deserializer(vec![4u32, 5])).unwrap();
+    assert_eq!(&[4, 5], &decompacted[..]);
+}
+
+#[test]
+fn detects_input_changing_between_passes() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    use serde::de::value::Error as ValueError;
+    use serde::de::IntoDeserializer;
+
+    let mut inputs = vec![vec!["a", "b"], vec!["a", "bc"]].into_iter();
+    let result: Result<CompactBox<CompactVec<CompactString>>, ValueError> =
+        deserialize_compact(|| inputs.next().unwrap().into_deserializer());
+    assert!(result.is_err());
+
+    let mut inputs = vec![vec![vec![1u32]], vec![vec![1], vec![2]]].into_iter();
+    let result: Result<CompactBox<CompactVec<CompactVec<u32>>>, ValueError> =
+        deserialize_compact(|| inputs.next().unwrap().into_deserializer());
+    assert!(result.is_err());
+}
+
+/// Deserializes its inner deserializer as `Some`, which `serde::de::value` has no deserializer for
+#[cfg(test)]
+struct Present<D>(D);
+
+#[cfg(test)]
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for Present<D> {
+    type Error = D::Error;
+
+    fn deserialize_any<V: ::serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, D::Error> {
+        self.0.deserialize_any(visitor)
+    }
+
+    fn deserialize_option<V: ::serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, D::Error> {
+        visitor.visit_some(self.0)
+    }
+
+    ::serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+#[test]
+fn deserializes_options_in_place() {
+    use super::compact_option::CompactOption;
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    use serde::de::value::Error as ValueError;
+    use serde::de::IntoDeserializer;
+
+    let words = vec!["some", "words"];
+    let boxed: CompactBox<CompactOption<CompactVec<CompactString>>> =
+        deserialize_compact(|| Present(IntoDeserializer::<ValueError>::into_deserializer(words.clone()))).unwrap();
+    assert!(boxed.is_still_compact());
+    assert_eq!(boxed.total_size_bytes(), boxed.as_bytes().len());
+    let list = boxed.as_ref().unwrap();
+    assert_eq!(words, list.iter().map(|word| &**word).collect::<Vec<_>>());
+
+    let boxed: CompactBox<CompactOption<CompactVec<CompactString>>> =
+        deserialize_compact(|| IntoDeserializer::<ValueError>::into_deserializer(())).unwrap();
+    assert!(boxed.is_none());
+}
+
+#[test]
+fn refuses_duplicate_dictionary_keys() {
+    use super::compact_dict::CompactDict;
+    use serde::de::value::{Error as ValueError, MapDeserializer};
+
+    let entries = vec![(1u64, 2u64), (7, 3), (1, 4)];
+    let result: Result<CompactBox<CompactDict<u64, u64>>, ValueError> =
+        deserialize_compact(|| MapDeserializer::new(entries.clone().into_iter()));
+    assert!(result.is_err());
+}
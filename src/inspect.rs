@@ -0,0 +1,229 @@
+use super::compact::Compact;
+use std::fmt;
+use std::mem;
+
+/// Where the dynamic part of a container is stored
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Storage {
+    /// There is no dynamic part (yet)
+    None,
+    /// Compactly, at this address
+    Compact(usize),
+    /// Freely on the heap, at this address
+    Free(usize),
+}
+
+/// A node in the tree of static and dynamic parts of a `Compact` value,
+/// as returned by `Compact::inspect_layout`.
+///
+/// Prints as an indented tree with offsets relative to the inspected value,
+/// and with the `serde-serialization` feature serializes with the same offsets.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayoutNode {
+    /// Field or index of this part in its parent, empty for the inspected value
+    pub label: String,
+    /// Type of the part
+    pub type_name: &'static str,
+    /// Address of the static part
+    pub address: usize,
+    /// Size of the static part in bytes
+    pub static_size: usize,
+    /// Size of the dynamic part in bytes, including the dynamic parts of children
+    pub dynamic_size: usize,
+    /// Where the dynamic part is stored
+    pub storage: Storage,
+    /// Bytes of the dynamic part that are reserved but unused, like spare capacity
+    pub wasted_bytes: usize,
+    /// Parts stored in the dynamic part that have dynamic parts themselves
+    pub children: Vec<LayoutNode>,
+}
+
+impl LayoutNode {
+    /// A node for `value` with its dynamic part stored in `storage`
+    pub fn new<T: Compact>(value: &T, storage: Storage) -> LayoutNode {
+        LayoutNode {
+            label: String::new(),
+            type_name: ::std::any::type_name::<T>(),
+            address: value as *const T as usize,
+            static_size: mem::size_of::<T>(),
+            dynamic_size: value.dynamic_size_bytes(),
+            storage,
+            wasted_bytes: 0,
+            children: Vec::new(),
+        }
+    }
+
+    /// Set the bytes of the dynamic part that are unused
+    pub fn wasting(mut self, wasted_bytes: usize) -> LayoutNode {
+        self.wasted_bytes = wasted_bytes;
+        self
+    }
+
+    /// Add the layout of a part of the dynamic part, labeled as `label`
+    pub fn child<L: Into<String>>(mut self, label: L, mut child: LayoutNode) -> LayoutNode {
+        child.label = label.into();
+        self.children.push(child);
+        self
+    }
+
+    /// Add the layout of a part like `child`, unless it is trivial
+    pub fn child_unless_trivial<L: Into<String>>(self, label: L, child: LayoutNode) -> LayoutNode {
+        if child.is_trivial() {
+            self
+        } else {
+            self.child(label, child)
+        }
+    }
+
+    /// Is there anything to report about this part besides its static size?
+    pub fn is_trivial(&self) -> bool {
+        self.storage == Storage::None && self.dynamic_size == 0 && self.children.is_empty()
+    }
+
+    /// Bytes unused in this part and all of its children
+    pub fn total_wasted_bytes(&self) -> usize {
+        self.wasted_bytes + self.children.iter().map(|child| child.total_wasted_bytes()).sum::<usize>()
+    }
+
+    /// Do this part and all of its children only use compact storage?
+    pub fn is_compact(&self) -> bool {
+        match self.storage {
+            Storage::Free(_) => false,
+            _ => self.children.iter().all(|child| child.is_compact()),
+        }
+    }
+
+    fn write_tree(&self, f: &mut fmt::Formatter, root: usize, depth: usize) -> fmt::Result {
+        write!(f, "{:indent$}", "", indent = depth * 2)?;
+        if !self.label.is_empty() {
+            write!(f, "{}: ", self.label)?;
+        }
+        write!(
+            f,
+            "{} @{:+} static {} B",
+            self.type_name,
+            self.address as isize - root as isize,
+            self.static_size
+        )?;
+        match self.storage {
+            Storage::None => {}
+            Storage::Compact(address) => write!(
+                f,
+                ", dynamic {} B compact @{:+}",
+                self.dynamic_size,
+                address as isize - root as isize
+            )?,
+            Storage::Free(address) => write!(f, ", dynamic {} B free at {:#x}", self.dynamic_size, address)?,
+        }
+        if self.wasted_bytes > 0 {
+            write!(f, ", {} B unused", self.wasted_bytes)?;
+        }
+        writeln!(f)?;
+        for child in &self.children {
+            child.write_tree(f, root, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for LayoutNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_tree(f, self.address, 0)
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+struct RelativeTo<'a>(&'a LayoutNode, usize);
+
+#[cfg(feature = "serde-serialization")]
+struct RelativeChildren<'a>(&'a [LayoutNode], usize);
+
+#[cfg(feature = "serde-serialization")]
+impl ::serde::Serialize for LayoutNode {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RelativeTo(self, self.address).serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'a> ::serde::Serialize for RelativeTo<'a> {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let RelativeTo(node, root) = *self;
+        let (storage, dynamic_offset, dynamic_address) = match node.storage {
+            Storage::None => ("none", None, None),
+            Storage::Compact(address) => ("compact", Some(address as isize - root as isize), None),
+            Storage::Free(address) => ("free", None, Some(address)),
+        };
+        let mut state = serializer.serialize_struct("LayoutNode", 10)?;
+        state.serialize_field("label", &node.label)?;
+        state.serialize_field("type_name", node.type_name)?;
+        state.serialize_field("offset", &(node.address as isize - root as isize))?;
+        state.serialize_field("static_size", &node.static_size)?;
+        state.serialize_field("dynamic_size", &node.dynamic_size)?;
+        state.serialize_field("storage", storage)?;
+        state.serialize_field("dynamic_offset", &dynamic_offset)?;
+        state.serialize_field("dynamic_address", &dynamic_address)?;
+        state.serialize_field("wasted_bytes", &node.wasted_bytes)?;
+        state.serialize_field("children", &RelativeChildren(&node.children, root))?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde-serialization")]
+impl<'a> ::serde::Serialize for RelativeChildren<'a> {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for child in self.0 {
+            seq.serialize_element(&RelativeTo(child, self.1))?;
+        }
+        seq.end()
+    }
+}
+
+#[test]
+fn inspect_compact_and_spilled_parts() {
+    use super::compact_box::CompactBox;
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    let mut value: CompactVec<CompactString> = CompactVec::with_capacity(3);
+    value.push("ab".to_owned().into());
+    value.push(CompactString::new());
+    let mut boxed = CompactBox::new(value);
+
+    let layout = boxed.inspect_layout();
+    let string_size = mem::size_of::<CompactString>();
+    assert_eq!(Storage::Compact(layout.address + mem::size_of::<CompactVec<CompactString>>()), layout.storage);
+    assert_eq!(string_size, layout.wasted_bytes);
+    assert_eq!(2, layout.children.len());
+    assert!(layout.is_compact());
+    let vec_size = mem::size_of::<CompactVec<CompactString>>();
+    let expected = format!(
+        "{} @+0 static {} B, dynamic {} B compact @+{}, {} B unused\n\
+         \x20 [0]: {} @+{} static {} B, dynamic 2 B compact @+{}\n\
+         \x20 [1]: {} @+{} static {} B, dynamic 0 B compact @+{}\n",
+        ::std::any::type_name::<CompactVec<CompactString>>(),
+        vec_size,
+        3 * string_size + 2,
+        vec_size,
+        string_size,
+        ::std::any::type_name::<CompactString>(),
+        vec_size,
+        string_size,
+        vec_size + 3 * string_size,
+        ::std::any::type_name::<CompactString>(),
+        vec_size + string_size,
+        string_size,
+        vec_size + 3 * string_size + 2,
+    );
+    assert_eq!(expected, layout.to_string());
+
+    boxed[1].push_str("c");
+    let layout = boxed.inspect_layout();
+    assert!(!layout.is_compact());
+    match layout.children[1].storage {
+        Storage::Free(address) => assert_eq!(boxed[1].as_ptr() as usize, address),
+        ref storage => panic!("expected free storage, got {:?}", storage),
+    }
+}
@@ -12,18 +12,60 @@
 //!   * Storing actor state compactly in one place for cache coherency and easy persistence
 //!   * Sending complex, dynamically-sized messages over boundaries
 //!     such as actors, threads and the network
+//!
+//! With the `portable` feature, pointers, lengths, capacities and map counters are stored
+//! little-endian with fixed widths. Element bytes and `Copy` payloads are still stored as they
+//! are in memory, so snapshots are only exchanged between platforms with the same endianness,
+//! and the type fingerprints include the size and alignment of every contained type.
+//! Portable images can go between 64-bit and 32-bit platforms like ARM or wasm32, but not
+//! 32-bit x86, where `u64` and `f64` are only 4-byte aligned.
 
 #![warn(missing_docs)]
 #![feature(specialization)]
+#![cfg_attr(feature = "serde-serialization", feature(offset_of_enum))]
+#![cfg_attr(feature = "allocator-api", feature(allocator_api))]
 
 extern crate simple_allocator_trait;
+extern crate crc32fast;
+#[cfg(target_os = "linux")]
+extern crate libc;
+mod allocator;
+mod stats;
+mod arena;
+mod pool;
 mod pointer_to_maybe_compact;
 mod compact;
+mod compact_index;
+mod inspect;
 mod compact_option;
 mod compact_vec;
+mod compact_ptr;
 mod compact_str;
 mod compact_dict;
 mod compact_hash_map;
+mod compact_tree;
+mod compact_box;
+mod stream;
+mod snapshot;
+mod versioned;
+mod transport;
+mod ring;
+mod compact_queue;
+#[cfg(feature = "allocator-api")]
+mod std_allocator;
+#[cfg(feature = "serde-serialization")]
+mod deserialize;
+/// Serde adapter for fields that should be stored as their compact image,
+/// for use with `#[serde(with = "compact::as_image")]`
+#[cfg(feature = "serde-serialization")]
+pub mod as_image;
+#[cfg(feature = "mmap")]
+mod compact_file;
+#[cfg(feature = "mmap")]
+mod shared_channel;
+/// Checks for `Compact` implementations, like `testing::assert_roundtrip`,
+/// to test containers and types that implement `Compact` manually
+pub mod testing;
 
 #[macro_use]
 extern crate lazy_static;
@@ -31,9 +73,78 @@ extern crate lazy_static;
 #[cfg(feature = "serde-serialization")]
 extern crate serde;
 
-pub use self::compact::Compact;
+#[cfg(feature = "mmap")]
+extern crate memmap2;
+
+#[cfg(test)]
+#[macro_use]
+extern crate static_assertions;
+
+pub use self::allocator::{InstanceAllocator, StaticHandle};
+pub use self::arena::ArenaHeap;
+pub use self::pool::PoolHeap;
+#[cfg(feature = "stats")]
+pub use self::stats::{reset_stats, stats_snapshot, TypeStats};
+#[cfg(feature = "allocator-api")]
+pub use self::std_allocator::{AsStdAllocator, StdAlloc};
+pub use self::compact::{Compact, Headroom, structural_fingerprint};
+pub use self::compact::{field_position, write_static_compact, write_zeros};
+pub use self::compact_index::CompactIndex;
+#[cfg(feature = "serde-serialization")]
+pub use self::deserialize::{deserialize_compact, DeserializeCompact, DeserializePlan};
+pub use self::inspect::{LayoutNode, Storage};
 pub use self::compact_option::CompactOption as COption;
 pub use self::compact_vec::CompactVec as CVec;
+pub use self::compact_vec::CompactVec16 as CVec16;
+pub use self::compact_vec::CompactVec64 as CVec64;
+pub use self::compact_ptr::CompactPtr as CPtr;
 pub use self::compact_str::CompactString as CString;
 pub use self::compact_dict::CompactDict as CDict;
 pub use self::compact_hash_map::OpenAddressingMap as CHashMap;
+pub use self::compact_tree::CompactTree as CTree;
+pub use self::compact_tree::NodeId;
+pub use self::compact_box::CompactBox;
+pub use self::stream::{read_compact, read_compact_limited, write_compact, TrustedImage};
+pub use self::stream::{DEFAULT_MAX_IMAGE_SIZE, LENGTH_PREFIX_SIZE};
+pub use self::snapshot::{read_snapshot, write_snapshot, SnapshotError, SnapshotHeader};
+pub use self::snapshot::{read_snapshot_header, read_snapshot_image, write_snapshot_with_version};
+pub use self::snapshot::{SNAPSHOT_FLAG_PORTABLE, SNAPSHOT_FORMAT_VERSION, SNAPSHOT_HEADER_SIZE};
+pub use self::versioned::{read_versioned_snapshot, write_versioned_snapshot};
+pub use self::versioned::{NoPreviousVersion, Versioned};
+pub use self::transport::{CompactReceiver, CompactSender};
+pub use self::ring::RingMessage;
+pub use self::compact_queue::{mpsc_queue, spsc_queue, MultiQueueSender, QueueReceiver, QueueSender};
+#[cfg(feature = "mmap")]
+pub use self::compact_file::CompactFile;
+#[cfg(feature = "mmap")]
+pub use self::shared_channel::SharedChannel;
+
+#[test]
+fn containers_are_send_and_sync_like_std() {
+    // checked at compile time
+    use compact_box::CompactBox;
+    use compact_dict::CompactDict;
+    use compact_hash_map::OpenAddressingMap;
+    use compact_option::CompactOption;
+    use compact_ptr::CompactPtr;
+    use compact_str::CompactString;
+    use compact_tree::CompactTree;
+    use compact_vec::CompactVec;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    assert_impl_all!(CompactVec<u32>: Send, Sync);
+    assert_impl_all!(CompactVec<CompactVec<u32>>: Send, Sync);
+    assert_impl_all!(CompactString: Send, Sync);
+    assert_impl_all!(CompactPtr<CompactString>: Send, Sync);
+    assert_impl_all!(CompactOption<CompactString>: Send, Sync);
+    assert_impl_all!(CompactDict<u32, CompactString>: Send, Sync);
+    assert_impl_all!(OpenAddressingMap<u32, CompactString>: Send, Sync);
+    assert_impl_all!(CompactTree<CompactString>: Send, Sync);
+    assert_impl_all!(CompactBox<CompactVec<u32>>: Send, Sync);
+
+    assert_impl_all!(CompactVec<Cell<u32>>: Send);
+    assert_not_impl_any!(CompactVec<Cell<u32>>: Sync);
+    assert_not_impl_any!(CompactVec<Rc<u32>>: Send, Sync);
+    assert_not_impl_any!(CompactPtr<Rc<u32>>: Send, Sync);
+}
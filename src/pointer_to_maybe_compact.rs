@@ -1,24 +1,43 @@
 use std;
-/// Specifies the 3 states that the pointer can be in:
-/// 1. Free: On the heap - Stores a pointer
-/// 2. Compact: On the dynamic part - Stores an offset
-/// 3. Null
-enum Inner {
-    Free(u64),
-    Compact(i32),
-    Uninitialized,
-}
 
-/// See Inner
+#[cfg(not(feature = "portable"))]
+type Raw = usize;
+#[cfg(not(feature = "portable"))]
+type SignedRaw = isize;
+
+/// With the `portable` feature, the pointer has the same width on all platforms
+/// and is stored little-endian
+#[cfg(feature = "portable")]
+type Raw = u64;
+#[cfg(feature = "portable")]
+type SignedRaw = i64;
+
+/// Set for compact offsets, never set for heap pointers in user space
+const COMPACT_TAG: Raw = 1 << (std::mem::size_of::<Raw>() * 8 - 1);
+
+/// Specifies the 3 states that the pointer can be in, packed into one word:
+/// 1. Free: On the heap - Stores a pointer (highest bit unset)
+/// 2. Compact: On the dynamic part - Stores an offset in the lower bits (highest bit set)
+/// 3. Null - Stores zero
+///
+/// Compact offsets take all bits but the tag, so they are one bit narrower than pointers
+/// and checked to fit when set. Narrower offsets would not make the pointer any smaller,
+/// since it still has to hold free pointers, so only the width of the lengths and capacities
+/// next to it is configurable (see `CompactIndex`).
 pub struct PointerToMaybeCompact<T> {
-    inner: Inner,
+    inner: Raw,
     marker: ::std::marker::PhantomData<*mut T>
 }
 
+// The pointer owns the `T`s it points to, wherever they are stored,
+// and only gives access to them through `&self`/`&mut self`, like `Box<T>`
+unsafe impl<T: Send> Send for PointerToMaybeCompact<T> {}
+unsafe impl<T: Sync> Sync for PointerToMaybeCompact<T> {}
+
 impl<T> Default for PointerToMaybeCompact<T> {
     fn default() -> PointerToMaybeCompact<T> {
         PointerToMaybeCompact {
-            inner: Inner::Uninitialized,
+            inner: 0,
             marker: ::std::marker::PhantomData
         }
     }
@@ -33,62 +52,154 @@ impl<T> std::fmt::Debug for PointerToMaybeCompact<T> {
 impl<T> PointerToMaybeCompact<T> {
     /// Create a new pointer which is initialized to point on the heap
     pub fn new_free(ptr: *mut T) -> Self {
-        PointerToMaybeCompact {
-            inner: Inner::Free(ptr as u64),
-            marker: ::std::marker::PhantomData
-        }
+        let mut pointer = Self::default();
+        pointer.set_to_free(ptr);
+        pointer
     }
 
     /// Set the pointer to point on the heap
     pub fn set_to_free(&mut self, ptr: *mut T) {
-        self.inner = Inner::Free(ptr as u64)
+        assert!(
+            ptr as usize as Raw & COMPACT_TAG == 0,
+            "heap pointer {:p} collides with compact tag",
+            ptr
+        );
+        self.set_raw(ptr as usize as Raw);
     }
 
     /// Set the pointer to point on the dynamic part of the data structure
     pub fn set_to_compact(&mut self, ptr: *mut T) {
-        self.inner = Inner::Compact((ptr as isize - self as *const Self as isize) as i32);
+        let offset = ptr as isize - self as *const Self as isize;
+        self.set_to_compact_offset(offset);
+    }
+
+    /// Set the pointer to point on the dynamic part of the data structure,
+    /// `offset` bytes away from the pointer itself
+    pub fn set_to_compact_offset(&mut self, offset: isize) {
+        let offset = offset as SignedRaw;
+        assert!((offset << 1) >> 1 == offset, "compact offset {} out of range", offset);
+        self.set_raw((offset as Raw & !COMPACT_TAG) | COMPACT_TAG);
+    }
+
+    fn raw(&self) -> Raw {
+        if cfg!(feature = "portable") {
+            Raw::from_le(self.inner)
+        } else {
+            self.inner
+        }
+    }
+
+    fn set_raw(&mut self, raw: Raw) {
+        self.inner = if cfg!(feature = "portable") {
+            raw.to_le()
+        } else {
+            raw
+        };
+    }
+
+    fn offset(&self) -> Option<isize> {
+        let raw = self.raw();
+        if raw & COMPACT_TAG != 0 {
+            // shift the tag out and sign-extend
+            Some((((raw << 1) as SignedRaw) >> 1) as isize)
+        } else {
+            None
+        }
     }
 
     /// Get a raw pointer to wherever it is pointing
     pub unsafe fn ptr(&self) -> *const T {
-        match self.inner {
-            Inner::Free(ptr) => ptr as *const T,
-            Inner::Compact(offset) => (self as *const Self as *const u8).offset(offset as isize) as *const T,
-            Inner::Uninitialized => ::std::ptr::null(),
+        match self.offset() {
+            Some(offset) => (self as *const Self as *const u8).offset(offset) as *const T,
+            None => self.raw() as usize as *const T,
         }
     }
 
     /// Get a mut pointer to wherever it is pointing
     pub unsafe fn mut_ptr(&mut self) -> *mut T {
-        match self.inner {
-            Inner::Free(ptr) => ptr as *mut T,
-            Inner::Compact(offset) => (self as *mut Self as *mut u8).offset(offset as isize) as *mut T,
-            Inner::Uninitialized => ::std::ptr::null_mut(),
+        match self.offset() {
+            Some(offset) => (self as *mut Self as *mut u8).offset(offset) as *mut T,
+            None => self.raw() as usize as *mut T,
         }
     }
 
     /// Check to see if pointer is on the dynamic part of the data structure
     pub fn is_compact(&self) -> bool {
-        match self.inner {
-            Inner::Free(_) => false,
-            Inner::Compact(_) | Inner::Uninitialized => true,
-        }
+        self.inner == 0 || self.offset().is_some()
     }
 
     /// Deallocate a memory range starting at pointer if it is in free mode
-    pub fn deallocate_if_free<A: ::simple_allocator_trait::Allocator>(&self, length: usize) {
-        if let Inner::Free(ptr) = self.inner {
+    pub fn deallocate_if_free<A: ::allocator::InstanceAllocator>(&self, alloc: &A::Handle, length: usize) {
+        if !self.is_compact() {
             unsafe {
-                A::deallocate(ptr as *mut T, length);
+                A::deallocate_in(alloc, self.raw() as usize as *mut T, length);
             }
         }
     }
 
+    /// Where the pointer is pointing, for layout inspection
+    pub fn storage(&self) -> ::inspect::Storage {
+        match self.offset() {
+            Some(_) => ::inspect::Storage::Compact(unsafe { self.ptr() } as usize),
+            None if self.inner == 0 => ::inspect::Storage::None,
+            None => ::inspect::Storage::Free(self.raw() as usize),
+        }
+    }
+
     pub fn to_string(&self) -> String {
-        match self.inner {
-            Inner::Free(p) => format!("Free {:p}", p as *const T),
-            Inner::Compact(i) => format!("Compact {:?}", i),
-            Inner::Uninitialized => String::from("uninitialized"),
+        match self.offset() {
+            Some(offset) => format!("Compact {:?}", offset),
+            None if self.inner == 0 => String::from("uninitialized"),
+            None => format!("Free {:p}", self.raw() as usize as *const T),
         }
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn fits_in_one_word() {
+    assert_eq!(
+        std::mem::size_of::<Raw>(),
+        std::mem::size_of::<PointerToMaybeCompact<u64>>()
+    );
+}
+
+#[test]
+fn stores_all_states() {
+    let mut storage = [0u64; 4];
+    let mut pointer = PointerToMaybeCompact::<u64>::default();
+    assert!(pointer.is_compact());
+    assert!(unsafe { pointer.ptr() }.is_null());
+
+    pointer.set_to_free(&mut storage[1]);
+    assert!(!pointer.is_compact());
+    assert_eq!(&storage[1] as *const u64, unsafe { pointer.ptr() });
+
+    let pointer_in_storage = &mut storage[2] as *mut u64 as *mut PointerToMaybeCompact<u64>;
+    unsafe {
+        (*pointer_in_storage).set_to_compact(&mut storage[0]);
+        assert!((*pointer_in_storage).is_compact());
+        assert_eq!(&storage[0] as *const u64, (*pointer_in_storage).ptr());
+        (*pointer_in_storage).set_to_compact(&mut storage[3]);
+        assert_eq!(&storage[3] as *const u64, (*pointer_in_storage).ptr());
+    }
+}
+
+#[test]
+#[should_panic(expected = "out of range")]
+fn refuses_offsets_out_of_range() {
+    let mut pointer = PointerToMaybeCompact::<u64>::default();
+    pointer.set_to_compact_offset(SignedRaw::MAX as isize);
+}
+
+#[test]
+#[cfg(feature = "portable")]
+fn portable_layout_is_little_endian() {
+    let mut storage = [0u64; 2];
+    let pointer_in_storage = &mut storage[1] as *mut u64 as *mut PointerToMaybeCompact<u64>;
+    unsafe {
+        (*pointer_in_storage).set_to_compact(&mut storage[0]);
+        assert_eq!(&storage[0] as *const u64, (*pointer_in_storage).ptr());
+    }
+    let expected = (-8i64 as u64 & !COMPACT_TAG) | COMPACT_TAG;
+    assert_eq!(expected.to_le_bytes(), storage[1].to_ne_bytes());
+}
@@ -0,0 +1,204 @@
+use simple_allocator_trait::Allocator;
+use std::alloc::{self, Layout};
+use std::cell::RefCell;
+
+/// Smallest size class, every class is double the size of the previous one
+const MIN_BLOCK_SIZE: usize = 16;
+const SIZE_CLASSES: usize = 12;
+
+/// Free lists of blocks from the global heap, one per power-of-two size class.
+/// Blocks are aligned to their size, so they fit any `T` of at most that size
+struct Pool {
+    free: [Vec<*mut u8>; SIZE_CLASSES],
+}
+
+fn size_class(size: usize) -> Option<usize> {
+    let block_size = size.max(MIN_BLOCK_SIZE).next_power_of_two();
+    let class = (block_size / MIN_BLOCK_SIZE).trailing_zeros() as usize;
+    if class < SIZE_CLASSES {
+        Some(class)
+    } else {
+        None
+    }
+}
+
+fn block_layout(class: usize) -> Layout {
+    let block_size = MIN_BLOCK_SIZE << class;
+    Layout::from_size_align(block_size, block_size).expect("invalid size class")
+}
+
+impl Pool {
+    fn new() -> Pool {
+        Pool { free: Default::default() }
+    }
+
+    fn allocate(&mut self, class: usize) -> *mut u8 {
+        self.free[class].pop().unwrap_or_else(|| Pool::allocate_block(class))
+    }
+
+    fn allocate_block(class: usize) -> *mut u8 {
+        let layout = block_layout(class);
+        let block = unsafe { alloc::alloc(layout) };
+        if block.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        block
+    }
+
+    fn trim(&mut self) {
+        for (class, blocks) in self.free.iter_mut().enumerate() {
+            for block in blocks.drain(..) {
+                unsafe { alloc::dealloc(block, block_layout(class)) };
+            }
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.trim();
+    }
+}
+
+thread_local! {
+    static POOL: RefCell<Pool> = RefCell::new(Pool::new());
+}
+
+/// An implementation of `Allocator` that keeps freed blocks in per-thread free lists
+/// of power-of-two size classes (16 bytes to 32 KiB) for reuse,
+/// larger allocations go directly to the global heap.
+///
+/// Good for frequently spilling containers of similar sizes,
+/// since their dynamic parts can be freed on any thread and are still reused.
+/// Only free blocks are returned to the global heap when a thread exits, so blocks
+/// in use by containers that were sent to other threads stay valid.
+pub struct PoolHeap {}
+
+impl PoolHeap {
+    /// Return all free blocks of the current thread to the global heap
+    pub fn trim() {
+        POOL.with(|pool| pool.borrow_mut().trim());
+    }
+
+    /// Bytes in free blocks of the current thread, ready for reuse
+    pub fn free_bytes() -> usize {
+        POOL.with(|pool| {
+            pool.borrow()
+                .free
+                .iter()
+                .enumerate()
+                .map(|(class, blocks)| blocks.len() * block_layout(class).size())
+                .sum()
+        })
+    }
+}
+
+impl Allocator for PoolHeap {
+    fn allocate<T>(capacity: usize) -> *mut T {
+        let layout = Layout::array::<T>(capacity).expect("capacity overflow");
+        if layout.size() == 0 {
+            return layout.align() as *mut T;
+        }
+        match size_class(layout.size()) {
+            Some(class) => {
+                // the pool might be gone already if this is called during thread exit
+                POOL.try_with(|pool| pool.borrow_mut().allocate(class))
+                    .unwrap_or_else(|_| Pool::allocate_block(class)) as *mut T
+            }
+            None => {
+                let ptr = unsafe { alloc::alloc(layout) };
+                if ptr.is_null() {
+                    alloc::handle_alloc_error(layout);
+                }
+                ptr as *mut T
+            }
+        }
+    }
+
+    unsafe fn deallocate<T>(ptr: *mut T, capacity: usize) {
+        let layout = Layout::array::<T>(capacity).expect("capacity overflow");
+        if layout.size() == 0 {
+            return;
+        }
+        match size_class(layout.size()) {
+            Some(class) => {
+                let block = ptr as *mut u8;
+                // the pool might be gone already if this is called during thread exit
+                if POOL.try_with(|pool| pool.borrow_mut().free[class].push(block)).is_err() {
+                    alloc::dealloc(block, block_layout(class));
+                }
+            }
+            None => alloc::dealloc(ptr as *mut u8, layout),
+        }
+    }
+}
+
+#[test]
+fn freed_blocks_are_reused() {
+    use super::compact_dict::CompactDict;
+    use super::compact_vec::CompactVec;
+
+    let mut dict: CompactDict<u32, CompactVec<u32, PoolHeap>, PoolHeap> = CompactDict::new();
+    for i in 0..50 {
+        dict.insert(i, (0..i).collect());
+    }
+    assert_eq!(Some(&[0, 1, 2][..]), dict.get(3).map(|v| &v[..]));
+    drop(dict);
+    let free = PoolHeap::free_bytes();
+    assert!(free >= 50 * 49 / 2 * 4);
+
+    let mut list: CompactVec<u32, PoolHeap> = CompactVec::with_capacity(40);
+    list.extend(0..40);
+    // took a free 256 byte block
+    assert_eq!(free - 256, PoolHeap::free_bytes());
+
+    let mut huge: CompactVec<u64, PoolHeap> = CompactVec::with_capacity(10_000);
+    huge.extend(0..10_000);
+    assert_eq!(free - 256, PoolHeap::free_bytes());
+    drop(huge);
+    drop(list);
+    assert_eq!(free, PoolHeap::free_bytes());
+
+    PoolHeap::trim();
+    assert_eq!(0, PoolHeap::free_bytes());
+}
+
+#[test]
+fn vecs_are_moved_into_blocks() {
+    use super::compact_vec::CompactVec;
+
+    let vec = vec![1u32, 2, 3];
+    let global = vec.as_ptr();
+    let mut list: CompactVec<u32, PoolHeap> = vec.into();
+    // the 12 byte buffer of the `Vec` doesn't fit the pool's 16 byte size class
+    assert!(list.as_ptr() != global);
+    assert_eq!(&[1, 2, 3], &list[..]);
+    list.push(4);
+    drop(list);
+    let free = PoolHeap::free_bytes();
+    let reused: CompactVec<u32, PoolHeap> = vec![5, 6, 7, 8].into();
+    assert_eq!(free - 16, PoolHeap::free_bytes());
+    assert_eq!(0, reused.as_ptr() as usize % 16);
+
+    let vec = vec![1u32, 2, 3];
+    let global = vec.as_ptr();
+    let list: CompactVec<u32> = vec.into();
+    assert_eq!(global, list.as_ptr());
+}
+
+#[test]
+fn blocks_outlive_their_thread() {
+    use super::compact_vec::CompactVec;
+
+    let list = ::std::thread::spawn(|| {
+        let list: CompactVec<u32, PoolHeap> = (0..40).collect();
+        list
+    })
+    .join()
+    .unwrap();
+    assert_eq!((0..40).collect::<Vec<_>>(), &list[..]);
+    // freed into the free list of this thread
+    let free = PoolHeap::free_bytes();
+    drop(list);
+    assert!(PoolHeap::free_bytes() > free);
+}
@@ -0,0 +1,395 @@
+use super::compact::Compact;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Deref;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// Records start at multiples of this, which is also the maximum supported alignment
+/// of message types
+pub const RECORD_ALIGN: usize = 8;
+/// Every record starts with its length as an atomic `u64`, which is zero until committed
+const RECORD_HEADER: usize = 8;
+/// Marks records that only skip the rest of the ring before wrapping around
+const PADDING_FLAG: u64 = 1 << 63;
+
+fn padded(size: usize) -> usize {
+    size.div_ceil(RECORD_ALIGN) * RECORD_ALIGN
+}
+
+#[repr(C, align(64))]
+struct CacheLine<T>(T);
+
+/// Lets threads or processes sleep until an event happens, using a futex where available
+#[repr(C)]
+struct Notifier {
+    sequence: AtomicU32,
+    waiters: AtomicU32,
+}
+
+impl Notifier {
+    fn notify(&self) {
+        self.sequence.fetch_add(1, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            wake_all(&self.sequence);
+        }
+    }
+
+    fn wait_until<F: Fn() -> bool>(&self, ready: F) {
+        while !ready() {
+            self.waiters.fetch_add(1, Ordering::SeqCst);
+            let sequence = self.sequence.load(Ordering::SeqCst);
+            // recheck, since notifications before reading the sequence would be lost
+            if !ready() {
+                wait(&self.sequence, sequence);
+            }
+            self.waiters.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn wait(word: &AtomicU32, expected: u32) {
+    // not FUTEX_PRIVATE, since the word may live in memory shared between processes
+    unsafe {
+        ::libc::syscall(
+            ::libc::SYS_futex,
+            word as *const AtomicU32,
+            ::libc::FUTEX_WAIT,
+            expected,
+            ptr::null::<::libc::timespec>(),
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn wake_all(word: &AtomicU32) {
+    unsafe {
+        ::libc::syscall(
+            ::libc::SYS_futex,
+            word as *const AtomicU32,
+            ::libc::FUTEX_WAKE,
+            i32::MAX,
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn wait(word: &AtomicU32, expected: u32) {
+    if word.load(Ordering::SeqCst) == expected {
+        ::std::thread::sleep(::std::time::Duration::from_micros(50));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn wake_all(_word: &AtomicU32) {}
+
+/// State of a ring, stored in front of its records
+#[repr(C)]
+pub struct RingHeader {
+    /// Identifies the kind of ring
+    pub magic: [u8; 8],
+    /// Size of the record area in bytes
+    pub capacity: u64,
+    /// `Compact::type_fingerprint` of the message type
+    pub type_fingerprint: u64,
+    /// Position up to which records are reserved by senders
+    tail: CacheLine<AtomicU64>,
+    /// Position of the oldest record not yet released by the receiver
+    head: CacheLine<AtomicU64>,
+    messages: CacheLine<Notifier>,
+    space: CacheLine<Notifier>,
+}
+
+/// The core of the compact message channels: a bounded ring of variable-sized
+/// records in a contiguous region of memory, which may be shared between processes.
+///
+/// Senders reserve records by advancing the tail with a compare-and-swap (or a plain
+/// store if there is only one sender), compact messages directly into them and then
+/// commit them by setting their length.
+/// A single receiver reads committed records in order, in-place, and releases them
+/// by zeroing them and advancing the head.
+///
+/// Positions only ever grow and are taken modulo the capacity. A record that
+/// doesn't fit before the end of the ring is preceded by a padding record.
+pub struct Ring {
+    header: *const RingHeader,
+    records: *mut u8,
+    capacity: usize,
+    /// Reserve records without compare-and-swap, since there is only one sender
+    single_sender: bool,
+}
+
+unsafe impl Send for Ring {}
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    /// The capacity of the record area actually used for a requested `capacity`
+    pub fn round_capacity(capacity: usize) -> usize {
+        padded(capacity.max(2 * (RECORD_HEADER + RECORD_ALIGN)))
+    }
+
+    /// Bytes of memory needed for a ring with a rounded `capacity`
+    pub fn memory_size(capacity: usize) -> usize {
+        mem::size_of::<RingHeader>() + capacity
+    }
+
+    /// Set up a new, empty ring in `memory`
+    ///
+    /// # Safety
+    /// `memory` has to be zeroed, aligned to 64 bytes, at least `memory_size(capacity)`
+    /// bytes large and outlive the ring. `capacity` has to be rounded by `round_capacity`.
+    pub unsafe fn init(memory: *mut u8, capacity: usize, magic: [u8; 8], type_fingerprint: u64) -> Ring {
+        let header = memory as *mut RingHeader;
+        (*header).magic = magic;
+        (*header).capacity = capacity as u64;
+        (*header).type_fingerprint = type_fingerprint;
+        Self::attach(memory)
+    }
+
+    /// Use a ring previously set up with `init` in `memory`, possibly by another process
+    ///
+    /// # Safety
+    /// `memory` has to contain a ring set up by `init` and outlive the returned ring
+    pub unsafe fn attach(memory: *mut u8) -> Ring {
+        let header = memory as *const RingHeader;
+        Ring {
+            header,
+            records: memory.add(mem::size_of::<RingHeader>()),
+            capacity: (*header).capacity as usize,
+            single_sender: false,
+        }
+    }
+
+    /// Promise that only one thread ever sends, making reservations cheaper
+    ///
+    /// # Safety
+    /// No other thread or process may send into this ring
+    pub unsafe fn with_single_sender(mut self) -> Ring {
+        self.single_sender = true;
+        self
+    }
+
+    /// The header of the ring
+    pub fn header(&self) -> &RingHeader {
+        unsafe { &*self.header }
+    }
+
+    /// Size of the largest message that always fits into the ring
+    pub fn max_message_size(&self) -> usize {
+        self.capacity / 2 - RECORD_HEADER
+    }
+
+    fn record_length(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*(self.records.add(offset) as *const AtomicU64) }
+    }
+
+    /// The padding needed in front of a record of `length` bytes reserved at `tail`
+    fn padding_before(&self, tail: u64, length: usize) -> usize {
+        let offset = (tail % self.capacity as u64) as usize;
+        if offset + length > self.capacity {
+            self.capacity - offset
+        } else {
+            0
+        }
+    }
+
+    fn has_space_for(&self, length: usize) -> bool {
+        let header = self.header();
+        // the head never passes the tail, so load it first
+        let head = header.head.0.load(Ordering::Acquire);
+        let tail = header.tail.0.load(Ordering::Acquire);
+        tail + (self.padding_before(tail, length) + length) as u64 - head <= self.capacity as u64
+    }
+
+    /// Reserve a record of `length` bytes, returning its offset
+    fn try_reserve(&self, length: usize) -> Option<usize> {
+        let header = self.header();
+        loop {
+            let head = header.head.0.load(Ordering::Acquire);
+            let tail = header.tail.0.load(Ordering::Acquire);
+            let padding = self.padding_before(tail, length);
+            let new_tail = tail + (padding + length) as u64;
+            if new_tail - head > self.capacity as u64 {
+                return None;
+            }
+            let reserved = if self.single_sender {
+                header.tail.0.store(new_tail, Ordering::Release);
+                true
+            } else {
+                header
+                    .tail
+                    .0
+                    .compare_exchange_weak(tail, new_tail, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+            };
+            if reserved {
+                let offset = (tail % self.capacity as u64) as usize;
+                if padding > 0 {
+                    self.record_length(offset)
+                        .store(padding as u64 | PADDING_FLAG, Ordering::Release);
+                    return Some(0);
+                }
+                return Some(offset);
+            }
+        }
+    }
+
+    fn assert_fits<T: Compact>(&self, length: usize) {
+        assert!(
+            T::max_align() <= RECORD_ALIGN,
+            "compact channels only support types aligned to at most {} bytes",
+            RECORD_ALIGN
+        );
+        assert!(
+            length - RECORD_HEADER <= self.max_message_size(),
+            "message of {} bytes doesn't fit into a ring of {} bytes",
+            length - RECORD_HEADER,
+            self.capacity
+        );
+    }
+
+    /// Compact `message` into the ring, or give it back if the ring is full.
+    ///
+    /// Panics if the message is larger than `max_message_size`.
+    pub fn try_send<T: Compact>(&self, mut message: T) -> Result<(), T> {
+        let length = RECORD_HEADER + padded(message.total_size_bytes());
+        self.assert_fits::<T>(length);
+        let offset = match self.try_reserve(length) {
+            Some(offset) => offset,
+            None => return Err(message),
+        };
+
+        unsafe {
+            let dest = self.records.add(offset + RECORD_HEADER) as *mut T;
+            Compact::compact_behind(&mut message, dest);
+            mem::forget(message);
+        }
+        self.record_length(offset)
+            .store(length as u64, Ordering::Release);
+        self.header().messages.0.notify();
+        Ok(())
+    }
+
+    /// Compact `message` into the ring, waiting for the receiver to make space if it is full.
+    ///
+    /// Panics if the message is larger than `max_message_size`.
+    pub fn send<T: Compact>(&self, message: T) {
+        let length = RECORD_HEADER + padded(message.total_size_bytes());
+        let mut message = message;
+        loop {
+            match self.try_send(message) {
+                Ok(()) => return,
+                Err(returned) => message = returned,
+            }
+            self.header()
+                .space
+                .0
+                .wait_until(|| self.has_space_for(length));
+        }
+    }
+
+    /// Offset and length of the oldest committed record, skipping padding
+    fn next_record(&self) -> Option<(usize, usize)> {
+        loop {
+            let head = self.header().head.0.load(Ordering::Acquire);
+            let offset = (head % self.capacity as u64) as usize;
+            let length = self.record_length(offset).load(Ordering::Acquire);
+            if length == 0 {
+                return None;
+            } else if length & PADDING_FLAG != 0 {
+                self.release(offset, (length & !PADDING_FLAG) as usize);
+            } else {
+                return Some((offset, length as usize));
+            }
+        }
+    }
+
+    fn release(&self, offset: usize, length: usize) {
+        unsafe { ptr::write_bytes(self.records.add(offset), 0, length) };
+        self.header()
+            .head
+            .0
+            .fetch_add(length as u64, Ordering::AcqRel);
+        self.header().space.0.notify();
+    }
+
+    /// Receive the oldest message, if there is one
+    ///
+    /// # Safety
+    /// There may only be one receiver, holding at most one message at a time,
+    /// and the ring has to contain messages of type `T`
+    pub unsafe fn try_recv<T: Compact>(&self) -> Option<RingMessage<'_, T>> {
+        self.next_record().map(|(offset, length)| RingMessage {
+            ring: self,
+            offset,
+            length,
+            _message: PhantomData,
+        })
+    }
+
+    /// Receive the oldest message, waiting for one if the ring is empty,
+    /// unless `stopped` becomes true while waiting, see `notify_receiver`
+    ///
+    /// # Safety
+    /// See `try_recv`
+    pub unsafe fn recv_unless<T: Compact, F: Fn() -> bool>(
+        &self,
+        stopped: F,
+    ) -> Option<RingMessage<'_, T>> {
+        self.header()
+            .messages
+            .0
+            .wait_until(|| self.next_record().is_some() || stopped());
+        self.try_recv()
+    }
+
+    /// Wake up the receiver, for example to let it notice that it was stopped
+    pub fn notify_receiver(&self) {
+        self.header().messages.0.notify();
+    }
+}
+
+/// A message received from a compact channel, stored in-place in its ring.
+/// It is released, making space for new messages, when this is dropped.
+pub struct RingMessage<'a, T: Compact> {
+    ring: &'a Ring,
+    offset: usize,
+    length: usize,
+    _message: PhantomData<T>,
+}
+
+impl<'a, T: Compact> RingMessage<'a, T> {
+    fn ptr(&self) -> *mut T {
+        unsafe { self.ring.records.add(self.offset + RECORD_HEADER) as *mut T }
+    }
+
+    /// Decompact the message out of the ring and release it
+    pub fn into_inner(self) -> T {
+        let value = unsafe { Compact::decompact(self.ptr()) };
+        self.ring.release(self.offset, self.length);
+        mem::forget(self);
+        value
+    }
+}
+
+impl<'a, T: Compact> Deref for RingMessage<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl<'a, T: Compact> Drop for RingMessage<'a, T> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.ptr()) };
+        self.ring.release(self.offset, self.length);
+    }
+}
+
+impl<'a, T: Compact + ::std::fmt::Debug> ::std::fmt::Debug for RingMessage<'a, T> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
@@ -0,0 +1,196 @@
+use super::compact::{check_handles_can_leave_process, Compact};
+use super::ring::{Ring, RingHeader, RingMessage, RECORD_ALIGN};
+use super::snapshot::SnapshotError;
+use memmap2::MmapMut;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::path::Path;
+
+const MAGIC: [u8; 8] = *b"CMPCTCHN";
+
+/// A bounded channel of compact messages of type `T` between processes, backed by
+/// a memory-mapped file, ideally on a tmpfs like `/dev/shm`.
+///
+/// Senders compact messages directly into a ring buffer in the shared mapping and
+/// the receiver reads them in-place, without any copying or deserialization.
+/// One process creates the channel and the others open it using the same path.
+///
+/// Any number of processes and threads may send, but only one may receive at a time.
+/// Blocking operations wait on a futex in the shared mapping on Linux and poll elsewhere.
+pub struct SharedChannel<T: Compact> {
+    _file: File,
+    _map: MmapMut,
+    ring: Ring,
+    _message: PhantomData<T>,
+}
+
+impl<T: Compact> SharedChannel<T> {
+    /// Create a new, empty channel at `path` that can hold at least `capacity` bytes
+    /// of messages, replacing any existing file
+    pub fn create<P: AsRef<Path>>(path: P, capacity: usize) -> io::Result<Self> {
+        Self::assert_alignment();
+        check_handles_can_leave_process::<T>()?;
+        let capacity = Ring::round_capacity(capacity);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(Ring::memory_size(capacity) as u64)?;
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        let ring = unsafe { Ring::init(map.as_mut_ptr(), capacity, MAGIC, T::type_fingerprint()) };
+
+        Ok(SharedChannel {
+            _file: file,
+            _map: map,
+            ring,
+            _message: PhantomData,
+        })
+    }
+
+    /// Open an existing channel at `path`, refusing channels for a different type
+    ///
+    /// # Safety
+    /// Messages are used in-place, so the file may only be used by other
+    /// `SharedChannel`s. The checks protect against accidental mismatches,
+    /// not against malicious processes.
+    pub unsafe fn open<P: AsRef<Path>>(path: P) -> Result<Self, SnapshotError> {
+        Self::assert_alignment();
+        check_handles_can_leave_process::<T>()?;
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut map = MmapMut::map_mut(&file)?;
+
+        if map.len() < mem::size_of::<RingHeader>() {
+            return Err(SnapshotError::Truncated);
+        }
+        let ring = Ring::attach(map.as_mut_ptr());
+        let header = ring.header();
+        if header.magic != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        if header.type_fingerprint != T::type_fingerprint() {
+            return Err(SnapshotError::TypeMismatch {
+                found: header.type_fingerprint,
+                expected: T::type_fingerprint(),
+            });
+        }
+        if map.len() < Ring::memory_size(header.capacity as usize) {
+            return Err(SnapshotError::Truncated);
+        }
+
+        Ok(SharedChannel {
+            _file: file,
+            _map: map,
+            ring,
+            _message: PhantomData,
+        })
+    }
+
+    fn assert_alignment() {
+        assert!(
+            T::max_align() <= RECORD_ALIGN,
+            "SharedChannel only supports types aligned to at most {} bytes",
+            RECORD_ALIGN
+        );
+    }
+
+    /// Size of the largest message that can be sent
+    pub fn max_message_size(&self) -> usize {
+        self.ring.max_message_size()
+    }
+
+    /// Compact `message` into the channel, or give it back if the channel is full.
+    ///
+    /// Panics if the message is larger than `max_message_size`.
+    pub fn try_send(&self, message: T) -> Result<(), T> {
+        self.ring.try_send(message)
+    }
+
+    /// Compact `message` into the channel, waiting for space if it is full.
+    ///
+    /// Panics if the message is larger than `max_message_size`.
+    pub fn send(&self, message: T) {
+        self.ring.send(message)
+    }
+
+    /// Receive the oldest message in-place, if there is one.
+    /// It is removed from the channel when the returned message is dropped.
+    ///
+    /// Only one process may receive from a channel at a time.
+    pub fn try_recv(&mut self) -> Option<RingMessage<'_, T>> {
+        unsafe { self.ring.try_recv() }
+    }
+
+    /// Receive the oldest message in-place, waiting for one if the channel is empty.
+    /// It is removed from the channel when the returned message is dropped.
+    ///
+    /// Only one process may receive from a channel at a time.
+    pub fn recv(&mut self) -> RingMessage<'_, T> {
+        unsafe { self.ring.recv_unless(|| false) }.expect("only the receiver takes messages")
+    }
+}
+
+#[cfg(test)]
+fn temp_path(name: &str) -> ::std::path::PathBuf {
+    ::std::env::temp_dir().join(format!("shared_channel_{}_{}", name, ::std::process::id()))
+}
+
+#[test]
+fn send_between_mappings() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    type Message = CompactVec<CompactString>;
+    let path = temp_path("send_between_mappings");
+
+    let mut receiver: SharedChannel<Message> = SharedChannel::create(&path, 256).unwrap();
+    assert!(receiver.try_recv().is_none());
+
+    // a small ring exercises wrapping around and waiting for space
+    let sender_path = path.clone();
+    let sending = ::std::thread::spawn(move || {
+        let sender: SharedChannel<Message> = unsafe { SharedChannel::open(&sender_path).unwrap() };
+        for i in 0..1000 {
+            let message: Message = vec![format!("message {}", i).into()].into();
+            sender.send(message);
+        }
+    });
+    for i in 0..1000 {
+        let message = receiver.recv();
+        assert!(message.is_still_compact());
+        assert_eq!(format!("message {}", i), &*message[0]);
+    }
+    sending.join().unwrap();
+    assert!(receiver.try_recv().is_none());
+
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn backpressure_and_mismatches() {
+    use super::compact_vec::CompactVec;
+    let path = temp_path("backpressure_and_mismatches");
+
+    let mut channel: SharedChannel<CompactVec<u32>> = SharedChannel::create(&path, 256).unwrap();
+    let mut sent = 0;
+    while channel.try_send(vec![1, 2, 3].into()).is_ok() {
+        sent += 1;
+    }
+    assert!(sent > 0);
+    let returned = channel.try_send(vec![4].into()).unwrap_err();
+    assert_eq!(&[4], &*returned);
+
+    assert_eq!(&[1, 2, 3], &**channel.try_recv().unwrap());
+    assert!(channel.try_send(vec![4].into()).is_ok());
+    assert_eq!(vec![1, 2, 3], channel.try_recv().unwrap().into_inner().to_vec());
+
+    match unsafe { SharedChannel::<CompactVec<u64>>::open(&path) } {
+        Err(SnapshotError::TypeMismatch { .. }) => {}
+        Err(other) => panic!("expected type mismatch, got {:?}", other),
+        Ok(_) => panic!("expected type mismatch"),
+    }
+
+    ::std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,447 @@
+use super::compact::{check_handles_can_leave_process, Compact};
+use super::compact_box::{CompactBox, Image};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+
+const MAGIC: &[u8; 8] = b"CMPCTSNP";
+
+/// Version of the snapshot format written by this version of the crate
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 2;
+
+/// Size of an encoded `SnapshotHeader` in bytes
+pub const SNAPSHOT_HEADER_SIZE: usize = 48;
+
+/// Set in `SnapshotHeader::flags` if the image uses the layout of the `portable` feature
+pub const SNAPSHOT_FLAG_PORTABLE: u16 = 1;
+
+/// Metadata written in front of every compact image, describing
+/// what it contains and where it was produced.
+///
+/// The header itself is always encoded little-endian.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SnapshotHeader {
+    /// Version of the snapshot format
+    pub format_version: u32,
+    /// Pointer width in bytes of the platform the image was produced on
+    pub pointer_width: u8,
+    /// Was the image produced on a big-endian platform?
+    pub big_endian: bool,
+    /// Combination of `SNAPSHOT_FLAG_*` values
+    pub flags: u16,
+    /// `Compact::type_fingerprint` of the contained type
+    pub type_fingerprint: u64,
+    /// Size of the image (static part + dynamic part)
+    pub total_size: u64,
+    /// Size of the dynamic part of the image
+    pub dynamic_size: u64,
+    /// CRC-32 of the encoded header (with this field zeroed) followed by the image bytes
+    pub checksum: u32,
+    /// `Versioned::VERSION` of the contained type, 0 for unversioned types
+    pub type_version: u32,
+}
+
+/// Why a snapshot could not be loaded
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// Reading the snapshot failed
+    Io(io::Error),
+    /// The data doesn't start with the snapshot magic bytes
+    BadMagic,
+    /// The snapshot was written in a different format version
+    UnsupportedVersion {
+        /// Version of the snapshot
+        found: u32,
+        /// Version this crate can read
+        supported: u32,
+    },
+    /// The snapshot was produced on a platform with a different memory layout
+    IncompatiblePlatform {
+        /// Pointer width and endianness of the snapshot
+        found: (u8, bool),
+        /// Pointer width and endianness of this platform
+        expected: (u8, bool),
+    },
+    /// The snapshot was written with the `portable` layout and this crate is built without,
+    /// or the other way around
+    LayoutMismatch {
+        /// Does the snapshot use the `portable` layout?
+        portable: bool,
+    },
+    /// The snapshot contains a different type
+    TypeMismatch {
+        /// Fingerprint of the type in the snapshot
+        found: u64,
+        /// Fingerprint of the requested type
+        expected: u64,
+    },
+    /// The sizes recorded in the header are inconsistent with the requested type
+    SizeMismatch {
+        /// Total size recorded in the header
+        total_size: u64,
+        /// Dynamic size recorded in the header
+        dynamic_size: u64,
+        /// Static size of the requested type
+        static_size: u64,
+    },
+    /// The image is shorter than recorded in the header
+    Truncated,
+    /// The image is corrupted
+    ChecksumMismatch {
+        /// Checksum of the image as read
+        found: u32,
+        /// Checksum recorded in the header
+        expected: u32,
+    },
+    /// The snapshot contains a version of the type that is not among its known versions
+    UnknownTypeVersion {
+        /// Type version of the snapshot
+        found: u32,
+        /// Current version of the requested type
+        current: u32,
+    },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::Io(ref err) => write!(f, "couldn't read snapshot: {}", err),
+            SnapshotError::BadMagic => write!(f, "not a compact snapshot"),
+            SnapshotError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "snapshot format version {} is not supported (expected {})",
+                found, supported
+            ),
+            SnapshotError::IncompatiblePlatform { found, expected } => write!(
+                f,
+                "snapshot was produced with {}-byte pointers, {} endian, \
+                 but this platform uses {}-byte pointers, {} endian",
+                found.0,
+                endianness_name(found.1),
+                expected.0,
+                endianness_name(expected.1)
+            ),
+            SnapshotError::LayoutMismatch { portable } => write!(
+                f,
+                "snapshot was written {} the portable layout, but this build uses {}",
+                if portable { "with" } else { "without" },
+                if portable { "the native layout" } else { "the portable layout" }
+            ),
+            SnapshotError::TypeMismatch { found, expected } => write!(
+                f,
+                "snapshot contains a different type (fingerprint {:016x}, expected {:016x})",
+                found, expected
+            ),
+            SnapshotError::SizeMismatch {
+                total_size,
+                dynamic_size,
+                static_size,
+            } => write!(
+                f,
+                "snapshot sizes are inconsistent: total {} bytes, dynamic {} bytes, \
+                 but the type has a static size of {} bytes",
+                total_size, dynamic_size, static_size
+            ),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::ChecksumMismatch { found, expected } => write!(
+                f,
+                "snapshot is corrupted (checksum {:08x}, expected {:08x})",
+                found, expected
+            ),
+            SnapshotError::UnknownTypeVersion { found, current } => write!(
+                f,
+                "snapshot contains unknown type version {} (current version is {})",
+                found, current
+            ),
+        }
+    }
+}
+
+impl ::std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> SnapshotError {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            SnapshotError::Truncated
+        } else {
+            SnapshotError::Io(err)
+        }
+    }
+}
+
+fn endianness_name(big_endian: bool) -> &'static str {
+    if big_endian {
+        "big"
+    } else {
+        "little"
+    }
+}
+
+impl SnapshotHeader {
+    /// Header describing an image of `T` on this platform
+    pub fn new<T: Compact>(dynamic_size: usize, checksum: u32) -> SnapshotHeader {
+        SnapshotHeader {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            pointer_width: mem::size_of::<usize>() as u8,
+            big_endian: cfg!(target_endian = "big"),
+            flags: if cfg!(feature = "portable") {
+                SNAPSHOT_FLAG_PORTABLE
+            } else {
+                0
+            },
+            type_fingerprint: T::type_fingerprint(),
+            total_size: (mem::size_of::<T>() + dynamic_size) as u64,
+            dynamic_size: dynamic_size as u64,
+            checksum,
+            type_version: 0,
+        }
+    }
+
+    /// Encode the header into its fixed-size byte representation
+    pub fn encode(&self) -> [u8; SNAPSHOT_HEADER_SIZE] {
+        let mut bytes = [0u8; SNAPSHOT_HEADER_SIZE];
+        bytes[0..8].copy_from_slice(MAGIC);
+        bytes[8..12].copy_from_slice(&self.format_version.to_le_bytes());
+        bytes[12] = self.pointer_width;
+        bytes[13] = self.big_endian as u8;
+        bytes[14..16].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.type_fingerprint.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.total_size.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.dynamic_size.to_le_bytes());
+        bytes[40..44].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes[44..48].copy_from_slice(&self.type_version.to_le_bytes());
+        bytes
+    }
+
+    /// Decode a header, checking only the magic bytes and format version
+    pub fn decode(bytes: &[u8; SNAPSHOT_HEADER_SIZE]) -> Result<SnapshotHeader, SnapshotError> {
+        if &bytes[0..8] != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let mut u16_bytes = [0u8; 2];
+        let mut u32_bytes = [0u8; 4];
+        let mut u64_bytes = [0u8; 8];
+
+        u32_bytes.copy_from_slice(&bytes[8..12]);
+        let format_version = u32::from_le_bytes(u32_bytes);
+        if format_version != SNAPSHOT_FORMAT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion {
+                found: format_version,
+                supported: SNAPSHOT_FORMAT_VERSION,
+            });
+        }
+
+        u16_bytes.copy_from_slice(&bytes[14..16]);
+        let flags = u16::from_le_bytes(u16_bytes);
+        u64_bytes.copy_from_slice(&bytes[16..24]);
+        let type_fingerprint = u64::from_le_bytes(u64_bytes);
+        u64_bytes.copy_from_slice(&bytes[24..32]);
+        let total_size = u64::from_le_bytes(u64_bytes);
+        u64_bytes.copy_from_slice(&bytes[32..40]);
+        let dynamic_size = u64::from_le_bytes(u64_bytes);
+        u32_bytes.copy_from_slice(&bytes[40..44]);
+        let checksum = u32::from_le_bytes(u32_bytes);
+        u32_bytes.copy_from_slice(&bytes[44..48]);
+        let type_version = u32::from_le_bytes(u32_bytes);
+
+        Ok(SnapshotHeader {
+            format_version,
+            pointer_width: bytes[12],
+            big_endian: bytes[13] != 0,
+            flags,
+            type_fingerprint,
+            total_size,
+            dynamic_size,
+            checksum,
+            type_version,
+        })
+    }
+
+    /// CRC-32 of this header, with its checksum zeroed, followed by `image`
+    pub fn compute_checksum(&self, image: &[u8]) -> u32 {
+        let mut header_bytes = self.encode();
+        header_bytes[40..44].copy_from_slice(&[0; 4]);
+        let mut hasher = ::crc32fast::Hasher::new();
+        hasher.update(&header_bytes);
+        hasher.update(image);
+        hasher.finalize()
+    }
+
+    /// Check that the image described by this header can be used as a `T` on this platform
+    pub fn check_compatible<T: Compact>(&self) -> Result<(), SnapshotError> {
+        let expected = SnapshotHeader::new::<T>(0, 0);
+        let portable = self.flags & SNAPSHOT_FLAG_PORTABLE != 0;
+        if portable != (expected.flags & SNAPSHOT_FLAG_PORTABLE != 0) {
+            return Err(SnapshotError::LayoutMismatch { portable });
+        }
+        // the headers in portable images don't depend on the pointer width and the sizes
+        // of the contained types are covered by the type fingerprint, but element bytes
+        // and `Copy` payloads are still stored in native byte order
+        let pointer_width_differs = !portable && self.pointer_width != expected.pointer_width;
+        if pointer_width_differs || self.big_endian != expected.big_endian {
+            return Err(SnapshotError::IncompatiblePlatform {
+                found: (self.pointer_width, self.big_endian),
+                expected: (expected.pointer_width, expected.big_endian),
+            });
+        }
+        if self.type_fingerprint != expected.type_fingerprint {
+            return Err(SnapshotError::TypeMismatch {
+                found: self.type_fingerprint,
+                expected: expected.type_fingerprint,
+            });
+        }
+        if self.total_size.checked_sub(self.dynamic_size) != Some(expected.total_size) {
+            return Err(SnapshotError::SizeMismatch {
+                total_size: self.total_size,
+                dynamic_size: self.dynamic_size,
+                static_size: expected.total_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Write a snapshot of `value` (header followed by its compact image) to `writer`
+pub fn write_snapshot<T: Compact, W: Write>(value: &T, writer: &mut W) -> io::Result<()> {
+    write_snapshot_with_version(value, 0, writer)
+}
+
+/// Like `write_snapshot`, recording `type_version` in the header
+pub fn write_snapshot_with_version<T: Compact, W: Write>(
+    value: &T,
+    type_version: u32,
+    writer: &mut W,
+) -> io::Result<()> {
+    check_handles_can_leave_process::<T>()?;
+    let boxed = CompactBox::new(value.clone());
+    let image_bytes = boxed.as_bytes();
+    let dynamic_size = image_bytes.len() - mem::size_of::<T>();
+    let mut header = SnapshotHeader::new::<T>(dynamic_size, 0);
+    header.type_version = type_version;
+    header.checksum = header.compute_checksum(image_bytes);
+    writer.write_all(&header.encode())?;
+    writer.write_all(image_bytes)
+}
+
+/// Read a snapshot written by `write_snapshot` from `reader`, refusing images
+/// of a different type, format version or platform, and corrupted images.
+///
+/// # Safety
+/// The snapshot has to come from a trusted source. The checks only protect against accidental
+/// mismatches and corruption: a crafted image can contain arbitrary lengths, offsets and
+/// bit patterns, which are then used as a `T`.
+pub unsafe fn read_snapshot<T: Compact, R: Read>(reader: &mut R) -> Result<T, SnapshotError> {
+    let header = read_snapshot_header(reader)?;
+    read_snapshot_image(&header, reader)
+}
+
+/// Read just the header of a snapshot from `reader`
+pub fn read_snapshot_header<R: Read>(reader: &mut R) -> Result<SnapshotHeader, SnapshotError> {
+    let mut header_bytes = [0u8; SNAPSHOT_HEADER_SIZE];
+    reader.read_exact(&mut header_bytes)?;
+    SnapshotHeader::decode(&header_bytes)
+}
+
+/// Read the image described by `header` from `reader`, as a `T`
+///
+/// # Safety
+/// Like `read_snapshot`, the snapshot has to come from a trusted source
+pub unsafe fn read_snapshot_image<T: Compact, R: Read>(
+    header: &SnapshotHeader,
+    reader: &mut R,
+) -> Result<T, SnapshotError> {
+    header.check_compatible::<T>()?;
+    check_handles_can_leave_process::<T>()?;
+
+    let mut image = Image::<T>::new(header.total_size as usize);
+    reader.read_exact(image.bytes_mut())?;
+
+    let checksum = header.compute_checksum(image.bytes());
+    if checksum != header.checksum {
+        return Err(SnapshotError::ChecksumMismatch {
+            found: checksum,
+            expected: header.checksum,
+        });
+    }
+
+    Ok(CompactBox::from_image(image).into_inner())
+}
+
+#[test]
+fn snapshot_roundtrip() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    let value: CompactVec<CompactString> =
+        vec!["hello".to_owned().into(), "world".to_owned().into()].into();
+
+    let mut bytes = Vec::new();
+    write_snapshot(&value, &mut bytes).unwrap();
+    let loaded: CompactVec<CompactString> = unsafe { read_snapshot(&mut &bytes[..]) }.unwrap();
+    assert_eq!(vec!["hello", "world"], loaded.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+}
+
+#[test]
+fn snapshot_refuses_mismatches() {
+    use super::compact_vec::CompactVec;
+    let value: CompactVec<u32> = vec![1, 2, 3].into();
+    let mut bytes = Vec::new();
+    write_snapshot(&value, &mut bytes).unwrap();
+
+    match unsafe { read_snapshot::<CompactVec<u64>, _>(&mut &bytes[..]) } {
+        Err(SnapshotError::TypeMismatch { .. }) => {}
+        other => panic!("expected type mismatch, got {:?}", other),
+    }
+
+    let mut corrupted = bytes.clone();
+    *corrupted.last_mut().unwrap() ^= 0xff;
+    match unsafe { read_snapshot::<CompactVec<u32>, _>(&mut &corrupted[..]) } {
+        Err(SnapshotError::ChecksumMismatch { .. }) => {}
+        other => panic!("expected checksum mismatch, got {:?}", other),
+    }
+
+    match unsafe { read_snapshot::<CompactVec<u32>, _>(&mut &bytes[..bytes.len() - 1]) } {
+        Err(SnapshotError::Truncated) => {}
+        other => panic!("expected truncation, got {:?}", other),
+    }
+
+    let mut other_layout = bytes.clone();
+    other_layout[14] ^= SNAPSHOT_FLAG_PORTABLE as u8;
+    match unsafe { read_snapshot::<CompactVec<u32>, _>(&mut &other_layout[..]) } {
+        Err(SnapshotError::LayoutMismatch { .. }) => {}
+        other => panic!("expected layout mismatch, got {:?}", other),
+    }
+
+    let mut other_endianness = bytes.clone();
+    other_endianness[13] ^= 1;
+    match unsafe { read_snapshot::<CompactVec<u32>, _>(&mut &other_endianness[..]) } {
+        Err(SnapshotError::IncompatiblePlatform { .. }) => {}
+        other => panic!("expected incompatible platform, got {:?}", other),
+    }
+
+    let mut future = bytes.clone();
+    future[8] = 99;
+    let err = unsafe { read_snapshot::<CompactVec<u32>, _>(&mut &future[..]) }.unwrap_err();
+    assert_eq!(
+        "snapshot format version 99 is not supported (expected 2)",
+        err.to_string()
+    );
+}
+
+#[test]
+fn checksum_covers_the_header() {
+    use super::compact_vec::CompactVec;
+    let value: CompactVec<u32> = vec![1, 2, 3].into();
+    let mut bytes = Vec::new();
+    write_snapshot(&value, &mut bytes).unwrap();
+
+    // consistently grow the recorded sizes, which would read beyond the vector
+    let mut grown = bytes.clone();
+    for size_field in &[24, 32] {
+        grown[*size_field] += 8;
+    }
+    grown.extend_from_slice(&[0xff; 8]);
+    match unsafe { read_snapshot::<CompactVec<u32>, _>(&mut &grown[..]) } {
+        Err(SnapshotError::ChecksumMismatch { .. }) => {}
+        other => panic!("expected checksum mismatch, got {:?}", other),
+    }
+}
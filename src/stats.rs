@@ -0,0 +1,98 @@
+#[cfg(feature = "stats")]
+use std::collections::HashMap;
+#[cfg(feature = "stats")]
+use std::sync::Mutex;
+
+/// Storage statistics of one container type, like `CompactVec<u32>`
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct TypeStats {
+    /// Times a container outgrew compact storage and spilled onto the heap
+    pub spills: u64,
+    /// Times a container was decompacted out of compact storage
+    pub decompactions: u64,
+    /// Times a container was moved into compact storage by `Compact::compact`
+    pub recompactions: u64,
+    /// Bytes currently allocated as free heap storage
+    pub heap_bytes: usize,
+    /// Bytes currently used as compact storage, as far as moves into and out of
+    /// compact storage are tracked (images copied byte-wise, like snapshots, are not)
+    pub compact_bytes: usize,
+}
+
+#[cfg(feature = "stats")]
+lazy_static! {
+    static ref STATS: Mutex<HashMap<&'static str, TypeStats>> = Mutex::new(HashMap::new());
+}
+
+/// Update the statistics of the container type `C`
+#[cfg(feature = "stats")]
+pub fn record<C: ?Sized>(update: impl FnOnce(&mut TypeStats)) {
+    let mut stats = STATS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    update(stats.entry(::std::any::type_name::<C>()).or_default());
+}
+
+#[cfg(not(feature = "stats"))]
+#[inline(always)]
+pub fn record<C: ?Sized>(_update: impl FnOnce(&mut TypeStats)) {}
+
+/// The statistics of all container types used so far, by type name
+#[cfg(feature = "stats")]
+pub fn stats_snapshot() -> HashMap<&'static str, TypeStats> {
+    STATS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+}
+
+/// Reset the counters of all container types, but keep the current byte counts
+#[cfg(feature = "stats")]
+pub fn reset_stats() {
+    let mut stats = STATS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    for type_stats in stats.values_mut() {
+        *type_stats = TypeStats {
+            heap_bytes: type_stats.heap_bytes,
+            compact_bytes: type_stats.compact_bytes,
+            ..TypeStats::default()
+        };
+    }
+}
+
+#[test]
+#[cfg(feature = "stats")]
+fn records_spills_and_storage() {
+    use super::compact_box::CompactBox;
+    use super::compact_vec::CompactVec;
+    #[derive(Clone, Copy)]
+    struct Sample(u32);
+    let stats = || {
+        let name = ::std::any::type_name::<CompactVec<Sample>>();
+        stats_snapshot().get(name).cloned().unwrap_or_default()
+    };
+
+    let mut list: CompactVec<Sample> = CompactVec::with_capacity(4);
+    list.push(Sample(0));
+    assert_eq!(16, stats().heap_bytes);
+
+    let mut boxed = CompactBox::new(list);
+    let expected = TypeStats { recompactions: 1, compact_bytes: 16, ..TypeStats::default() };
+    assert_eq!(expected, stats());
+
+    for i in 1..5 {
+        boxed.push(Sample(i));
+    }
+    let expected = TypeStats { spills: 1, recompactions: 1, heap_bytes: 32, ..TypeStats::default() };
+    assert_eq!(expected, stats());
+
+    let boxed = CompactBox::new(boxed.into_inner());
+    let decompacted = boxed.into_inner();
+    assert_eq!(4, decompacted[4].0);
+    let expected = TypeStats {
+        spills: 1,
+        decompactions: 1,
+        recompactions: 2,
+        heap_bytes: 20,
+        compact_bytes: 0,
+    };
+    assert_eq!(expected, stats());
+
+    drop(decompacted);
+    reset_stats();
+    assert_eq!(TypeStats::default(), stats());
+}
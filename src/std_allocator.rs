@@ -0,0 +1,173 @@
+use super::allocator::InstanceAllocator;
+use super::simple_allocator_trait::DefaultHeap;
+use std::alloc::{self, AllocError, Layout};
+use std::marker::PhantomData;
+use std::ptr::NonNull;
+
+/// An `InstanceAllocator` that allocates using a `std::alloc::Allocator`, which is the handle,
+/// so that compact containers can share an allocator with std collections:
+/// `CVec<T, StdAlloc<A>>` allocates like `Vec<T, A>` and converts to and from it.
+pub struct StdAlloc<A>(PhantomData<fn() -> A>);
+
+impl<A: alloc::Allocator + Clone> InstanceAllocator for StdAlloc<A> {
+    type Handle = A;
+
+    fn allocate_in<T>(handle: &A, capacity: usize) -> *mut T {
+        let layout = Layout::array::<T>(capacity).expect("capacity overflow");
+        if layout.size() == 0 {
+            return NonNull::dangling().as_ptr();
+        }
+        match handle.allocate(layout) {
+            Ok(ptr) => ptr.as_ptr() as *mut T,
+            Err(AllocError) => alloc::handle_alloc_error(layout),
+        }
+    }
+
+    unsafe fn deallocate_in<T>(handle: &A, ptr: *mut T, capacity: usize) {
+        let layout = Layout::array::<T>(capacity).expect("capacity overflow");
+        if layout.size() != 0 {
+            handle.deallocate(NonNull::new_unchecked(ptr as *mut u8), layout);
+        }
+    }
+}
+
+/// A `std::alloc::Allocator` that allocates using the `InstanceAllocator` `A`
+/// (including every `simple_allocator_trait::Allocator`), through its handle,
+/// so that std collections can share an allocator with compact containers,
+/// like `Vec<T, AsStdAllocator<ArenaHeap>>`.
+///
+/// Std collections using it can be sent to other threads like with any other allocator,
+/// so `A` has to keep allocations valid until they are deallocated, on any thread,
+/// which the allocators of this crate do.
+///
+/// Supports alignments of up to 4096 bytes.
+pub struct AsStdAllocator<A: InstanceAllocator = DefaultHeap>(pub A::Handle);
+
+impl<A: InstanceAllocator> Clone for AsStdAllocator<A> {
+    fn clone(&self) -> Self {
+        AsStdAllocator(self.0.clone())
+    }
+}
+
+impl<A: InstanceAllocator> Default for AsStdAllocator<A>
+where
+    A::Handle: Default,
+{
+    fn default() -> Self {
+        AsStdAllocator(A::Handle::default())
+    }
+}
+
+/// `A` can only allocate typed arrays, so allocations use arrays of units of the requested alignment
+macro_rules! aligned_units {
+    ($($unit:ident: $align:literal),*) => {
+        $(
+            #[repr(align($align))]
+            #[allow(dead_code)]
+            struct $unit([u8; $align]);
+        )*
+
+        fn allocate_units<A: InstanceAllocator>(handle: &A::Handle, layout: Layout) -> Option<*mut u8> {
+            match layout.align() {
+                $($align => Some(A::allocate_in::<$unit>(handle, units(layout)) as *mut u8),)*
+                _ => None,
+            }
+        }
+
+        unsafe fn deallocate_units<A: InstanceAllocator>(handle: &A::Handle, ptr: *mut u8, layout: Layout) {
+            match layout.align() {
+                $($align => A::deallocate_in::<$unit>(handle, ptr as *mut $unit, units(layout)),)*
+                _ => unreachable!("never allocated"),
+            }
+        }
+    };
+}
+
+aligned_units!(
+    Align1: 1, Align2: 2, Align4: 4, Align8: 8, Align16: 16, Align32: 32, Align64: 64,
+    Align128: 128, Align256: 256, Align512: 512, Align1024: 1024, Align2048: 2048, Align4096: 4096
+);
+
+/// Number of alignment-sized units that fit `layout`
+fn units(layout: Layout) -> usize {
+    layout.size().div_ceil(layout.align())
+}
+
+unsafe impl<A: InstanceAllocator> alloc::Allocator for AsStdAllocator<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = if layout.size() == 0 {
+            layout.align() as *mut u8
+        } else {
+            allocate_units::<A>(&self.0, layout).ok_or(AllocError)?
+        };
+        NonNull::new(ptr)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+            .ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            deallocate_units::<A>(&self.0, ptr.as_ptr(), layout);
+        }
+    }
+}
+
+#[test]
+fn vec_conversions_keep_free_storage() {
+    use super::compact_vec::CompactVec;
+    use std::alloc::Global;
+
+    let mut vec: Vec<u32> = Vec::with_capacity(10);
+    vec.extend(0..7);
+    let storage = vec.as_ptr();
+
+    let mut list: CompactVec<u32, StdAlloc<Global>> = CompactVec::from_std_vec(vec);
+    assert_eq!(storage, list.as_ptr());
+    assert_eq!(10, list.capacity());
+    list.push(7);
+
+    let vec = list.into_std_vec();
+    assert_eq!(storage, vec.as_ptr());
+    assert_eq!((0..8).collect::<Vec<_>>(), vec);
+
+    let empty: CompactVec<u32, StdAlloc<Global>> = CompactVec::from_std_vec(Vec::new());
+    assert_eq!(0, empty.capacity());
+    assert_eq!(0, empty.into_std_vec().capacity());
+
+    let units: CompactVec<(), StdAlloc<Global>> = CompactVec::from_std_vec(vec![(); 5]);
+    assert_eq!(5, units.into_std_vec().len());
+}
+
+#[test]
+fn std_collections_use_compact_allocators() {
+    use super::arena::ArenaHeap;
+    use super::pool::PoolHeap;
+
+    let mut pooled: Vec<u64, AsStdAllocator<PoolHeap>> = Vec::new_in(AsStdAllocator::default());
+    pooled.extend(0..100);
+    let free = PoolHeap::free_bytes();
+    drop(pooled);
+    assert_eq!(free + 1024, PoolHeap::free_bytes());
+
+    #[repr(align(256))]
+    struct Page(u8);
+    let mut pages: Vec<Page, AsStdAllocator<ArenaHeap>> = Vec::with_capacity_in(3, AsStdAllocator::default());
+    pages.push(Page(1));
+    assert_eq!(0, pages.as_ptr() as usize % 256);
+    assert_eq!(1, pages[0].0);
+    assert!(ArenaHeap::allocated_bytes() >= 3 * 256);
+}
+
+#[test]
+fn std_collections_outlive_the_allocating_thread() {
+    use super::arena::ArenaHeap;
+
+    let list = ::std::thread::spawn(|| {
+        let mut list: Vec<u64, AsStdAllocator<ArenaHeap>> = Vec::new_in(AsStdAllocator::default());
+        list.extend(0..1000);
+        list
+    })
+    .join()
+    .unwrap();
+    assert_eq!((0..1000).collect::<Vec<_>>(), &list[..]);
+}
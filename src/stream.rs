@@ -0,0 +1,237 @@
+use super::compact::{check_handles_can_leave_process, write_static_compact, Compact};
+use super::compact_box::CompactBox;
+use std::io::{self, Read, Write};
+use std::mem;
+
+/// Size of the length prefix in front of every streamed compact image
+pub const LENGTH_PREFIX_SIZE: usize = 8;
+
+/// Largest image `read_compact` accepts, see `read_compact_limited` for other limits
+pub const DEFAULT_MAX_IMAGE_SIZE: usize = 1 << 30;
+
+/// Marks types whose compact images are only read from trusted sources, like streams written
+/// by the same program, which makes reading them with `read_compact` or `as_image` safe.
+///
+/// # Safety
+/// Images are used as-is, without validating the lengths, offsets and bit patterns in them.
+/// Every image read as this type has to be written by `write_compact` (or `as_image::serialize`)
+/// with the same type, on a platform with the same layout.
+pub unsafe trait TrustedImage: Compact {}
+
+/// Write a compact image of `value` to `writer`, prefixed with its length as a
+/// little-endian `u64`.
+///
+/// If `T::streams_compact()`, the image is streamed directly from `value`,
+/// otherwise a compacted clone is buffered first.
+pub fn write_compact<T: Compact, W: Write>(value: &T, mut writer: W) -> io::Result<()> {
+    check_handles_can_leave_process::<T>()?;
+    let total_size = value.total_size_bytes();
+    writer.write_all(&(total_size as u64).to_le_bytes())?;
+
+    if T::streams_compact() {
+        let dynamic_at = mem::size_of::<T>();
+        write_static_compact(value, 0, dynamic_at, &mut writer)?;
+        value.write_dynamic_compact(dynamic_at, &mut writer)
+    } else {
+        writer.write_all(CompactBox::new(value.clone()).as_bytes())
+    }
+}
+
+/// Read a length-prefixed compact image written by `write_compact` from `reader`,
+/// refusing images larger than `DEFAULT_MAX_IMAGE_SIZE`.
+///
+/// The image is used as-is, see `TrustedImage`. For checked transports, see `CompactReceiver`.
+pub fn read_compact<R: Read, T: TrustedImage>(reader: R) -> io::Result<CompactBox<T>> {
+    read_compact_limited(reader, DEFAULT_MAX_IMAGE_SIZE)
+}
+
+/// Like `read_compact`, refusing images larger than `max_size` bytes
+pub fn read_compact_limited<R: Read, T: TrustedImage>(
+    mut reader: R,
+    max_size: usize,
+) -> io::Result<CompactBox<T>> {
+    check_handles_can_leave_process::<T>()?;
+    let mut length_bytes = [0u8; LENGTH_PREFIX_SIZE];
+    reader.read_exact(&mut length_bytes)?;
+    let total_size = u64::from_le_bytes(length_bytes);
+    if total_size > max_size as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "compact image of {} bytes exceeds the limit of {} bytes",
+                total_size, max_size
+            ),
+        ));
+    }
+    unsafe { CompactBox::read_from(&mut reader, total_size as usize) }
+}
+
+#[cfg(test)]
+fn stream_to_vec<T: Compact>(value: &T) -> Vec<u8> {
+    let mut streamed = Vec::new();
+    write_compact(value, &mut streamed).unwrap();
+    assert_eq!(LENGTH_PREFIX_SIZE + value.total_size_bytes(), streamed.len());
+    streamed
+}
+
+#[cfg(test)]
+use super::compact_str::CompactString;
+
+#[cfg(test)]
+type Name = super::compact_option::CompactOption<super::compact_ptr::CompactPtr<CompactString>>;
+#[cfg(test)]
+type NestedType = super::compact_dict::CompactDict<u64, super::compact_vec::CompactVec<Name>>;
+#[cfg(test)]
+type StreamedMap = super::compact_hash_map::OpenAddressingMap<u32, CompactString>;
+#[cfg(test)]
+type StreamedTree = super::compact_tree::CompactTree<CompactString>;
+
+#[cfg(test)]
+unsafe impl TrustedImage for NestedType {}
+#[cfg(test)]
+unsafe impl TrustedImage for StreamedMap {}
+#[cfg(test)]
+unsafe impl TrustedImage for StreamedTree {}
+
+#[cfg(test)]
+#[derive(Clone, Copy)]
+#[repr(align(64))]
+struct Align64(u64);
+#[cfg(test)]
+type OverAligned = super::compact_vec::CompactVec<super::compact_ptr::CompactPtr<Align64>>;
+#[cfg(test)]
+unsafe impl TrustedImage for OverAligned {}
+
+#[test]
+fn streams_nested_containers() {
+    use super::compact_dict::CompactDict;
+    use super::compact_option::CompactOption;
+    use super::compact_ptr::CompactPtr;
+    use super::compact_vec::CompactVec;
+
+    let mut value: NestedType = CompactDict::new();
+    let mut names = CompactVec::with_capacity(4);
+    names.push(CompactOption(Some(CompactPtr::new("first".to_owned().into()))));
+    names.push(CompactOption(None));
+    names.push(CompactOption(Some(CompactPtr::new("third".to_owned().into()))));
+    value.insert(7, names);
+    assert!(NestedType::streams_compact());
+
+    let streamed = stream_to_vec(&value);
+
+    let read: CompactBox<NestedType> = read_compact(&streamed[..]).unwrap();
+    assert!(read.is_still_compact());
+    let names = read.get(7).unwrap();
+    assert_eq!("first", &***names[0].as_ref().unwrap());
+    assert!(names[1].is_none());
+    assert_eq!("third", &***names[2].as_ref().unwrap());
+}
+
+#[test]
+fn streams_maps_and_trees() {
+    let mut map = StreamedMap::new();
+    for i in 0..20 {
+        map.insert(i, format!("value {}", i).into());
+    }
+    map.remove(3);
+    let streamed = stream_to_vec(&map);
+    let read: CompactBox<StreamedMap> = read_compact(&streamed[..]).unwrap();
+    assert_eq!(19, read.len());
+    assert_eq!("value 19", &**read.get(19).unwrap());
+    assert!(read.get(3).is_none());
+
+    let mut tree = StreamedTree::new();
+    let root = tree.push_root("root".to_owned().into());
+    tree.push_child(root, "child".to_owned().into());
+    let streamed = stream_to_vec(&tree);
+    let read: CompactBox<StreamedTree> = read_compact(&streamed[..]).unwrap();
+    let names = read
+        .depth_first()
+        .map(|(_, name)| name.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(vec!["root", "child"], names);
+}
+
+#[test]
+fn streams_over_aligned_values() {
+    use super::compact_ptr::CompactPtr;
+
+    let value: OverAligned = (1..4).map(|i| CompactPtr::new(Align64(i))).collect();
+    assert_eq!(64, OverAligned::max_align());
+    let streamed = stream_to_vec(&value);
+    let read: CompactBox<OverAligned> = read_compact(&streamed[..]).unwrap();
+    for (i, value) in read.iter().enumerate() {
+        assert_eq!(0, &**value as *const Align64 as usize % 64);
+        assert_eq!(i as u64 + 1, value.0);
+    }
+    // compacting in memory lays out the image the same way
+    assert_eq!(&streamed[LENGTH_PREFIX_SIZE..], CompactBox::new(value).as_bytes());
+}
+
+#[test]
+fn refuses_images_over_the_limit() {
+    let streamed = stream_to_vec(&StreamedMap::new());
+    let result: io::Result<CompactBox<StreamedMap>> = read_compact_limited(&streamed[..], 16);
+    assert_eq!(Some(io::ErrorKind::InvalidData), result.err().map(|err| err.kind()));
+
+    let mut huge = streamed.clone();
+    huge[..LENGTH_PREFIX_SIZE].copy_from_slice(&u64::MAX.to_le_bytes());
+    let result: io::Result<CompactBox<StreamedMap>> = read_compact(&huge[..]);
+    assert_eq!(Some(io::ErrorKind::InvalidData), result.err().map(|err| err.kind()));
+}
+
+/// A `Compact` type implemented without streaming support
+#[cfg(test)]
+#[derive(Clone)]
+struct Labelled(CompactString, super::compact_vec::CompactVec<u32>);
+
+#[cfg(test)]
+impl Compact for Labelled {
+    fn is_still_compact(&self) -> bool {
+        self.0.is_still_compact() && self.1.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        self.0.dynamic_size_bytes() + self.1.dynamic_size_bytes()
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let offset = (*source).0.dynamic_size_bytes();
+        Compact::compact(&mut (*source).0, &mut (*dest).0, new_dynamic_part);
+        Compact::compact(&mut (*source).1, &mut (*dest).1, new_dynamic_part.add(offset));
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        Labelled(Compact::decompact(&(*source).0), Compact::decompact(&(*source).1))
+    }
+
+    fn type_fingerprint() -> u64 {
+        let fields = [
+            CompactString::type_fingerprint(),
+            super::compact_vec::CompactVec::<u32>::type_fingerprint(),
+        ];
+        super::compact::structural_fingerprint::<Self>("Labelled", &fields)
+    }
+}
+
+#[test]
+fn streams_types_without_streaming_support() {
+    use super::compact_vec::CompactVec;
+    let mut numbers = CompactVec::with_capacity(8);
+    numbers.extend_from_copy_slice(&[1u32, 2, 3]);
+    let mut list: CompactVec<Labelled> = CompactVec::with_capacity(3);
+    list.push(Labelled("labelled".to_owned().into(), numbers));
+    assert!(!CompactVec::<Labelled>::streams_compact());
+
+    // items of containers are streamed one by one, using the buffering defaults
+    let mut streamed = Vec::new();
+    let dynamic_at = mem::size_of::<CompactVec<Labelled>>();
+    write_static_compact(&list, 0, dynamic_at, &mut streamed).unwrap();
+    list.write_dynamic_compact(dynamic_at, &mut streamed).unwrap();
+    assert_eq!(list.total_size_bytes(), streamed.len());
+
+    let read = unsafe { CompactBox::<CompactVec<Labelled>>::from_bytes(&streamed) };
+    assert!(read.is_still_compact());
+    assert_eq!("labelled", &*read[0].0);
+    assert_eq!(&[1, 2, 3], &read[0].1[..]);
+}
@@ -0,0 +1,181 @@
+use super::compact::{write_static_compact, Compact};
+use super::compact_box::Image;
+use super::inspect::{LayoutNode, Storage};
+use std::fmt::Debug;
+use std::mem;
+
+/// Bytes behind a compact image that compaction must not touch
+const GUARD_SIZE: usize = 64;
+const GUARD_BYTE: u8 = 0xA5;
+
+/// Compact a clone of `value` into an image of exactly its (tight) total size,
+/// followed by guard bytes. Returns the image and its size without the guard bytes.
+fn compact_guarded<T: Compact>(value: &T, tight: bool) -> (Image<T>, usize) {
+    let mut source = value.clone();
+    let size = if tight { source.total_size_bytes_tight() } else { source.total_size_bytes() };
+    let mut image = Image::new(size + GUARD_SIZE);
+    for byte in &mut image.bytes_mut()[size..] {
+        *byte = GUARD_BYTE;
+    }
+    unsafe {
+        if tight {
+            Compact::compact_behind_tight(&mut source, image.ptr());
+        } else {
+            Compact::compact_behind(&mut source, image.ptr());
+        }
+        mem::forget(source);
+    }
+    (image, size)
+}
+
+fn variant(tight: bool) -> &'static str {
+    if tight {
+        "tight compaction"
+    } else {
+        "compaction"
+    }
+}
+
+/// Check that `value` compacts (also tightly), stays compact and decompacts to values equal to it
+pub fn assert_roundtrip<T: Compact + PartialEq + Debug>(value: T) {
+    assert_compact_equivalent(value, T::clone);
+}
+
+/// Like `assert_roundtrip`, for types without `PartialEq`: check that `observe` returns
+/// the same for `value`, its compacted versions and their decompacted versions
+pub fn assert_compact_equivalent<T, R, F>(value: T, observe: F)
+where
+    T: Compact,
+    R: PartialEq + Debug,
+    F: Fn(&T) -> R,
+{
+    let expected = observe(&value);
+    for &tight in &[false, true] {
+        let (image, _) = compact_guarded(&value, tight);
+        let compacted = unsafe { &*image.ptr() };
+        assert!(compacted.is_still_compact(), "not compact after {}", variant(tight));
+        assert_eq!(expected, observe(compacted), "changed by {}", variant(tight));
+
+        let decompacted = unsafe { Compact::decompact(image.ptr()) };
+        assert_eq!(expected, observe(&decompacted), "changed by decompaction after {}", variant(tight));
+    }
+}
+
+/// Check that the size accounting of `value` matches what compaction actually does:
+/// compacting (also tightly) writes nothing beyond the reported total size, all compact parts
+/// lie within the image, the compacted value reports the same sizes as the compacted clone
+/// and the same tight sizes as `value`, and if `T` streams compact
+/// images, streaming writes exactly the reported total size.
+///
+/// The layout is checked using `Compact::inspect_layout`, so custom containers should implement it.
+pub fn assert_size_accounting<T: Compact>(value: &T) {
+    for &tight in &[false, true] {
+        let (image, size) = compact_guarded(value, tight);
+        if let Some(offset) = image.bytes()[size..].iter().position(|&byte| byte != GUARD_BYTE) {
+            panic!(
+                "{} wrote beyond the total size of {} bytes, at byte {}",
+                variant(tight),
+                size,
+                size + offset
+            );
+        }
+
+        let compacted = unsafe { &*image.ptr() };
+        assert_within(&compacted.inspect_layout(), image.ptr() as usize, size, tight);
+        assert_eq!(
+            size - mem::size_of::<T>(),
+            compacted.dynamic_size_bytes(),
+            "dynamic size changed by {}",
+            variant(tight)
+        );
+        assert_eq!(
+            value.dynamic_size_bytes_tight(),
+            compacted.dynamic_size_bytes_tight(),
+            "tight dynamic size changed by {}",
+            variant(tight)
+        );
+    }
+
+    if T::streams_compact() {
+        let mut streamed = Vec::new();
+        let dynamic_at = mem::size_of::<T>();
+        write_static_compact(value, 0, dynamic_at, &mut streamed).expect("writing to a Vec failed");
+        value.write_dynamic_compact(dynamic_at, &mut streamed).expect("writing to a Vec failed");
+        assert_eq!(value.total_size_bytes(), streamed.len(), "streamed size differs from total size");
+    }
+}
+
+fn assert_within(layout: &LayoutNode, start: usize, size: usize, tight: bool) {
+    if let Storage::Compact(address) = layout.storage {
+        assert!(
+            address >= start && address + layout.dynamic_size <= start + size,
+            "dynamic part of {} at offset {} with {} bytes lies outside the image of {} bytes after {}",
+            layout.type_name,
+            address as isize - start as isize,
+            layout.dynamic_size,
+            size,
+            variant(tight)
+        );
+    }
+    for child in &layout.children {
+        assert_within(child, start, size, tight);
+    }
+}
+
+#[cfg(test)]
+#[derive(Clone)]
+struct Underreporting(super::compact_vec::CompactVec<u8>);
+
+#[cfg(test)]
+impl Compact for Underreporting {
+    fn is_still_compact(&self) -> bool {
+        self.0.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        self.0.dynamic_size_bytes().saturating_sub(4)
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        Compact::compact(&mut (*source).0, &mut (*dest).0, new_dynamic_part)
+    }
+
+    unsafe fn decompact(source: *const Self) -> Self {
+        Underreporting(Compact::decompact(&(*source).0))
+    }
+
+    fn type_fingerprint() -> u64 {
+        let fields = [super::compact_vec::CompactVec::<u8>::type_fingerprint()];
+        super::compact::structural_fingerprint::<Self>("Underreporting", &fields)
+    }
+}
+
+#[test]
+fn containers_pass_their_own_checks() {
+    use super::compact_str::CompactString;
+    use super::compact_vec::CompactVec;
+    assert_roundtrip((7u32, 3.5f64));
+
+    let mut list: CompactVec<CompactString> = CompactVec::with_capacity(5);
+    list.push("abc".to_owned().into());
+    list.push(CompactString::new());
+    list.push("de".to_owned().into());
+    assert_compact_equivalent(list.clone(), |list| {
+        list.iter().map(|string| (**string).to_owned()).collect::<Vec<_>>()
+    });
+    assert_size_accounting(&list);
+
+    // spare capacity is kept by compaction, but not by tight compaction
+    let mut numbers: CompactVec<u32> = CompactVec::with_capacity(10);
+    numbers.extend_from_copy_slice(&[1, 2, 3]);
+    assert_size_accounting(&numbers);
+    assert_size_accounting(&CompactVec::<CompactVec<u32>>::from(vec![numbers]));
+}
+
+#[test]
+#[should_panic(expected = "wrote beyond the total size")]
+fn detects_underreported_sizes() {
+    let mut list = super::compact_vec::CompactVec::with_capacity(10);
+    list.extend_from_copy_slice(b"0123456789");
+    assert_size_accounting(&Underreporting(list));
+}
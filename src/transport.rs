@@ -0,0 +1,450 @@
+use super::compact::{check_handles_can_leave_process, write_static_compact, Compact};
+use super::compact_box::{CompactBox, Image};
+use super::snapshot::{SnapshotError, SnapshotHeader};
+use super::stream::DEFAULT_MAX_IMAGE_SIZE;
+use std::io::{self, BufWriter, Read, Write};
+use std::marker::PhantomData;
+use std::mem;
+
+const FRAME_MAGIC: &[u8; 4] = b"CMSG";
+/// Magic, flags, pointer width, endianness, type tag, image size and CRC-32 of all these
+const FRAME_HEADER_SIZE: usize = 28;
+const FRAME_HEADER_CHECKSUM_AT: usize = 24;
+/// CRC-32 of the image, sent after it so the image can be streamed
+const FRAME_TRAILER_SIZE: usize = 4;
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Updates a checksum with everything written through it
+struct ChecksumWriter<'a, W: Write + 'a> {
+    inner: &'a mut W,
+    hasher: ::crc32fast::Hasher,
+}
+
+impl<'a, W: Write> Write for ChecksumWriter<'a, W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(bytes)?;
+        self.hasher.update(&bytes[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Sends compacted messages of type `T` as frames over a stream, like a `TcpStream`
+/// or `UnixStream`, to be received by a `CompactReceiver<T>`.
+///
+/// Each frame contains a type tag (`Compact::type_fingerprint`), the layout of the
+/// sending platform, the image size and checksums of this header and of the image.
+pub struct CompactSender<S: Write, T: Compact> {
+    stream: BufWriter<S>,
+    _message: PhantomData<fn(T)>,
+}
+
+impl<S: Write, T: Compact> CompactSender<S, T> {
+    /// Send messages over `stream`
+    pub fn new(stream: S) -> Self {
+        CompactSender {
+            stream: BufWriter::new(stream),
+            _message: PhantomData,
+        }
+    }
+
+    /// Send a compact image of `message`, streaming it if `T::streams_compact()`
+    pub fn send(&mut self, message: &T) -> io::Result<()> {
+        check_handles_can_leave_process::<T>()?;
+        let total_size = message.total_size_bytes();
+        let header = SnapshotHeader::new::<T>(total_size - mem::size_of::<T>(), 0);
+
+        let mut header_bytes = [0u8; FRAME_HEADER_SIZE];
+        header_bytes[0..4].copy_from_slice(FRAME_MAGIC);
+        header_bytes[4..6].copy_from_slice(&header.flags.to_le_bytes());
+        header_bytes[6] = header.pointer_width;
+        header_bytes[7] = header.big_endian as u8;
+        header_bytes[8..16].copy_from_slice(&header.type_fingerprint.to_le_bytes());
+        header_bytes[16..24].copy_from_slice(&(total_size as u64).to_le_bytes());
+        let header_checksum = ::crc32fast::hash(&header_bytes[..FRAME_HEADER_CHECKSUM_AT]);
+        header_bytes[FRAME_HEADER_CHECKSUM_AT..].copy_from_slice(&header_checksum.to_le_bytes());
+        self.stream.write_all(&header_bytes)?;
+
+        let checksum = {
+            let mut writer = ChecksumWriter {
+                inner: &mut self.stream,
+                hasher: ::crc32fast::Hasher::new(),
+            };
+            if T::streams_compact() {
+                let dynamic_at = mem::size_of::<T>();
+                write_static_compact(message, 0, dynamic_at, &mut writer)?;
+                message.write_dynamic_compact(dynamic_at, &mut writer)?;
+            } else {
+                writer.write_all(CompactBox::new(message.clone()).as_bytes())?;
+            }
+            writer.hasher.finalize()
+        };
+
+        self.stream.write_all(&checksum.to_le_bytes())?;
+        self.stream.flush()
+    }
+
+    /// The underlying stream
+    pub fn get_ref(&self) -> &S {
+        self.stream.get_ref()
+    }
+
+    /// The underlying stream, mutably
+    pub fn get_mut(&mut self) -> &mut S {
+        self.stream.get_mut()
+    }
+}
+
+/// Receives compacted messages of type `T` sent by a `CompactSender<T>` from a stream,
+/// refusing messages of a different type or layout, messages larger than a maximum size
+/// and corrupted messages.
+///
+/// Refused messages with an intact frame header are skipped, so receiving continues with
+/// the next message. After a corrupted frame header, the stream can't be followed anymore
+/// and every further receive fails.
+pub struct CompactReceiver<S: Read, T: Compact> {
+    stream: S,
+    /// Bytes of the frames received so far
+    pending: Vec<u8>,
+    /// Bytes of a refused frame that still have to be skipped when they arrive
+    skipping: u64,
+    max_message_size: usize,
+    _message: PhantomData<fn() -> T>,
+}
+
+impl<S: Read, T: Compact> CompactReceiver<S, T> {
+    /// Receive messages from `stream`, of at most `DEFAULT_MAX_IMAGE_SIZE` bytes each
+    ///
+    /// # Safety
+    /// The peer has to be trusted: the checks only protect against accidental mismatches and
+    /// corruption, and received images are used as-is, without validating the lengths, offsets
+    /// and bit patterns in them.
+    pub unsafe fn new(stream: S) -> Self {
+        CompactReceiver {
+            stream,
+            pending: Vec::new(),
+            skipping: 0,
+            max_message_size: DEFAULT_MAX_IMAGE_SIZE,
+            _message: PhantomData,
+        }
+    }
+
+    /// Refuse messages with images larger than `max_size` bytes,
+    /// which also bounds the memory used for receiving
+    pub fn set_max_message_size(&mut self, max_size: usize) {
+        self.max_message_size = max_size;
+    }
+
+    /// Block until the next message is received.
+    ///
+    /// If the stream is non-blocking and no complete message is available,
+    /// this fails with an `io::ErrorKind::WouldBlock` error, see `try_recv`.
+    pub fn recv(&mut self) -> Result<CompactBox<T>, SnapshotError> {
+        loop {
+            if let Some(message) = self.take_message()? {
+                return Ok(message);
+            }
+            self.receive_chunk()?;
+        }
+    }
+
+    /// Receive the next message if it is completely available without blocking.
+    /// Partially received messages are kept until the next call.
+    ///
+    /// The stream has to be set to non-blocking mode, for example using
+    /// `UnixStream::set_nonblocking`, else this blocks like `recv`.
+    pub fn try_recv(&mut self) -> Result<Option<CompactBox<T>>, SnapshotError> {
+        loop {
+            if let Some(message) = self.take_message()? {
+                return Ok(Some(message));
+            }
+            match self.receive_chunk() {
+                Ok(()) => {}
+                Err(SnapshotError::Io(ref err)) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(None)
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn receive_chunk(&mut self) -> Result<(), SnapshotError> {
+        let old_len = self.pending.len();
+        self.pending.resize(old_len + READ_CHUNK_SIZE, 0);
+        let result = self.stream.read(&mut self.pending[old_len..]);
+        self.pending.truncate(old_len + *result.as_ref().unwrap_or(&0));
+        match result {
+            Ok(0) if old_len == 0 => Err(SnapshotError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "stream closed",
+            ))),
+            Ok(0) => Err(SnapshotError::Truncated),
+            Ok(_) => Ok(()),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => Ok(()),
+            Err(err) => Err(SnapshotError::Io(err)),
+        }
+    }
+
+    /// Drop the first `frame_size` bytes, now or once they are received
+    fn skip(&mut self, frame_size: u64) {
+        let available = ::std::cmp::min(frame_size, self.pending.len() as u64);
+        self.pending.drain(..available as usize);
+        self.skipping = frame_size - available;
+    }
+
+    /// Parse and remove the first frame, if it was received completely
+    fn take_message(&mut self) -> Result<Option<CompactBox<T>>, SnapshotError> {
+        if self.skipping > 0 {
+            let skipping = self.skipping;
+            self.skip(skipping);
+            if self.skipping > 0 {
+                return Ok(None);
+            }
+        }
+        if self.pending.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+        if &self.pending[0..4] != FRAME_MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let mut u32_bytes = [0u8; 4];
+        u32_bytes.copy_from_slice(&self.pending[FRAME_HEADER_CHECKSUM_AT..FRAME_HEADER_SIZE]);
+        let expected_checksum = u32::from_le_bytes(u32_bytes);
+        let checksum = ::crc32fast::hash(&self.pending[..FRAME_HEADER_CHECKSUM_AT]);
+        if checksum != expected_checksum {
+            return Err(SnapshotError::ChecksumMismatch {
+                found: checksum,
+                expected: expected_checksum,
+            });
+        }
+
+        let mut u16_bytes = [0u8; 2];
+        let mut u64_bytes = [0u8; 8];
+        u16_bytes.copy_from_slice(&self.pending[4..6]);
+        let flags = u16::from_le_bytes(u16_bytes);
+        u64_bytes.copy_from_slice(&self.pending[8..16]);
+        let type_fingerprint = u64::from_le_bytes(u64_bytes);
+        u64_bytes.copy_from_slice(&self.pending[16..24]);
+        let total_size = u64::from_le_bytes(u64_bytes);
+
+        // reuse the snapshot checks for layout, type and size
+        let mut header = SnapshotHeader::new::<T>(0, 0);
+        header.flags = flags;
+        header.pointer_width = self.pending[6];
+        header.big_endian = self.pending[7] != 0;
+        header.type_fingerprint = type_fingerprint;
+        header.total_size = total_size;
+        header.dynamic_size = total_size.saturating_sub(mem::size_of::<T>() as u64);
+
+        // the header is intact, so refused messages can be skipped
+        let frame_size = total_size.saturating_add((FRAME_HEADER_SIZE + FRAME_TRAILER_SIZE) as u64);
+        let refusal = header.check_compatible::<T>().and_then(|()| {
+            check_handles_can_leave_process::<T>()?;
+            if total_size > self.max_message_size as u64 {
+                return Err(SnapshotError::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "message of {} bytes exceeds the limit of {} bytes",
+                        total_size, self.max_message_size
+                    ),
+                )));
+            }
+            Ok(())
+        });
+        if let Err(err) = refusal {
+            self.skip(frame_size);
+            return Err(err);
+        }
+        let total_size = total_size as usize;
+        let frame_size = (FRAME_HEADER_SIZE + FRAME_TRAILER_SIZE)
+            .checked_add(total_size)
+            .expect("message size limit too large");
+        if self.pending.len() < frame_size {
+            return Ok(None);
+        }
+
+        let image_end = FRAME_HEADER_SIZE + total_size;
+        let mut checksum_bytes = [0u8; FRAME_TRAILER_SIZE];
+        checksum_bytes.copy_from_slice(&self.pending[image_end..frame_size]);
+        let expected_checksum = u32::from_le_bytes(checksum_bytes);
+        let checksum = ::crc32fast::hash(&self.pending[FRAME_HEADER_SIZE..image_end]);
+        if checksum != expected_checksum {
+            self.pending.drain(..frame_size);
+            return Err(SnapshotError::ChecksumMismatch {
+                found: checksum,
+                expected: expected_checksum,
+            });
+        }
+
+        let mut image = Image::<T>::new(total_size);
+        image
+            .bytes_mut()
+            .copy_from_slice(&self.pending[FRAME_HEADER_SIZE..image_end]);
+        self.pending.drain(..frame_size);
+        Ok(Some(unsafe { CompactBox::from_image(image) }))
+    }
+
+    /// The underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// The underlying stream, mutably
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+}
+
+#[cfg(test)]
+type Message = super::compact_vec::CompactVec<super::compact_str::CompactString>;
+
+#[cfg(test)]
+fn message(words: &[&str]) -> Message {
+    words.iter().map(|word| (*word).to_owned().into()).collect()
+}
+
+#[cfg(test)]
+fn words(message: &Message) -> Vec<String> {
+    message.iter().map(|word| word.to_string()).collect()
+}
+
+/// Hands out a few bytes per read, so that frames arrive in pieces
+#[cfg(test)]
+struct Trickle<'a>(&'a [u8]);
+
+#[cfg(test)]
+impl<'a> Read for Trickle<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = ::std::cmp::min(7, buf.len()).min(self.0.len());
+        buf[..n].copy_from_slice(&self.0[..n]);
+        self.0 = &self.0[n..];
+        Ok(n)
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn send_and_receive_over_unix_socket() {
+    use std::os::unix::net::UnixStream;
+    let (a, b) = UnixStream::pair().unwrap();
+    let mut sender = CompactSender::new(a);
+    let mut receiver = unsafe { CompactReceiver::<_, Message>::new(b) };
+
+    let sending = ::std::thread::spawn(move || {
+        for i in 0..100 {
+            sender.send(&message(&["message", &i.to_string()])).unwrap();
+        }
+    });
+    for i in 0..100 {
+        let received = receiver.recv().unwrap();
+        assert!(received.is_still_compact());
+        assert_eq!(vec!["message".to_owned(), i.to_string()], words(&received));
+    }
+    sending.join().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn try_receive_without_blocking() {
+    use std::os::unix::net::UnixStream;
+    let (a, b) = UnixStream::pair().unwrap();
+    b.set_nonblocking(true).unwrap();
+    let mut sender = CompactSender::new(a);
+    let mut receiver = unsafe { CompactReceiver::<_, Message>::new(b) };
+
+    assert!(receiver.try_recv().unwrap().is_none());
+    sender.send(&message(&["hello"])).unwrap();
+    let received = loop {
+        if let Some(received) = receiver.try_recv().unwrap() {
+            break received;
+        }
+    };
+    assert_eq!(vec!["hello"], words(&received));
+    assert!(receiver.try_recv().unwrap().is_none());
+}
+
+#[test]
+fn refuses_mismatched_and_corrupted_messages() {
+    use super::compact_vec::CompactVec;
+    let mut sender = CompactSender::new(Vec::new());
+    sender.send(&message(&["hello", "world"])).unwrap();
+    let frame = sender.get_ref().clone();
+
+    match unsafe { CompactReceiver::<_, CompactVec<u32>>::new(&frame[..]) }.recv() {
+        Err(SnapshotError::TypeMismatch { .. }) => {}
+        other => panic!("expected type mismatch, got {:?}", other.map(|_| ())),
+    }
+
+    let mut corrupted = frame.clone();
+    corrupted[FRAME_HEADER_SIZE + 1] ^= 0xff;
+    match unsafe { CompactReceiver::<_, Message>::new(&corrupted[..]) }.recv() {
+        Err(SnapshotError::ChecksumMismatch { .. }) => {}
+        other => panic!("expected checksum mismatch, got {:?}", other.map(|_| ())),
+    }
+
+    match unsafe { CompactReceiver::<_, Message>::new(&frame[..frame.len() - 1]) }.recv() {
+        Err(SnapshotError::Truncated) => {}
+        other => panic!("expected truncation, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn refuses_corrupted_headers_and_oversized_messages() {
+    let mut sender = CompactSender::new(Vec::new());
+    sender.send(&message(&["hello", "world"])).unwrap();
+    let frame = sender.get_ref().clone();
+
+    // a huge size would otherwise make the receiver wait for data forever
+    let mut huge = frame.clone();
+    huge[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+    match unsafe { CompactReceiver::<_, Message>::new(&huge[..]) }.recv() {
+        Err(SnapshotError::ChecksumMismatch { .. }) => {}
+        other => panic!("expected checksum mismatch, got {:?}", other.map(|_| ())),
+    }
+
+    let mut receiver = unsafe { CompactReceiver::<_, Message>::new(&frame[..]) };
+    receiver.set_max_message_size(16);
+    match receiver.recv() {
+        Err(SnapshotError::Io(ref err)) if err.kind() == io::ErrorKind::InvalidData => {}
+        other => panic!("expected a refused message, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn skips_refused_messages() {
+    let mut sender = CompactSender::new(Vec::new());
+    sender.send(&message(&["refused", "message"])).unwrap();
+    sender.send(&message(&["next"])).unwrap();
+    let frames = sender.get_ref().clone();
+
+    let mut receiver = unsafe { CompactReceiver::<_, Message>::new(Trickle(&frames)) };
+    receiver.set_max_message_size(message(&["next"]).total_size_bytes());
+    match receiver.recv() {
+        Err(SnapshotError::Io(ref err)) if err.kind() == io::ErrorKind::InvalidData => {}
+        other => panic!("expected a refused message, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(vec!["next".to_owned()], words(&receiver.recv().unwrap()));
+
+    let mut corrupted = frames.clone();
+    corrupted[FRAME_HEADER_SIZE + 3] ^= 1;
+    let mut receiver = unsafe { CompactReceiver::<_, Message>::new(Trickle(&corrupted)) };
+    match receiver.recv() {
+        Err(SnapshotError::ChecksumMismatch { .. }) => {}
+        other => panic!("expected checksum mismatch, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(vec!["next".to_owned()], words(&receiver.recv().unwrap()));
+
+    let mut other_type = CompactSender::new(Vec::new());
+    other_type.send(&super::compact_vec::CompactVec::<u32>::from(vec![1, 2, 3])).unwrap();
+    let mut mixed = other_type.get_ref().clone();
+    mixed.extend_from_slice(&frames);
+    let mut receiver = unsafe { CompactReceiver::<_, Message>::new(&mixed[..]) };
+    match receiver.recv() {
+        Err(SnapshotError::TypeMismatch { .. }) => {}
+        other => panic!("expected type mismatch, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(vec!["refused".to_owned(), "message".to_owned()], words(&receiver.recv().unwrap()));
+}
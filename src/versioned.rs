@@ -0,0 +1,195 @@
+use super::compact::Compact;
+use super::snapshot::{
+    read_snapshot_header, read_snapshot_image, write_snapshot_with_version, SnapshotError,
+    SnapshotHeader,
+};
+use std::io::{self, Read, Write};
+
+/// A `Compact` type that knows its previous layouts, so that persisted images
+/// of older versions can still be loaded and upgraded to the current one.
+///
+/// Each version names its predecessor in `Previous`, forming a chain that ends
+/// in `NoPreviousVersion`. Versions should be distinct along the chain and start at 1,
+/// since 0 denotes unversioned images.
+///
+/// ```ignore
+/// impl Versioned for StateV1 {
+///     const VERSION: u32 = 1;
+///     type Previous = NoPreviousVersion;
+///     fn upgrade(previous: NoPreviousVersion) -> Self { match previous {} }
+/// }
+///
+/// impl Versioned for State {
+///     const VERSION: u32 = 2;
+///     type Previous = StateV1;
+///     fn upgrade(previous: StateV1) -> Self { State { a: previous.a, b: 0 } }
+/// }
+/// ```
+pub trait Versioned: Compact {
+    /// Version of this layout
+    const VERSION: u32;
+
+    /// The layout this one replaced
+    type Previous: Versioned;
+
+    /// Convert a value of the previous version to this version
+    fn upgrade(previous: Self::Previous) -> Self;
+
+    /// Load the image described by `header` as whichever version in the chain it was
+    /// written with, then upgrade it version by version to `Self`.
+    ///
+    /// Only needs to be overridden by `NoPreviousVersion`.
+    ///
+    /// # Safety
+    /// Like `read_snapshot`, the snapshot has to come from a trusted source
+    unsafe fn read_any_version<R: Read>(
+        header: &SnapshotHeader,
+        reader: &mut R,
+    ) -> Result<Self, SnapshotError> {
+        if header.type_version == Self::VERSION {
+            read_snapshot_image(header, reader)
+        } else {
+            Self::Previous::read_any_version(header, reader).map(Self::upgrade)
+        }
+    }
+}
+
+/// Marks the end of a chain of `Versioned` layouts. Can't be constructed.
+#[derive(Clone, Copy, Debug)]
+pub enum NoPreviousVersion {}
+
+impl Versioned for NoPreviousVersion {
+    const VERSION: u32 = 0;
+    type Previous = NoPreviousVersion;
+
+    fn upgrade(previous: NoPreviousVersion) -> Self {
+        previous
+    }
+
+    unsafe fn read_any_version<R: Read>(
+        header: &SnapshotHeader,
+        _reader: &mut R,
+    ) -> Result<Self, SnapshotError> {
+        Err(SnapshotError::UnknownTypeVersion {
+            found: header.type_version,
+            current: 0,
+        })
+    }
+}
+
+/// Write a snapshot of `value`, recording its current version
+pub fn write_versioned_snapshot<T: Versioned, W: Write>(
+    value: &T,
+    writer: &mut W,
+) -> io::Result<()> {
+    write_snapshot_with_version(value, T::VERSION, writer)
+}
+
+/// Read a snapshot written by `write_versioned_snapshot` with the current
+/// or any previous version of `T`, upgrading it to the current version
+///
+/// # Safety
+/// Like `read_snapshot`, the snapshot has to come from a trusted source
+pub unsafe fn read_versioned_snapshot<T: Versioned, R: Read>(
+    reader: &mut R,
+) -> Result<T, SnapshotError> {
+    let header = read_snapshot_header(reader)?;
+    T::read_any_version(&header, reader).map_err(|err| match err {
+        SnapshotError::UnknownTypeVersion { found, .. } => SnapshotError::UnknownTypeVersion {
+            found,
+            current: T::VERSION,
+        },
+        err => err,
+    })
+}
+
+#[cfg(test)]
+mod v1 {
+    #[derive(Clone, Copy)]
+    pub struct Position {
+        pub x: u32,
+        pub y: u32,
+    }
+}
+
+#[cfg(test)]
+mod v2 {
+    #[derive(Clone, Copy)]
+    pub struct Position {
+        pub x: u32,
+        pub y: u32,
+        pub z: u32,
+    }
+}
+
+#[cfg(test)]
+impl Versioned for v1::Position {
+    const VERSION: u32 = 1;
+    type Previous = NoPreviousVersion;
+
+    fn upgrade(previous: NoPreviousVersion) -> Self {
+        match previous {}
+    }
+}
+
+#[cfg(test)]
+impl Versioned for v2::Position {
+    const VERSION: u32 = 2;
+    type Previous = v1::Position;
+
+    fn upgrade(previous: v1::Position) -> Self {
+        v2::Position {
+            x: previous.x,
+            y: previous.y,
+            z: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Versioned for super::compact_vec::CompactVec<v2::Position> {
+    const VERSION: u32 = 3;
+    type Previous = v2::Position;
+
+    fn upgrade(previous: v2::Position) -> Self {
+        vec![previous].into()
+    }
+}
+
+#[test]
+fn upgrades_through_all_versions() {
+    use super::compact_vec::CompactVec;
+    let mut bytes = Vec::new();
+    write_versioned_snapshot(&v1::Position { x: 1, y: 2 }, &mut bytes).unwrap();
+
+    let path: CompactVec<v2::Position> = unsafe { read_versioned_snapshot(&mut &bytes[..]) }.unwrap();
+    assert_eq!(1, path.len());
+    assert_eq!((1, 2, 0), (path[0].x, path[0].y, path[0].z));
+
+    let mut bytes = Vec::new();
+    write_versioned_snapshot(&path, &mut bytes).unwrap();
+    let same: CompactVec<v2::Position> = unsafe { read_versioned_snapshot(&mut &bytes[..]) }.unwrap();
+    assert_eq!(1, same.len());
+}
+
+#[test]
+fn refuses_unknown_versions() {
+    let mut bytes = Vec::new();
+    write_versioned_snapshot(&v1::Position { x: 1, y: 2 }, &mut bytes).unwrap();
+    bytes[44] = 7;
+
+    match unsafe { read_versioned_snapshot::<v2::Position, _>(&mut &bytes[..]) } {
+        Err(SnapshotError::UnknownTypeVersion {
+            found: 7,
+            current: 2,
+        }) => {}
+        other => panic!("expected unknown version, got {:?}", other.map(|_| ())),
+    }
+
+    // a version number with the wrong layout is caught by the fingerprint
+    bytes[44] = 2;
+    match unsafe { read_versioned_snapshot::<v2::Position, _>(&mut &bytes[..]) } {
+        Err(SnapshotError::TypeMismatch { .. }) => {}
+        other => panic!("expected type mismatch, got {:?}", other.map(|_| ())),
+    }
+}